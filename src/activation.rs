@@ -0,0 +1,98 @@
+//! Starting from systemd units instead of a timer: a path unit that fires when the directory
+//! changes, or a socket unit that fires when something connects. Either way, a run does one
+//! rotation and exits. Connections that triggered the run are answered with its summary and
+//! closed, so that they don't start it again. Sockets are only taken on Linux.
+use log::{debug, info};
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+
+/// The first file descriptor that systemd passes
+const LISTEN_FDS_START: i32 = 3;
+
+/// Connections waiting on the sockets passed by systemd
+pub struct Connections(Vec<File>);
+
+fn passed_sockets() -> i32 {
+    // The number of sockets passed, if they were passed to this process
+    let for_us =
+        env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(process::id());
+    let count = match for_us {
+        true => env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0),
+        false => 0,
+    };
+    // Not for the commands the run starts
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    count
+}
+
+#[cfg(target_os = "linux")]
+fn accept_pending(listener: i32, connections: &mut Vec<File>) -> io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+    let flags = unsafe { libc::fcntl(listener, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(listener, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    loop {
+        let fd = unsafe {
+            libc::accept4(
+                listener,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            let why = io::Error::last_os_error();
+            return match why.kind() {
+                io::ErrorKind::WouldBlock => Ok(()),
+                // With Accept=yes, systemd passes the connection itself
+                _ if why.raw_os_error() == Some(libc::EINVAL) => {
+                    connections.push(unsafe { File::from_raw_fd(listener) });
+                    Ok(())
+                }
+                _ => Err(why),
+            };
+        }
+        connections.push(unsafe { File::from_raw_fd(fd) });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn accept_pending(_listener: i32, _connections: &mut Vec<File>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Log what started the run, and take the connections waiting on the sockets systemd
+/// passed, if any
+pub fn activated() -> io::Result<Connections> {
+    if let Ok(unit) = env::var("TRIGGER_UNIT") {
+        match env::var("TRIGGER_PATH") {
+            Ok(path) => info!("Started by {} for {}", unit, path),
+            Err(_) => info!("Started by {}", unit),
+        }
+    }
+    let mut connections = Vec::new();
+    for listener in LISTEN_FDS_START..LISTEN_FDS_START + passed_sockets() {
+        accept_pending(listener, &mut connections)?;
+    }
+    if !connections.is_empty() {
+        debug!("Started by {} connections", connections.len());
+    }
+    Ok(Connections(connections))
+}
+
+impl Connections {
+    /// Answer the connections with how the run went, and close them
+    pub fn reply(self, text: &str) {
+        for mut connection in self.0 {
+            let _ = writeln!(connection, "{}", text);
+        }
+    }
+}
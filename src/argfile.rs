@@ -0,0 +1,55 @@
+//! Arguments from a file, for `dirrotate @FILE` and `dirrotate check-config FILE`, so that a
+//! fleet can roll out the settings of its runs as files. One option per line, with its value
+//! after a space, e.g. `--max-age 30d`, and the directory and size on lines of their own.
+//! Blank lines and lines starting with # are skipped.
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// An argument and the line it is on
+pub struct Argument {
+    pub line: usize,
+    pub value: String,
+}
+
+pub fn read(path: &Path) -> io::Result<Vec<Argument>> {
+    let mut arguments = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Values may hold spaces, so only an option is split from what follows it
+        let words = match line.split_once(char::is_whitespace) {
+            Some((option, value)) if option.starts_with('-') => vec![option, value.trim()],
+            _ => vec![line],
+        };
+        arguments.extend(words.into_iter().map(|word| Argument {
+            line: i + 1,
+            value: word.to_string(),
+        }));
+    }
+    Ok(arguments)
+}
+
+/// The arguments, with each @FILE replaced by the arguments in the file
+pub fn expand(args: impl Iterator<Item = OsString>) -> io::Result<Vec<OsString>> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        match arg.to_str().and_then(|s| s.strip_prefix('@')) {
+            Some(path) => expanded
+                .extend((read(Path::new(path))?.into_iter()).map(|argument| argument.value.into())),
+            None => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+/// The line of the option, given as `--option value` or `--option=value`
+pub fn line_of(arguments: &[Argument], option: &str) -> Option<usize> {
+    let with_value = format!("{}=", option);
+    (arguments.iter())
+        .find(|argument| argument.value == option || argument.value.starts_with(&with_value))
+        .map(|argument| argument.line)
+}
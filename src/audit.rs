@@ -0,0 +1,66 @@
+use crate::clock;
+use crate::json;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// One line of the audit log
+pub struct Entry<'a> {
+    pub path: &'a Path,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// What happened: deleted, changed, failed or restored
+    pub result: &'a str,
+    pub error: Option<String>,
+    pub sha256: Option<&'a str>,
+    /// Where the file was uploaded to before deleting it
+    pub destination: Option<&'a str>,
+}
+
+/// An append-only record of every operation, one JSON object per line
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> AuditLog {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("Could not open the audit log");
+        AuditLog {
+            path: path.to_path_buf(),
+            file,
+        }
+    }
+
+    pub fn record(&mut self, entry: &Entry) {
+        let line = json::Object::new()
+            .string(
+                "time",
+                &humantime::format_rfc3339_micros(clock::now()).to_string(),
+            )
+            .string("result", entry.result)
+            .string("path", &entry.path.to_string_lossy())
+            .number("size", entry.size)
+            .string(
+                "modified",
+                &humantime::format_rfc3339_seconds(entry.modified).to_string(),
+            )
+            .optional_string("sha256", entry.sha256)
+            .optional_string("destination", entry.destination)
+            .optional_string("error", entry.error.as_deref())
+            .finish();
+        if let Err(why) = writeln!(self.file, "{}", line) {
+            warn!(
+                "Could not write to audit log {}: {}",
+                self.path.display(),
+                why
+            );
+        }
+    }
+}
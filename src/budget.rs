@@ -0,0 +1,254 @@
+use crate::matching::{canonical_path, get_glob_matcher};
+use parse_size::parse_size;
+use path_matchers::PathMatcher;
+use std::path::{Path, PathBuf};
+
+/// A share of the directory budget set aside for files matching a pattern
+#[derive(Clone, Debug)]
+pub struct Reservation {
+    pub pattern: String,
+    pub size: u64,
+}
+
+/// Parse "PATTERN=SIZE", e.g. "*.results=2GiB"
+pub fn reservation_parser(s: &str) -> Result<Reservation, String> {
+    let (pattern, size) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected PATTERN=SIZE, got {}", s))?;
+    let size = parse_size(size.trim()).map_err(|e| e.to_string())?;
+    Ok(Reservation {
+        pattern: pattern.to_string(),
+        size,
+    })
+}
+
+struct Reserved {
+    matcher: Box<dyn PathMatcher>,
+    size: u64,
+    /// Size of the matching files that are still there (as far as the plan goes)
+    occupied: u64,
+}
+
+/// Keeps track of how much of each reservation is in use while planning
+pub struct Reservations {
+    reserved: Vec<Reserved>,
+}
+
+impl Reservations {
+    /// Account for the files, which are all there is to the directory
+    pub fn new<'a>(
+        base_dir: &Path,
+        reservations: &[Reservation],
+        files: impl Iterator<Item = (&'a Path, u64)>,
+    ) -> Reservations {
+        let mut reserved: Vec<Reserved> = reservations
+            .iter()
+            .map(|r| Reserved {
+                matcher: Box::new(get_glob_matcher(base_dir, &r.pattern)),
+                size: r.size,
+                occupied: 0,
+            })
+            .collect();
+        if !reserved.is_empty() {
+            for (path, size) in files {
+                let path = canonical_path(path);
+                for r in reserved.iter_mut().filter(|r| r.matcher.matches(&path)) {
+                    r.occupied += size;
+                }
+            }
+        }
+        Reservations { reserved }
+    }
+
+    /// The file goes no matter what (e.g. it's expired)
+    pub fn remove(&mut self, path: &Path, size: u64) {
+        if self.reserved.is_empty() {
+            return;
+        }
+        let path = canonical_path(path);
+        for r in self
+            .reserved
+            .iter_mut()
+            .filter(|r| r.matcher.matches(&path))
+        {
+            r.occupied = r.occupied.saturating_sub(size);
+        }
+    }
+
+    /// Remove the files (a single one, or a group) if that leaves every reservation they
+    /// fall under at least filled. Returns whether they were removed.
+    pub fn try_remove(&mut self, files: &[(&Path, u64)]) -> bool {
+        if self.reserved.is_empty() {
+            return true;
+        }
+        let files: Vec<(PathBuf, u64)> = files
+            .iter()
+            .map(|(path, size)| (canonical_path(path), *size))
+            .collect();
+        let removed = |r: &Reserved| -> u64 {
+            files
+                .iter()
+                .filter(|(path, _)| r.matcher.matches(path))
+                .map(|(_, size)| size)
+                .sum()
+        };
+        let allowed = self.reserved.iter().all(|r| match removed(r) {
+            0 => true,
+            removed => r.occupied.saturating_sub(removed) >= r.size,
+        });
+        if allowed {
+            for r in self.reserved.iter_mut() {
+                r.occupied -= removed(r);
+            }
+        }
+        allowed
+    }
+}
+
+/// How much the planner prefers to keep files matching a pattern
+#[derive(Clone, Debug)]
+pub struct Weight {
+    pub pattern: String,
+    pub weight: f64,
+}
+
+/// Parse "PATTERN=WEIGHT", e.g. "*.processed=5"
+pub fn weight_parser(s: &str) -> Result<Weight, String> {
+    let (pattern, weight) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected PATTERN=WEIGHT, got {}", s))?;
+    let weight: f64 = weight
+        .trim()
+        .parse()
+        .map_err(|_| format!("Not a weight: {}", weight))?;
+    if !weight.is_finite() {
+        return Err(format!("Not a weight: {}", weight));
+    }
+    Ok(Weight {
+        pattern: pattern.to_string(),
+        weight,
+    })
+}
+
+/// The weights of the file classes, matched against paths
+pub struct Weights {
+    weights: Vec<(Box<dyn PathMatcher>, f64)>,
+}
+
+impl Weights {
+    pub fn new(base_dir: &Path, weights: &[Weight]) -> Weights {
+        let weights = weights
+            .iter()
+            .map(|w| {
+                let matcher: Box<dyn PathMatcher> =
+                    Box::new(get_glob_matcher(base_dir, &w.pattern));
+                (matcher, w.weight)
+            })
+            .collect();
+        Weights { weights }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+
+    /// The highest weight of the patterns the file matches, or 1 if it matches none
+    pub fn weight_of(&self, path: &Path) -> f64 {
+        let path = canonical_path(path);
+        self.weights
+            .iter()
+            .filter(|(matcher, _)| matcher.matches(&path))
+            .map(|(_, weight)| *weight)
+            .reduce(f64::max)
+            .unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Not there, so that paths are matched as they are
+    const BASE: &str = "/nonexistent/dirrotate";
+
+    fn path(name: &str) -> PathBuf {
+        Path::new(BASE).join(name)
+    }
+
+    fn reservations(reservations: &[&str], files: &[(&str, u64)]) -> Reservations {
+        let reservations: Vec<Reservation> = (reservations.iter())
+            .map(|r| reservation_parser(r).unwrap())
+            .collect();
+        let files: Vec<(PathBuf, u64)> = (files.iter())
+            .map(|(name, size)| (path(name), *size))
+            .collect();
+        Reservations::new(
+            Path::new(BASE),
+            &reservations,
+            files.iter().map(|(path, size)| (path.as_path(), *size)),
+        )
+    }
+
+    #[test]
+    fn parses_reservations() {
+        let reservation = reservation_parser("*.results=2KiB").unwrap();
+        assert_eq!(reservation.pattern, "*.results");
+        assert_eq!(reservation.size, 2048);
+        // The size is after the last =
+        assert_eq!(reservation_parser("a=b=1K").unwrap().pattern, "a=b");
+        assert!(reservation_parser("*.results").is_err());
+        assert!(reservation_parser("*.results=lots").is_err());
+    }
+
+    #[test]
+    fn keeps_reservations_filled() {
+        let files = [("a.results", 600), ("b.results", 600), ("c.raw", 600)];
+        let mut reserved = reservations(&["*.results=1000"], &files);
+        // Only 200 would be left of the 1000 reserved
+        assert!(!reserved.try_remove(&[(&path("a.results"), 600)]));
+        assert!(reserved.try_remove(&[(&path("c.raw"), 600)]));
+        // Nothing changed by the refusal: removing 200 still leaves 1000
+        assert!(reserved.try_remove(&[(&path("a.results"), 200)]));
+        assert!(!reserved.try_remove(&[(&path("b.results"), 1)]));
+    }
+
+    #[test]
+    fn refuses_groups_as_a_whole() {
+        let files = [("a.results", 600), ("a.raw", 600), ("b.results", 600)];
+        let mut reserved = reservations(&["*.results=1000"], &files);
+        let group = [(path("a.raw"), 600), (path("a.results"), 600)];
+        let group: Vec<(&Path, u64)> = group.iter().map(|(p, s)| (p.as_path(), *s)).collect();
+        assert!(!reserved.try_remove(&group));
+        // a.raw wasn't taken out of it either
+        assert!(reserved.try_remove(&group[..1]));
+    }
+
+    #[test]
+    fn forced_removals_use_up_reservations() {
+        let files = [("a.results", 600), ("b.results", 600)];
+        let mut reserved = reservations(&["*.results=500"], &files);
+        reserved.remove(&path("a.results"), 600);
+        assert!(!reserved.try_remove(&[(&path("b.results"), 600)]));
+    }
+
+    #[test]
+    fn allows_anything_without_reservations() {
+        let mut reserved = reservations(&[], &[("a.results", 600)]);
+        assert!(reserved.try_remove(&[(&path("a.results"), 600)]));
+    }
+
+    #[test]
+    fn weighs_by_the_highest_matching_weight() {
+        let weights: Vec<Weight> = ["*.processed=5", "run1*=2", "run1.processed=0.5"]
+            .iter()
+            .map(|w| weight_parser(w).unwrap())
+            .collect();
+        let weights = Weights::new(Path::new(BASE), &weights);
+        assert_eq!(weights.weight_of(&path("run1.processed")), 5.0);
+        assert_eq!(weights.weight_of(&path("run1.raw")), 2.0);
+        assert_eq!(weights.weight_of(&path("run2.raw")), 1.0);
+        assert!(weight_parser("*.processed=inf").is_err());
+        assert!(weight_parser("*.processed=heavy").is_err());
+        assert!(weight_parser("*.processed").is_err());
+    }
+}
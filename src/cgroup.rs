@@ -0,0 +1,155 @@
+//! A cgroup of its own with limits on memory and disk I/O (--cgroup-limit), so that a heavy
+//! rotation leaves the rest of the device alone. Needs cgroup v2, and root or a delegated
+//! cgroup. Linux only.
+use parse_size::parse_size;
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const PREFIX: &str = "dirrotate-";
+
+/// Limits for the run
+#[derive(Clone, Debug, Default)]
+pub struct CgroupLimit {
+    /// Bytes of memory
+    pub memory: Option<u64>,
+    /// Bytes per second, for reads and for writes each, on the disk of the directory
+    pub io: Option<u64>,
+}
+
+/// Parse "mem=SIZE,io=SIZE/s", either part optional, e.g. "mem=64M,io=10M/s"
+pub fn cgroup_limit_parser(s: &str) -> Result<CgroupLimit, String> {
+    let mut limit = CgroupLimit::default();
+    for part in s.split(',') {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| format!("Expected KEY=VALUE, got {}", part))?;
+        match key.trim() {
+            "mem" => limit.memory = Some(parse_size(value.trim()).map_err(|e| e.to_string())?),
+            "io" => {
+                let rate = value
+                    .trim()
+                    .strip_suffix("/s")
+                    .ok_or_else(|| format!("Expected a rate like 10M/s, got {}", value))?;
+                limit.io = Some(parse_size(rate).map_err(|e| e.to_string())?);
+            }
+            other => return Err(format!("Unknown limit {} (expected mem or io)", other)),
+        }
+    }
+    Ok(limit)
+}
+
+fn cgroup2_mount() -> io::Result<PathBuf> {
+    // The fields after " - " start with the type of the filesystem
+    fs::read_to_string("/proc/self/mountinfo")?
+        .lines()
+        .find_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            filesystem
+                .starts_with("cgroup2 ")
+                .then(|| mount.split(' ').nth(4))?
+        })
+        .map(PathBuf::from)
+        .ok_or_else(|| io::Error::other("cgroup v2 is not mounted"))
+}
+
+fn own_cgroup() -> io::Result<PathBuf> {
+    fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| PathBuf::from(path.trim_start_matches('/')))
+        .ok_or_else(|| io::Error::other("Not in a cgroup v2 hierarchy"))
+}
+
+#[cfg(target_os = "linux")]
+fn block_device(path: &Path) -> io::Result<String> {
+    // "MAJOR:MINOR" of the disk the path is on. Limits apply to disks, not partitions.
+    let dev = fs::metadata(path)?.dev();
+    let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+    let sys = PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+    if !sys.exists() {
+        return Err(io::Error::other(format!(
+            "{} is not on a block device",
+            path.display()
+        )));
+    }
+    let disk = match sys.join("partition").exists() {
+        true => fs::canonicalize(&sys)?.join("../dev"),
+        false => sys.join("dev"),
+    };
+    Ok(fs::read_to_string(disk)?.trim().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn block_device(_path: &Path) -> io::Result<String> {
+    Err(io::Error::other("I/O limits are only supported on Linux"))
+}
+
+fn remove_stale(parent: &Path) {
+    // Cgroups of earlier runs, which could not remove their own. Populated ones stay.
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(PREFIX) {
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+}
+
+/// Move the process to a new cgroup next to its own, with the limits. The I/O limit is for
+/// the disk of the directory. Returns the cgroup.
+pub fn enter(limit: &CgroupLimit, directory: Option<&Path>) -> io::Result<PathBuf> {
+    let (mount, own) = (cgroup2_mount()?, own_cgroup()?);
+    // A cgroup with processes can't pass controllers on, except the root. So the new one
+    // goes next to the process's own.
+    let parent = match own.parent() {
+        Some(parent) => mount.join(parent),
+        None => mount,
+    };
+    remove_stale(&parent);
+
+    let controllers = fs::read_to_string(parent.join("cgroup.controllers"))?;
+    let needed: Vec<&str> = [
+        ("memory", limit.memory.is_some()),
+        ("io", limit.io.is_some()),
+    ]
+    .iter()
+    .filter(|(_, needed)| *needed)
+    .map(|(controller, _)| *controller)
+    .collect();
+    for controller in &needed {
+        if !controllers.split_whitespace().any(|c| c == *controller) {
+            return Err(io::Error::other(format!(
+                "The {} controller is not available in {}",
+                controller,
+                parent.display()
+            )));
+        }
+    }
+    let enable: Vec<String> = needed.iter().map(|c| format!("+{}", c)).collect();
+    if !enable.is_empty() {
+        fs::write(parent.join("cgroup.subtree_control"), enable.join(" "))?;
+    }
+
+    let group = parent.join(format!("{}{}", PREFIX, process::id()));
+    fs::create_dir(&group)?;
+    if let Some(memory) = limit.memory {
+        fs::write(group.join("memory.max"), memory.to_string())?;
+    }
+    if let Some(rate) = limit.io {
+        let directory =
+            directory.ok_or_else(|| io::Error::other("An I/O limit needs a directory"))?;
+        let device = block_device(directory)?;
+        fs::write(
+            group.join("io.max"),
+            format!("{} rbps={} wbps={}", device, rate, rate),
+        )?;
+    }
+    fs::write(group.join("cgroup.procs"), process::id().to_string())?;
+    Ok(group)
+}
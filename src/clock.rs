@@ -0,0 +1,27 @@
+//! The time that age-based policies are evaluated against
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static FROZEN: OnceLock<SystemTime> = OnceLock::new();
+
+/// Make `now` return the given time for the rest of the run
+pub fn freeze(time: SystemTime) {
+    FROZEN.set(time).expect("The clock is already frozen");
+}
+
+/// The frozen time, or else the system time
+pub fn now() -> SystemTime {
+    FROZEN.get().copied().unwrap_or_else(SystemTime::now)
+}
+
+/// Parse an RFC 3339 timestamp (e.g. 2026-10-14T12:00:00Z, the offset may be left out)
+/// or seconds since the epoch prefixed with @ (e.g. @1791979200)
+pub fn timestamp_parser(s: &str) -> Result<SystemTime, String> {
+    match s.strip_prefix('@') {
+        Some(secs) => secs
+            .parse()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            .map_err(|_| format!("Not a number of seconds: {}", secs)),
+        None => humantime::parse_rfc3339_weak(s).map_err(|e| e.to_string()),
+    }
+}
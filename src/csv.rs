@@ -0,0 +1,36 @@
+//! Just enough CSV (RFC 4180) for --output csv
+use crate::executor::{Operation, Outcome};
+
+/// A CSV field, quoted if it needs to be
+pub fn field(s: &str) -> String {
+    match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    }
+}
+
+fn row(fields: &[&str]) -> String {
+    let fields: Vec<String> = fields.iter().map(|f| field(f)).collect();
+    fields.join(",")
+}
+
+fn operation_row(operation: &Operation, result: &str, error: &str) -> String {
+    row(&[
+        &operation.path.to_string_lossy(),
+        &operation.size.to_string(),
+        &humantime::format_rfc3339_nanos(operation.modified).to_string(),
+        "delete",
+        result,
+        error,
+    ])
+}
+
+/// Every operation of the run and what came of it, one per line, with a header
+pub fn outcome_to_csv(outcome: &Outcome) -> String {
+    let mut lines = vec![row(&["path", "size", "mtime", "action", "result", "error"])];
+    for (operation, result, error) in outcome.results() {
+        let error = error.map_or_else(String::new, |why| why.to_string());
+        lines.push(operation_row(operation, result, &error));
+    }
+    lines.join("\r\n")
+}
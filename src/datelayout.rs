@@ -0,0 +1,143 @@
+//! Trees of dated directories, like YYYY/MM/DD (--date-layout). A purge by age only needs
+//! to look at the directories old enough to hold expired files, oldest first.
+use log::{debug, warn};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// Directory dates are taken as UTC, so a day of slack covers any time zone
+const SLACK: Duration = Duration::from_secs(86400);
+
+static LEAVES: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+/// A directory that holds the files of a year, month or day
+struct Leaf {
+    path: PathBuf,
+    /// When its period starts
+    start: SystemTime,
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn start_of(date: &[i64]) -> SystemTime {
+    let days = days_from_civil(
+        date[0],
+        *date.get(1).unwrap_or(&1),
+        *date.get(2).unwrap_or(&1),
+    );
+    match u64::try_from(days) {
+        Ok(days) => SystemTime::UNIX_EPOCH + Duration::from_secs(days * 86400),
+        Err(_) => SystemTime::UNIX_EPOCH,
+    }
+}
+
+fn subdirectories(dir: &Path) -> Option<Vec<(String, PathBuf)>> {
+    // The entries of a directory if they are all (visible) directories
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(dir).ok()? {
+        let entry = entry.ok()?;
+        let name = entry.file_name().into_string().ok()?;
+        if name.starts_with('.') {
+            continue;
+        }
+        if !entry.file_type().ok()?.is_dir() {
+            return None;
+        }
+        subdirs.push((name, entry.path()));
+    }
+    Some(subdirs)
+}
+
+fn parse_component(name: &str, digits: usize, range: (i64, i64)) -> Option<i64> {
+    if name.len() != digits || !name.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value = name.parse().ok()?;
+    (range.0..=range.1).contains(&value).then_some(value)
+}
+
+fn collect_leaves(dir: &Path, date: &mut Vec<i64>, leaves: &mut Vec<Leaf>) {
+    // Months below a year and days below a month, as long as all entries are such
+    let (digits, range) = match date.len() {
+        1 => (2, (1, 12)),
+        2 => (2, (1, 31)),
+        _ => (0, (0, 0)),
+    };
+    let children = (digits > 0)
+        .then(|| subdirectories(dir))
+        .flatten()
+        .and_then(|subdirs| {
+            (subdirs.into_iter())
+                .map(|(name, path)| Some((parse_component(&name, digits, range)?, path)))
+                .collect::<Option<Vec<_>>>()
+        })
+        .filter(|children| !children.is_empty());
+    match children {
+        Some(children) => {
+            for (value, path) in children {
+                date.push(value);
+                collect_leaves(&path, date, leaves);
+                date.pop();
+            }
+        }
+        None => leaves.push(Leaf {
+            path: dir.to_path_buf(),
+            start: start_of(date),
+        }),
+    }
+}
+
+/// The dated directories below the directory, oldest first, if all that is in it is
+/// directories named after years (YYYY), optionally with months (MM) and days (DD) below
+fn dated_leaves(base_dir: &Path) -> Option<Vec<Leaf>> {
+    let mut leaves = Vec::new();
+    for (name, path) in subdirectories(base_dir)? {
+        let year = parse_component(&name, 4, (0, 9999))?;
+        collect_leaves(&path, &mut vec![year], &mut leaves);
+    }
+    leaves.sort_by_key(|leaf| leaf.start);
+    Some(leaves)
+}
+
+/// List only the dated directories that may hold files modified before the cutoff, for
+/// the rest of the run. Without that layout, everything is listed as usual.
+pub fn install(base_dir: &Path, cutoff: SystemTime) {
+    let leaves = match dated_leaves(base_dir) {
+        Some(leaves) => leaves,
+        None => {
+            warn!(
+                "{} is not laid out in dated directories (YYYY/MM/DD), walking all of it",
+                base_dir.display()
+            );
+            return;
+        }
+    };
+    let total = leaves.len();
+    let old: Vec<PathBuf> = (leaves.into_iter())
+        .take_while(|leaf| leaf.start < cutoff + SLACK)
+        .map(|leaf| leaf.path)
+        .collect();
+    debug!(
+        "Walking {} of {} dated directories, skipping the newer ones",
+        old.len(),
+        total
+    );
+    LEAVES
+        .set(old)
+        .expect("Dated directories are already installed");
+}
+
+/// The installed dated directories, if there are
+pub fn leaves() -> Option<&'static [PathBuf]> {
+    LEAVES.get().map(Vec::as_slice)
+}
@@ -0,0 +1,19 @@
+//! When the run has to give up (--timeout)
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+/// Give the rest of the run this long
+pub fn set(timeout: Duration) {
+    DEADLINE
+        .set(Instant::now() + timeout)
+        .expect("The deadline is already set");
+}
+
+/// Whether the run is out of time. Never, without a deadline.
+pub fn passed() -> bool {
+    DEADLINE
+        .get()
+        .is_some_and(|&deadline| Instant::now() >= deadline)
+}
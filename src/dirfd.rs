@@ -0,0 +1,243 @@
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+#[cfg(unix)]
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// What identifies a file as the one seen during the scan: If any of this changed,
+/// the file at that path was replaced or rewritten in the meantime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileIdentity {
+    #[cfg(unix)]
+    dev: u64,
+    #[cfg(unix)]
+    ino: u64,
+    #[cfg(unix)]
+    mtime: (i64, i64),
+    #[cfg(not(unix))]
+    modified: Option<std::time::SystemTime>,
+    size: u64,
+}
+
+impl FileIdentity {
+    /// From the (not symlink-following) metadata of a file
+    pub fn of(metadata: &Metadata) -> FileIdentity {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+        FileIdentity {
+            #[cfg(unix)]
+            dev: metadata.dev(),
+            #[cfg(unix)]
+            ino: metadata.ino(),
+            #[cfg(unix)]
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            #[cfg(not(unix))]
+            modified: metadata.modified().ok(),
+            size: metadata.len(),
+        }
+    }
+
+    /// From what a stat-like call returned
+    #[cfg(unix)]
+    pub fn from_raw(dev: u64, ino: u64, mtime: (i64, i64), size: u64) -> FileIdentity {
+        FileIdentity {
+            dev,
+            ino,
+            mtime,
+            size,
+        }
+    }
+
+    /// From what a saved scan kept of the file (--load-scan)
+    pub fn from_saved(inode: Option<(u64, u64)>, modified: SystemTime, size: u64) -> FileIdentity {
+        #[cfg(unix)]
+        {
+            // No file has inode 0, so a file saved without one never passes for the same
+            let (dev, ino) = inode.unwrap_or((0, 0));
+            let (secs, nanos) = crate::spill::time_to_parts(modified);
+            FileIdentity::from_raw(dev, ino, (secs, nanos as i64), size)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = inode;
+            FileIdentity {
+                modified: Some(modified),
+                size,
+            }
+        }
+    }
+
+    /// Device and inode, where there are such
+    pub fn inode(&self) -> Option<(u64, u64)> {
+        #[cfg(unix)]
+        {
+            Some((self.dev, self.ino))
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+}
+
+/// Deletes files relative to directory file descriptors.
+///
+/// Directories are opened one component at a time from the rotated directory, never
+/// following symlinks, and the descriptors are reused for all files in the same
+/// directory. This saves the kernel a full path lookup per file on deep trees, and
+/// a directory renamed or replaced by a symlink mid-run can't redirect a deletion
+/// outside the tree.
+#[cfg(unix)]
+pub struct DirCache {
+    base: PathBuf,
+    base_fd: Arc<unix::DirFd>,
+    dirs: Mutex<HashMap<PathBuf, Arc<unix::DirFd>>>,
+}
+
+/// Without directory file descriptors, fall back to plain path-based deletion
+#[cfg(not(unix))]
+pub struct DirCache;
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::{CString, OsStr};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::RawFd;
+
+    pub struct DirFd(pub RawFd);
+
+    impl Drop for DirFd {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.0);
+            }
+        }
+    }
+
+    pub fn c_name(name: &OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub fn open_dir_at(parent: RawFd, name: &OsStr) -> io::Result<DirFd> {
+        let name = c_name(name)?;
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(parent, name.as_ptr(), flags) };
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(DirFd(fd))
+        }
+    }
+
+    pub fn identity_at(parent: RawFd, name: &OsStr) -> io::Result<super::FileIdentity> {
+        let name = c_name(name)?;
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        let res = unsafe {
+            libc::fstatat(
+                parent,
+                name.as_ptr(),
+                stat.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stat = unsafe { stat.assume_init() };
+        // Same conversions as std's MetadataExt
+        #[allow(clippy::unnecessary_cast)]
+        Ok(super::FileIdentity {
+            dev: stat.st_dev as u64,
+            ino: stat.st_ino as u64,
+            mtime: (stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+            size: stat.st_size as u64,
+        })
+    }
+
+    pub fn unlink_at(parent: RawFd, name: &OsStr) -> io::Result<()> {
+        let name = c_name(name)?;
+        if unsafe { libc::unlinkat(parent, name.as_ptr(), 0) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Upper bound on cached descriptors, to stay well clear of the open files limit
+#[cfg(unix)]
+const MAX_OPEN_DIRS: usize = 128;
+
+#[cfg(unix)]
+impl DirCache {
+    pub fn new(base: &Path) -> DirCache {
+        let base_fd = unix::open_dir_at(libc::AT_FDCWD, base.as_os_str())
+            .expect("Could not open the rotated directory");
+        DirCache {
+            base: base.to_path_buf(),
+            base_fd: Arc::new(base_fd),
+            dirs: Default::default(),
+        }
+    }
+
+    fn dir_fd(&self, relative: &Path) -> io::Result<Arc<unix::DirFd>> {
+        let (parent, name) = match (relative.parent(), relative.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Ok(Arc::clone(&self.base_fd)),
+        };
+        if let Some(fd) = self.dirs.lock().expect("Lock poisoned").get(relative) {
+            return Ok(Arc::clone(fd));
+        }
+        // Don't hold the lock while opening. Two workers may race to open the same
+        // directory, which is harmless.
+        let parent_fd = self.dir_fd(parent)?;
+        let fd = Arc::new(unix::open_dir_at(parent_fd.0, name)?);
+        let mut dirs = self.dirs.lock().expect("Lock poisoned");
+        if dirs.len() >= MAX_OPEN_DIRS {
+            dirs.clear();
+        }
+        dirs.insert(relative.to_path_buf(), Arc::clone(&fd));
+        Ok(fd)
+    }
+
+    /// Delete the file, unless it is no longer the one identified at scan time.
+    /// Returns whether the file was deleted.
+    pub fn remove_file(&self, path: &Path, expected: &FileIdentity) -> io::Result<bool> {
+        let relative = path.strip_prefix(&self.base).ok();
+        let (parent, name) = match relative.and_then(|r| Some((r.parent()?, r.file_name()?))) {
+            Some(parent_and_name) => parent_and_name,
+            None => return remove_file_by_path(path, expected),
+        };
+        let parent_fd = self.dir_fd(parent)?;
+        if &unix::identity_at(parent_fd.0, name)? != expected {
+            return Ok(false);
+        }
+        unix::unlink_at(parent_fd.0, name).map(|_| true)
+    }
+}
+
+#[cfg(not(unix))]
+impl DirCache {
+    pub fn new(_base: &Path) -> DirCache {
+        DirCache
+    }
+
+    /// Delete the file, unless it is no longer the one identified at scan time.
+    /// Returns whether the file was deleted.
+    pub fn remove_file(&self, path: &Path, expected: &FileIdentity) -> io::Result<bool> {
+        remove_file_by_path(path, expected)
+    }
+}
+
+fn remove_file_by_path(path: &Path, expected: &FileIdentity) -> io::Result<bool> {
+    if &FileIdentity::of(&fs::symlink_metadata(path)?) != expected {
+        return Ok(false);
+    }
+    fs::remove_file(path).map(|_| true)
+}
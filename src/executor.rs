@@ -0,0 +1,655 @@
+use crate::audit::{self, AuditLog};
+use crate::deadline;
+use crate::dirfd::{DirCache, FileIdentity};
+use crate::journal::Journal;
+use crate::lease;
+use crate::messages::text;
+use crate::record::FileRecord;
+use crate::sha256::sha256_file;
+use crate::trash::Trash;
+use crate::upload::Backend;
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+/// A planned deletion
+pub struct Operation {
+    pub path: PathBuf,
+    /// The size it frees
+    pub size: u64,
+    pub modified: SystemTime,
+    /// The file as seen during the scan. Deletion is skipped if the file changed since.
+    identity: FileIdentity,
+}
+
+impl Operation {
+    pub fn new(path: PathBuf, metadata: &Metadata) -> Operation {
+        Operation {
+            path,
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .expect("Last Modified Time is not available on this platform"),
+            identity: FileIdentity::of(metadata),
+        }
+    }
+
+    /// The deletion of a file found by the walk
+    pub fn of(file: &FileRecord) -> Operation {
+        Operation {
+            path: file.path.clone(),
+            size: file.len,
+            modified: file.modified,
+            identity: file.identity.clone(),
+        }
+    }
+}
+
+/// What happened to a single operation
+enum Status {
+    /// Nothing was done (dry-run)
+    Planned,
+    Deleted,
+    /// The file was replaced or modified after the scan, so it was left alone
+    Changed,
+    /// The run was out of time (--timeout) before getting to it
+    TimedOut,
+}
+
+type OperationResult = io::Result<Status>;
+
+/// What was recorded while carrying out an operation, for the audit log
+#[derive(Default)]
+struct Details {
+    sha256: Option<String>,
+    destination: Option<String>,
+    /// Where the file is in the trash
+    trashed: Option<PathBuf>,
+}
+
+/// How to carry out the operations
+pub struct Options {
+    pub concurrency: usize,
+    pub dryrun: bool,
+    /// Where to copy files before deleting them
+    pub upload: Option<Box<dyn Backend>>,
+    pub audit_log: Option<PathBuf>,
+    /// Record the SHA-256 of each file in the audit log
+    pub checksum: bool,
+    /// Number of files to checksum at once, ahead of the deletions
+    pub hash_threads: usize,
+    /// Move files there instead of deleting them
+    pub trash: Option<Trash>,
+    /// Where to journal the operations, to detect an interrupted run
+    pub journal: Option<PathBuf>,
+    /// Rename files to NAME+SUFFIX before archiving and deleting them
+    pub claim_suffix: Option<String>,
+}
+
+/// Everything the executor did, for the summary
+#[derive(Default)]
+pub struct Outcome {
+    /// Operations that were only planned (dry-run)
+    pub planned: Vec<Operation>,
+    pub deleted: Vec<Operation>,
+    pub changed: Vec<Operation>,
+    pub failed: Vec<(Operation, io::Error)>,
+    /// Operations that were not started, as the run was out of time
+    pub timed_out: Vec<Operation>,
+    /// Where deleted files are in the trash (with --trash), by their original path
+    pub trashed: HashMap<PathBuf, PathBuf>,
+    /// The checksums of deleted files (with --checksum), by their path
+    pub sha256: HashMap<PathBuf, String>,
+    /// Deleted files that were put back, as their group couldn't be deleted whole
+    pub restored: Vec<Operation>,
+    /// Groups that are left with some members deleted and some not
+    pub partial_groups: Vec<PathBuf>,
+}
+
+impl Outcome {
+    /// Each operation with what came of it ("planned", "deleted", ...), and the error if it
+    /// failed
+    pub fn results(&self) -> Vec<(&Operation, &'static str, Option<&io::Error>)> {
+        let categories = [
+            ("planned", &self.planned),
+            ("deleted", &self.deleted),
+            ("changed", &self.changed),
+            ("timed out", &self.timed_out),
+            ("restored", &self.restored),
+        ];
+        let mut results: Vec<_> = (categories.iter())
+            .flat_map(|&(result, operations)| operations.iter().map(move |o| (o, result, None)))
+            .collect();
+        results.extend((self.failed.iter()).map(|(o, why)| (o, "failed", Some(why))));
+        results
+    }
+}
+
+/// How operations are carried out. Shared by all workers.
+struct Context {
+    base_dir: PathBuf,
+    dirs: DirCache,
+    upload: Option<Box<dyn Backend>>,
+    trash: Option<Trash>,
+    dryrun: bool,
+    claim_suffix: Option<String>,
+}
+
+/// An operation by its index in the plan, with the file's checksum if one was computed
+type Job = (usize, Operation, Option<io::Result<String>>);
+
+fn upload_key(base_dir: &Path, path: &Path) -> String {
+    // The path relative to the rotated directory, '/'-separated
+    let relative = path.strip_prefix(base_dir).unwrap_or(path);
+    let parts: Vec<_> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+fn perform_operation(
+    context: &Context,
+    operation: &Operation,
+    sha256: Option<io::Result<String>>,
+    details: &mut Details,
+) -> OperationResult {
+    if context.dryrun {
+        return Ok(Status::Planned);
+    }
+    if deadline::passed() {
+        return Ok(Status::TimedOut);
+    }
+    if lease::lost() {
+        return Err(io::Error::other("Another host took over the lease"));
+    }
+    if let Some(sha256) = sha256 {
+        details.sha256 = Some(sha256?);
+    }
+    let claimed = match &context.claim_suffix {
+        Some(suffix) => match claim(operation, suffix)? {
+            Some(claimed) => Some(claimed),
+            None => return Ok(Status::Changed),
+        },
+        None => None,
+    };
+    let path = claimed.as_deref().unwrap_or(&operation.path);
+    // Without a verified copy, the file stays
+    if let Some(backend) = &context.upload {
+        let key = upload_key(&context.base_dir, &operation.path);
+        if let Err(why) = backend.upload(path, &key) {
+            if let Some(claimed) = &claimed {
+                unclaim(claimed, &operation.path);
+            }
+            return Err(why);
+        }
+        let destination = backend.destination(&key);
+        debug!(
+            "Uploaded file: {} -> {}",
+            operation.path.display(),
+            destination
+        );
+        details.destination = Some(destination);
+    }
+    if let Some(trash) = &context.trash {
+        return match trash.put(&operation.path, &operation.identity)? {
+            Some(target) => {
+                debug!(
+                    "Moved file to the trash: {} -> {}",
+                    operation.path.display(),
+                    target.display()
+                );
+                if details.destination.is_none() {
+                    details.destination = Some(target.display().to_string());
+                }
+                details.trashed = Some(target);
+                Ok(Status::Deleted)
+            }
+            None => Ok(Status::Changed),
+        };
+    }
+    let removed = context.dirs.remove_file(path, &operation.identity);
+    if let (Some(claimed), Ok(false) | Err(_)) = (&claimed, &removed) {
+        unclaim(claimed, &operation.path);
+    }
+    match removed? {
+        true => Ok(Status::Deleted),
+        false => Ok(Status::Changed),
+    }
+}
+
+fn claimed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut claimed = path.as_os_str().to_os_string();
+    claimed.push(suffix);
+    PathBuf::from(claimed)
+}
+
+fn claim(operation: &Operation, suffix: &str) -> io::Result<Option<PathBuf>> {
+    // Rename the file out of the writer's way, unless it's no longer the file of the scan
+    if FileIdentity::of(&fs::symlink_metadata(&operation.path)?) != operation.identity {
+        return Ok(None);
+    }
+    let claimed = claimed_path(&operation.path, suffix);
+    if claimed.symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is in the way", claimed.display()),
+        ));
+    }
+    fs::rename(&operation.path, &claimed)?;
+    // The writer may have put another file in place just before the rename
+    if FileIdentity::of(&fs::symlink_metadata(&claimed)?) != operation.identity {
+        unclaim(&claimed, &operation.path);
+        return Ok(None);
+    }
+    Ok(Some(claimed))
+}
+
+fn unclaim(claimed: &Path, path: &Path) {
+    // Give the file its name back, unless the writer has a new file by that name. A link
+    // never replaces anything, unlike a rename.
+    let result = fs::hard_link(claimed, path).and_then(|_| fs::remove_file(claimed));
+    if let Err(why) = result {
+        warn!(
+            "Could not give {} its name back: {}",
+            claimed.display(),
+            why
+        );
+    }
+}
+
+fn log_result(operation: &Operation, result: &OperationResult) {
+    let path = operation.path.display();
+    match result {
+        Ok(Status::Planned) => info!("Delete file: {}", path),
+        Ok(Status::Deleted) => info!("Deleted file: {}", path),
+        Ok(Status::Changed) => warn!("File changed since the scan, not deleting: {}", path),
+        Ok(Status::TimedOut) => debug!("Out of time, not deleting: {}", path),
+        Err(why) => warn!("Could not delete file: {} ({})", path, why),
+    }
+}
+
+fn audit_result(
+    audit_log: &mut AuditLog,
+    operation: &Operation,
+    result: &OperationResult,
+    details: &Details,
+) {
+    let (result, error) = match result {
+        Ok(Status::Planned) | Ok(Status::TimedOut) => return,
+        Ok(Status::Deleted) => ("deleted", None),
+        Ok(Status::Changed) => ("changed", None),
+        Err(why) => ("failed", Some(why.to_string())),
+    };
+    audit_log.record(&audit::Entry {
+        path: &operation.path,
+        size: operation.size,
+        modified: operation.modified,
+        result,
+        error,
+        sha256: details.sha256.as_deref(),
+        destination: details.destination.as_deref(),
+    });
+}
+
+type Report = (usize, Operation, OperationResult, Details);
+
+fn collect_results(
+    results: Receiver<Report>,
+    mut audit_log: Option<AuditLog>,
+    journal: Option<Arc<Mutex<Journal>>>,
+) -> Outcome {
+    // Workers finish out of order. Buffer results so that they are logged in plan order.
+    let mut outcome = Outcome::default();
+    let mut pending = BTreeMap::new();
+    let mut next_index = 0;
+    for (index, operation, result, details) in results {
+        pending.insert(index, (operation, result, details));
+        while let Some((operation, result, details)) = pending.remove(&next_index) {
+            log_result(&operation, &result);
+            if let Some(audit_log) = &mut audit_log {
+                audit_result(audit_log, &operation, &result, &details);
+            }
+            if let Some(journal) = &journal {
+                let done = match &result {
+                    Ok(Status::Planned) => "planned",
+                    Ok(Status::Deleted) => "deleted",
+                    Ok(Status::Changed) => "changed",
+                    Ok(Status::TimedOut) => "timed out",
+                    Err(_) => "failed",
+                };
+                (journal.lock().expect("Journal lock poisoned")).done(next_index, done);
+            }
+            if let (Ok(Status::Deleted), Some(trashed)) = (&result, details.trashed) {
+                outcome.trashed.insert(operation.path.clone(), trashed);
+            }
+            if let (Ok(Status::Deleted), Some(sha256)) = (&result, details.sha256) {
+                outcome.sha256.insert(operation.path.clone(), sha256);
+            }
+            match result {
+                Ok(Status::Planned) => outcome.planned.push(operation),
+                Ok(Status::Deleted) => outcome.deleted.push(operation),
+                Ok(Status::Changed) => outcome.changed.push(operation),
+                Ok(Status::TimedOut) => outcome.timed_out.push(operation),
+                Err(why) => outcome.failed.push((operation, why)),
+            }
+            next_index += 1;
+        }
+    }
+    outcome
+}
+
+fn log_summary(outcome: &Outcome) {
+    if !outcome.planned.is_empty() {
+        let size_planned: u64 = outcome.planned.iter().map(|op| op.size).sum();
+        info!(
+            "{}",
+            text(
+                "summary-planned",
+                &[("files", &outcome.planned.len()), ("bytes", &size_planned)]
+            )
+        );
+        return;
+    }
+    let size_freed: u64 = outcome.deleted.iter().map(|op| op.size).sum();
+    info!(
+        "{}",
+        text(
+            "summary-deleted",
+            &[("files", &outcome.deleted.len()), ("bytes", &size_freed)]
+        )
+    );
+    if !outcome.changed.is_empty() {
+        warn!(
+            "{}",
+            text("summary-changed", &[("files", &outcome.changed.len())])
+        );
+    }
+    if !outcome.timed_out.is_empty() {
+        let size_left: u64 = outcome.timed_out.iter().map(|op| op.size).sum();
+        warn!(
+            "{}",
+            text(
+                "summary-timed-out",
+                &[("files", &outcome.timed_out.len()), ("bytes", &size_left)]
+            )
+        );
+    }
+    if outcome.failed.is_empty() {
+        return;
+    }
+    let mut by_kind: HashMap<io::ErrorKind, usize> = HashMap::new();
+    for (_, why) in &outcome.failed {
+        *by_kind.entry(why.kind()).or_default() += 1;
+    }
+    let mut by_kind: Vec<String> = by_kind
+        .into_iter()
+        .map(|(kind, count)| format!("{}x {:?}", count, kind))
+        .collect();
+    by_kind.sort();
+    warn!(
+        "{}",
+        text(
+            "summary-failed",
+            &[
+                ("files", &outcome.failed.len()),
+                ("errors", &by_kind.join(", "))
+            ]
+        )
+    );
+}
+
+/// Find the groups that were only partly deleted, because some members failed, changed or
+/// were not started. With a trash to restore from, put their deleted members back.
+pub fn settle_groups(
+    outcome: &mut Outcome,
+    group_of: impl Fn(&Path) -> PathBuf,
+    restore_from: Option<&Trash>,
+    audit_log: Option<&Path>,
+) {
+    let mut kept: Vec<PathBuf> = outcome
+        .changed
+        .iter()
+        .chain(outcome.failed.iter().map(|(operation, _)| operation))
+        .chain(&outcome.timed_out)
+        .map(|operation| group_of(&operation.path))
+        .collect();
+    kept.sort();
+    kept.dedup();
+    let mut audit_log = audit_log.map(AuditLog::open);
+    let deleted = std::mem::take(&mut outcome.deleted);
+    for operation in deleted {
+        let group = group_of(&operation.path);
+        if kept.binary_search(&group).is_err() {
+            outcome.deleted.push(operation);
+            continue;
+        }
+        let restored = match (restore_from, outcome.trashed.get(&operation.path)) {
+            (Some(trash), Some(trashed)) => trash.restore(trashed, &operation.path),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "Not in the trash")),
+        };
+        match restored {
+            Ok(()) => {
+                info!("Restored file from the trash: {}", operation.path.display());
+                if let Some(audit_log) = &mut audit_log {
+                    audit_log.record(&audit::Entry {
+                        path: &operation.path,
+                        size: operation.size,
+                        modified: operation.modified,
+                        result: "restored",
+                        error: None,
+                        sha256: None,
+                        destination: None,
+                    });
+                }
+                outcome.restored.push(operation);
+            }
+            Err(why) => {
+                if restore_from.is_some() {
+                    warn!(
+                        "Could not restore file from the trash: {} ({})",
+                        operation.path.display(),
+                        why
+                    );
+                }
+                if !outcome.partial_groups.contains(&group) {
+                    outcome.partial_groups.push(group);
+                }
+                outcome.deleted.push(operation);
+            }
+        }
+    }
+    for group in &outcome.partial_groups {
+        warn!("Group partially deleted: {}", group.display());
+    }
+}
+
+/// How many operations to journal at once
+const JOURNAL_BATCH: usize = 4096;
+
+/// Performs operations on a pool of worker threads. Deletions are blocking syscalls, so
+/// on network filesystems the latency of one unlink is hidden behind the others. With
+/// checksums, a second pool reads and hashes files ahead of the deletions.
+pub struct Executor {
+    sender: Sender<Job>,
+    /// Where operations go to be checksummed first
+    hash_sender: Option<Sender<(usize, Operation)>>,
+    submitted: usize,
+    hashers: Vec<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+    collector: JoinHandle<Outcome>,
+    journal: Option<Arc<Mutex<Journal>>>,
+    /// Operations waiting to be journaled, by their index
+    pending: Vec<(usize, Operation)>,
+}
+
+impl Executor {
+    pub fn new(base_dir: &Path, options: Options) -> Executor {
+        let context = Arc::new(Context {
+            base_dir: base_dir.to_path_buf(),
+            dirs: DirCache::new(base_dir),
+            upload: options.upload,
+            trash: options.trash,
+            dryrun: options.dryrun,
+            claim_suffix: options.claim_suffix,
+        });
+        // Dry-runs don't do anything worth auditing
+        let audit_log = match (&options.audit_log, options.dryrun) {
+            (Some(path), false) => Some(AuditLog::open(path)),
+            _ => None,
+        };
+        let journal = match (&options.journal, options.dryrun) {
+            (Some(path), false) => match Journal::create(path) {
+                Ok(journal) => Some(Arc::new(Mutex::new(journal))),
+                Err(why) => {
+                    error!(
+                        "Could not start the journal {}, so an interrupted run won't be told apart: {}",
+                        path.display(),
+                        why
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (result_sender, result_receiver) = channel();
+        let workers = (0..options.concurrency.max(1))
+            .map(|worker| {
+                let receiver = Arc::clone(&receiver);
+                let context = Arc::clone(&context);
+                let result_sender = result_sender.clone();
+                thread::spawn(move || {
+                    let (mut succeeded, mut failed) = (0, 0);
+                    loop {
+                        // Only hold the lock while receiving, not while deleting
+                        let next = receiver.lock().expect("Executor lock poisoned").recv();
+                        let (index, operation, sha256) = match next {
+                            Ok(next) => next,
+                            Err(_) => break,
+                        };
+                        let mut details = Details::default();
+                        let result = perform_operation(&context, &operation, sha256, &mut details);
+                        if result.is_ok() {
+                            succeeded += 1;
+                        } else {
+                            failed += 1;
+                        }
+                        let _ = result_sender.send((index, operation, result, details));
+                    }
+                    debug!(
+                        "Worker {}: {} succeeded, {} failed",
+                        worker, succeeded, failed
+                    );
+                })
+            })
+            .collect();
+        let (hash_sender, hashers) = match (options.checksum, options.dryrun) {
+            (true, false) => {
+                let (hash_sender, hash_receiver) = channel::<(usize, Operation)>();
+                let hash_receiver = Arc::new(Mutex::new(hash_receiver));
+                let hashers = (0..options.hash_threads.max(1))
+                    .map(|_| {
+                        let hash_receiver = Arc::clone(&hash_receiver);
+                        let sender = sender.clone();
+                        thread::spawn(move || loop {
+                            let next = hash_receiver.lock().expect("Hasher lock poisoned").recv();
+                            let (index, operation) = match next {
+                                Ok(next) => next,
+                                Err(_) => break,
+                            };
+                            // Out of time, the operation won't be carried out anyway
+                            let sha256 =
+                                (!deadline::passed()).then(|| sha256_file(&operation.path));
+                            let _ = sender.send((index, operation, sha256));
+                        })
+                    })
+                    .collect();
+                (Some(hash_sender), hashers)
+            }
+            _ => (None, Vec::new()),
+        };
+        let collector_journal = journal.clone();
+        let collector =
+            thread::spawn(move || collect_results(result_receiver, audit_log, collector_journal));
+        Executor {
+            sender,
+            hash_sender,
+            submitted: 0,
+            hashers,
+            workers,
+            collector,
+            journal,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn submit(&mut self, operation: Operation) {
+        let index = self.submitted;
+        self.submitted += 1;
+        match &self.journal {
+            // Nothing is carried out before it's journaled
+            Some(_) => {
+                self.pending.push((index, operation));
+                if self.pending.len() >= JOURNAL_BATCH {
+                    self.flush();
+                }
+            }
+            None => self.send(index, operation),
+        }
+    }
+
+    fn send(&self, index: usize, operation: Operation) {
+        match &self.hash_sender {
+            Some(hash_sender) => hash_sender
+                .send((index, operation))
+                .expect("All executor hashers stopped"),
+            None => self
+                .sender
+                .send((index, operation, None))
+                .expect("All executor workers stopped"),
+        }
+    }
+
+    /// Journal the pending operations, then carry them out
+    fn flush(&mut self) {
+        if let Some(journal) = &self.journal {
+            (journal.lock().expect("Journal lock poisoned")).planned(&self.pending);
+        }
+        for (index, operation) in std::mem::take(&mut self.pending) {
+            self.send(index, operation);
+        }
+    }
+
+    /// Wait for all submitted operations and log a summary
+    pub fn finish(mut self) -> Outcome {
+        self.flush();
+        drop(self.hash_sender);
+        for hasher in self.hashers {
+            hasher.join().expect("Executor hasher panicked");
+        }
+        drop(self.sender);
+        for worker in self.workers {
+            worker.join().expect("Executor worker panicked");
+        }
+        let outcome = self.collector.join().expect("Executor collector panicked");
+        if let Some(journal) = self.journal {
+            match Arc::try_unwrap(journal) {
+                Ok(journal) => journal
+                    .into_inner()
+                    .expect("Journal lock poisoned")
+                    .finish(),
+                Err(_) => unreachable!("The collector is done with the journal"),
+            }
+        }
+        log_summary(&outcome);
+        outcome
+    }
+}
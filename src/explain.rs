@@ -0,0 +1,102 @@
+//! Why a dry-run deletes or keeps each file (--explain)
+use crate::executor::Operation;
+use crate::groups::{format_age, format_duration, stem_group};
+use crate::record::FileRecord;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A plan, with what it was made for
+pub struct Plan<'a> {
+    pub operations: &'a [Operation],
+    /// How many of the operations, from the first, are for expired files
+    pub expired: usize,
+    pub max_age: Option<Duration>,
+    /// What the rest of the operations are to free for --max-size
+    pub size_to_free: u64,
+    pub group: bool,
+}
+
+fn relative<'a>(base_dir: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(base_dir).unwrap_or(path)
+}
+
+/// Print why each planned file goes and why each of the scanned files that is not a
+/// candidate stays. The candidates that simply weren't needed are summed up in one line.
+pub fn print_explanation(
+    base_dir: &Path,
+    plan: &Plan,
+    files: &[FileRecord],
+    kept_groups: &HashMap<PathBuf, &'static str>,
+    reserved: &HashSet<PathBuf>,
+) {
+    let mut rank = 0;
+    let mut freed = 0;
+    let mut previous_group = None;
+    for (i, operation) in plan.operations.iter().enumerate() {
+        let path = relative(base_dir, &operation.path);
+        let group = plan.group.then(|| stem_group(&operation.path));
+        let age = format_age(operation.modified);
+        if i < plan.expired {
+            let max_age = plan.max_age.map_or(0, |max_age| max_age.as_secs());
+            print!(
+                "delete {}: modified {} ago, older than --max-age {}",
+                path.display(),
+                age,
+                format_duration(max_age)
+            );
+        } else {
+            // A group is one unit, so all its members share a rank
+            if group.is_none() || group != previous_group {
+                rank += 1;
+            }
+            freed += operation.size;
+            print!(
+                "delete {}: modified {} ago, candidate #{} in deletion order, bringing the plan \
+                 to {} of the {} bytes to free for --max-size",
+                path.display(),
+                age,
+                rank,
+                freed.min(plan.size_to_free),
+                plan.size_to_free
+            );
+        }
+        match &group {
+            Some(group) => println!(", with its group {}", relative(base_dir, group).display()),
+            None => println!(),
+        }
+        previous_group = group;
+    }
+
+    let planned: HashSet<&Path> = (plan.operations.iter())
+        .map(|operation| operation.path.as_path())
+        .collect();
+    let (mut spared, mut spared_bytes) = (0, 0);
+    for file in files.iter().filter(|x| !planned.contains(x.path.as_path())) {
+        let why = file
+            .kept_by
+            .or_else(|| match plan.group {
+                true => kept_groups.get(&stem_group(&file.path)).copied(),
+                false => None,
+            })
+            .or_else(|| (reserved.contains(&file.path)).then_some("within its --reserve"));
+        match why {
+            Some(why) => println!("keep {}: {}", relative(base_dir, &file.path).display(), why),
+            None => {
+                spared += 1;
+                spared_bytes += file.len;
+            }
+        }
+    }
+    if spared > 0 {
+        println!(
+            "keep {} other candidates of {} bytes: {}",
+            spared,
+            spared_bytes,
+            match plan.size_to_free {
+                0 => "not older than --max-age",
+                _ => "newer than the planned ones, and not needed for --max-size",
+            }
+        );
+    }
+}
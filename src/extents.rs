@@ -0,0 +1,122 @@
+use std::io;
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+mod fiemap {
+    pub const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    pub const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    pub const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    pub const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    /// Extents to fetch per ioctl
+    pub const BATCH: usize = 64;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub struct Extent {
+        pub logical: u64,
+        pub physical: u64,
+        pub length: u64,
+        pub reserved64: [u64; 2],
+        pub flags: u32,
+        pub reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    pub struct Request {
+        pub start: u64,
+        pub length: u64,
+        pub flags: u32,
+        pub mapped_extents: u32,
+        pub extent_count: u32,
+        pub reserved: u32,
+        pub extents: [Extent; BATCH],
+    }
+}
+
+/// Bytes of the file that no other file shares (through reflinks or deduplication),
+/// which is what deleting it gives back. Holes don't count either.
+#[cfg(target_os = "linux")]
+pub fn unshared_size(path: &Path) -> io::Result<u64> {
+    use fiemap::*;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    let file = File::open(path)?;
+    let mut request = Request {
+        start: 0,
+        length: u64::MAX,
+        flags: FIEMAP_FLAG_SYNC,
+        mapped_extents: 0,
+        extent_count: BATCH as u32,
+        reserved: 0,
+        extents: [Extent::default(); BATCH],
+    };
+    let mut unshared = 0;
+    loop {
+        request.length = u64::MAX - request.start;
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut request) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let extents = &request.extents[..request.mapped_extents as usize];
+        for extent in extents {
+            if extent.flags & FIEMAP_EXTENT_SHARED == 0 {
+                unshared += extent.length;
+            }
+        }
+        match extents.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                request.start = last.logical + last.length;
+            }
+            _ => return Ok(unshared),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn unshared_size(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "No extent maps on this platform",
+    ))
+}
+
+/// The ranges (offset, length) of the file from `start` to `len` that hold data, skipping
+/// holes. Where holes can't be found, that is all of it.
+#[cfg(target_os = "linux")]
+pub fn data_segments(file: &std::fs::File, start: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+    let seek = |offset: u64, whence| unsafe {
+        libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence)
+    };
+    let mut segments = Vec::new();
+    let mut offset = start;
+    while offset < len {
+        let data = seek(offset, libc::SEEK_DATA);
+        if data < 0 {
+            return match io::Error::last_os_error() {
+                // No data past the offset
+                why if why.raw_os_error() == Some(libc::ENXIO) => Ok(segments),
+                why if why.raw_os_error() == Some(libc::EINVAL) => {
+                    segments.push((offset, len - offset));
+                    Ok(segments)
+                }
+                why => Err(why),
+            };
+        }
+        let hole = seek(data as u64, libc::SEEK_HOLE);
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (data, hole) = (data as u64, (hole as u64).min(len));
+        if data >= len {
+            break;
+        }
+        segments.push((data, hole - data));
+        offset = hole;
+    }
+    Ok(segments)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_segments(_file: &std::fs::File, start: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    Ok(vec![(start, len.saturating_sub(start))])
+}
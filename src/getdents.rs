@@ -0,0 +1,216 @@
+//! A directory walk that reads entries with getdents64 into a large buffer
+//! (--dir-reader getdents). Huge flat directories take far fewer syscalls than with
+//! readdir, and the entry types come with the names, so only entries of unknown type
+//! are stat'ed. On other platforms than Linux, directories are read with std's read_dir.
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static ENABLED: OnceLock<()> = OnceLock::new();
+
+/// Walk with this reader instead of walkdir for the rest of the run
+pub fn enable() {
+    let _ = ENABLED.set(());
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.get().is_some()
+}
+
+/// What a directory entry is, not following symlinks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+#[cfg(target_os = "linux")]
+mod raw {
+    use super::Kind;
+    use std::ffi::OsString;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    const BUFFER_SIZE: usize = 256 * 1024;
+
+    /// The entries of one directory, read a buffer at a time
+    pub struct DirStream {
+        dir: File,
+        buffer: Vec<u8>,
+        pos: usize,
+        len: usize,
+    }
+
+    impl DirStream {
+        pub fn open(path: &Path) -> io::Result<DirStream> {
+            Ok(DirStream {
+                dir: File::open(path)?,
+                buffer: vec![0; BUFFER_SIZE],
+                pos: 0,
+                len: 0,
+            })
+        }
+
+        fn fill(&mut self) -> io::Result<bool> {
+            let read = unsafe {
+                libc::syscall(
+                    libc::SYS_getdents64,
+                    self.dir.as_raw_fd(),
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len(),
+                )
+            };
+            if read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.pos = 0;
+            self.len = read as usize;
+            Ok(read > 0)
+        }
+
+        /// The next entry (other than . and ..) and its type, if the filesystem knows it.
+        /// Nothing at the end of the directory.
+        pub fn next_entry(&mut self) -> io::Result<Option<(OsString, Option<Kind>)>> {
+            loop {
+                if self.pos >= self.len && !self.fill()? {
+                    return Ok(None);
+                }
+                // struct linux_dirent64: d_ino (8), d_off (8), d_reclen (2), d_type (1), d_name
+                let record = &self.buffer[self.pos..self.len];
+                let reclen = u16::from_ne_bytes([record[16], record[17]]) as usize;
+                let kind = match record[18] {
+                    libc::DT_REG => Some(Kind::File),
+                    libc::DT_DIR => Some(Kind::Dir),
+                    libc::DT_LNK => Some(Kind::Symlink),
+                    libc::DT_UNKNOWN => None,
+                    _ => Some(Kind::Other),
+                };
+                let name = &record[19..reclen];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                self.pos += reclen;
+                if name != b"." && name != b".." {
+                    return Ok(Some((OsString::from_vec(name.to_vec()), kind)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod raw {
+    use super::Kind;
+    use std::ffi::OsString;
+    use std::fs::{self, ReadDir};
+    use std::io;
+    use std::path::Path;
+
+    pub struct DirStream(ReadDir);
+
+    impl DirStream {
+        pub fn open(path: &Path) -> io::Result<DirStream> {
+            Ok(DirStream(fs::read_dir(path)?))
+        }
+
+        pub fn next_entry(&mut self) -> io::Result<Option<(OsString, Option<Kind>)>> {
+            let entry = match self.0.next() {
+                Some(entry) => entry?,
+                None => return Ok(None),
+            };
+            let kind = entry.file_type().ok().map(|t| {
+                if t.is_file() {
+                    Kind::File
+                } else if t.is_dir() {
+                    Kind::Dir
+                } else if t.is_symlink() {
+                    Kind::Symlink
+                } else {
+                    Kind::Other
+                }
+            });
+            Ok(Some((entry.file_name(), kind)))
+        }
+    }
+}
+
+fn kind_of(path: &Path) -> io::Result<Kind> {
+    let file_type = path.symlink_metadata()?.file_type();
+    Ok(if file_type.is_file() {
+        Kind::File
+    } else if file_type.is_dir() {
+        Kind::Dir
+    } else if file_type.is_symlink() {
+        Kind::Symlink
+    } else {
+        Kind::Other
+    })
+}
+
+fn annotate(path: &Path, why: io::Error) -> io::Error {
+    io::Error::new(why.kind(), format!("{}: {}", path.display(), why))
+}
+
+/// The entries below a directory other than directories, depth first. Entries for which
+/// `skip` is true are left out; for a directory, that's everything in it.
+pub struct Walk<F> {
+    stack: Vec<(PathBuf, raw::DirStream)>,
+    pending_error: Option<io::Error>,
+    skip: F,
+}
+
+pub fn walk<F: Fn(&Path) -> bool>(root: &Path, skip: F) -> Walk<F> {
+    let (stack, pending_error) = match raw::DirStream::open(root) {
+        Ok(stream) => (vec![(root.to_path_buf(), stream)], None),
+        Err(why) => (Vec::new(), Some(annotate(root, why))),
+    };
+    Walk {
+        stack,
+        pending_error,
+        skip,
+    }
+}
+
+impl<F: Fn(&Path) -> bool> Iterator for Walk<F> {
+    type Item = io::Result<(PathBuf, Kind)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(why) = self.pending_error.take() {
+            return Some(Err(why));
+        }
+        loop {
+            let (dir, stream) = self.stack.last_mut()?;
+            let (name, kind): (OsString, Option<Kind>) = match stream.next_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    self.stack.pop();
+                    continue;
+                }
+                Err(why) => {
+                    let why = annotate(dir, why);
+                    self.stack.pop();
+                    return Some(Err(why));
+                }
+            };
+            let path = dir.join(name);
+            if (self.skip)(&path) {
+                continue;
+            }
+            let kind = match kind.map_or_else(|| kind_of(&path), Ok) {
+                Ok(kind) => kind,
+                Err(why) => return Some(Err(annotate(&path, why))),
+            };
+            if kind != Kind::Dir {
+                return Some(Ok((path, kind)));
+            }
+            match raw::DirStream::open(&path) {
+                Ok(stream) => self.stack.push((path, stream)),
+                Err(why) => return Some(Err(annotate(&path, why))),
+            }
+        }
+    }
+}
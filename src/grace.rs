@@ -0,0 +1,189 @@
+use crate::executor::Operation;
+use crate::state::{format_time, parse_time};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// A file selected for deletion, as it was when it was first selected
+struct Mark {
+    marked_at: SystemTime,
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Files that have been selected for deletion, but are still in their grace period
+#[derive(Default)]
+pub struct GraceMarks {
+    marks: HashMap<PathBuf, Mark>,
+}
+
+impl GraceMarks {
+    pub fn load(path: &Path) -> GraceMarks {
+        let mut grace_marks = GraceMarks::default();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return grace_marks,
+        };
+        for line in content.lines() {
+            let mut fields = line.splitn(4, '\t');
+            let parsed = (|| {
+                let marked_at = parse_time(fields.next()?)?;
+                let modified = parse_time(fields.next()?)?;
+                let size = fields.next()?.parse().ok()?;
+                let path = PathBuf::from(fields.next()?);
+                Some((
+                    path,
+                    Mark {
+                        marked_at,
+                        modified,
+                        size,
+                    },
+                ))
+            })();
+            match parsed {
+                Some((path, mark)) => {
+                    grace_marks.marks.insert(path, mark);
+                }
+                None => warn!("Ignoring malformed line in grace file: {}", line),
+            }
+        }
+        grace_marks
+    }
+
+    pub fn save(&self, path: &Path) {
+        let mut content = String::new();
+        for (file, mark) in &self.marks {
+            // A file that can't be represented loses its mark, and gets a new grace period
+            match file.to_str() {
+                Some(file) if !file.contains('\n') => content.push_str(&format!(
+                    "{}\t{}\t{}\t{}\n",
+                    format_time(mark.marked_at),
+                    format_time(mark.modified),
+                    mark.size,
+                    file
+                )),
+                _ => debug!("Not saving grace mark for {}", file.display()),
+            }
+        }
+        let tmp_path = path.with_extension("tmp");
+        let result = fs::write(&tmp_path, content).and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(why) = result {
+            warn!("Could not save grace marks to {}: {}", path.display(), why);
+        }
+    }
+
+    /// Returns the operations whose grace period is over. The rest of the plan is marked,
+    /// and kept for now. A file that was modified after it was marked starts over, and
+    /// marks of files that are no longer planned are dropped.
+    pub fn apply(
+        &mut self,
+        operations: Vec<Operation>,
+        grace: Duration,
+        now: SystemTime,
+    ) -> Vec<Operation> {
+        let mut previous = std::mem::take(&mut self.marks);
+        let mut due = Vec::new();
+        let mut newly_marked = 0;
+        for op in operations {
+            let mark = match previous.remove(&op.path) {
+                Some(mark) if mark.modified == op.modified && mark.size == op.size => mark,
+                _ => {
+                    debug!("Marking file for deletion: {}", op.path.display());
+                    newly_marked += 1;
+                    Mark {
+                        marked_at: now,
+                        modified: op.modified,
+                        size: op.size,
+                    }
+                }
+            };
+            let elapsed = now.duration_since(mark.marked_at).unwrap_or_default();
+            if elapsed >= grace {
+                due.push(op);
+            } else {
+                self.marks.insert(op.path, mark);
+            }
+        }
+        info!(
+            "Grace period: {} files due for deletion, {} newly marked, {} still waiting",
+            due.len(),
+            newly_marked,
+            self.marks.len() - newly_marked
+        );
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn operation(path: &Path) -> Operation {
+        Operation::new(path.to_path_buf(), &fs::metadata(path).unwrap())
+    }
+
+    #[test]
+    fn deletes_once_the_grace_period_is_over() {
+        let dir = TestDir::new("grace");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, b"data").unwrap();
+        fs::write(&b, b"data").unwrap();
+        let (start, grace_file) = (SystemTime::now(), dir.join("grace"));
+
+        // Marked on the first run, and still waiting an hour later
+        let mut marks = GraceMarks::load(&grace_file);
+        assert!(marks.apply(vec![operation(&a)], 2 * HOUR, start).is_empty());
+        marks.save(&grace_file);
+        let mut marks = GraceMarks::load(&grace_file);
+        assert!(marks
+            .apply(vec![operation(&a)], 2 * HOUR, start + HOUR)
+            .is_empty());
+        marks.save(&grace_file);
+
+        // Due after two hours, while b was only marked now
+        let mut marks = GraceMarks::load(&grace_file);
+        let operations = vec![operation(&a), operation(&b)];
+        let due = marks.apply(operations, 2 * HOUR, start + 2 * HOUR);
+        assert_eq!(due.iter().map(|op| &op.path).collect::<Vec<_>>(), [&a]);
+    }
+
+    #[test]
+    fn starts_over_when_modified_or_no_longer_planned() {
+        let dir = TestDir::new("grace-over");
+        let a = dir.join("a");
+        fs::write(&a, b"data").unwrap();
+        let start = SystemTime::now();
+        let mut marks = GraceMarks::default();
+        assert!(marks.apply(vec![operation(&a)], HOUR, start).is_empty());
+
+        // Grown since it was marked
+        fs::write(&a, b"more data").unwrap();
+        assert!(marks
+            .apply(vec![operation(&a)], HOUR, start + HOUR)
+            .is_empty());
+        // Not planned for a run, so the mark is gone
+        assert!(marks.apply(vec![], HOUR, start + 2 * HOUR).is_empty());
+        assert!(marks
+            .apply(vec![operation(&a)], HOUR, start + 2 * HOUR)
+            .is_empty());
+        assert_eq!(
+            marks
+                .apply(vec![operation(&a)], HOUR, start + 3 * HOUR)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let dir = TestDir::new("grace-bad");
+        let grace_file = dir.join("grace");
+        fs::write(&grace_file, "not a mark\n1.0\t2.0\tbig\t/data/a\n").unwrap();
+        assert!(GraceMarks::load(&grace_file).marks.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::clock;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The group of a file: its directory and the part of its name before the first dot,
+/// so that data.csv, data.json and data.tar.gz are one group
+pub fn stem_group(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    let stem = match name.find('.') {
+        Some(0) | None => &name[..],
+        Some(dot) => &name[..dot],
+    };
+    path.with_file_name(OsString::from(stem))
+}
+
+/// Group the items by the stem of their path, in the order the groups first appear
+pub fn group_by_stem<T>(items: Vec<T>, path: impl Fn(&T) -> &Path) -> Vec<Vec<T>> {
+    let mut index: HashMap<PathBuf, usize> = HashMap::new();
+    let mut groups: Vec<Vec<T>> = Vec::new();
+    for item in items {
+        let group = stem_group(path(&item));
+        match index.get(&group) {
+            Some(&i) => groups[i].push(item),
+            None => {
+                index.insert(group, groups.len());
+                groups.push(vec![item]);
+            }
+        }
+    }
+    groups
+}
+
+/// The groups that have members that aren't candidates, and so can't go as a whole
+pub fn split_groups<'a>(
+    files: impl Iterator<Item = &'a Path>,
+    candidates: &HashSet<&Path>,
+) -> HashSet<PathBuf> {
+    files
+        .filter(|path| !candidates.contains(path))
+        .map(stem_group)
+        .collect()
+}
+
+/// The required suffixes (e.g. ".bin") that none of the members' names end with
+pub fn missing_members<'a, 'b>(
+    members: impl Iterator<Item = &'b Path>,
+    required: &'a [String],
+) -> Vec<&'a str> {
+    let names: Vec<String> = members
+        .filter_map(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    required
+        .iter()
+        .filter(|suffix| !names.iter().any(|name| name.ends_with(suffix.as_str())))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Files (or directories, or groups) summed up
+pub struct Total {
+    pub size: u64,
+    pub files: usize,
+    pub oldest: SystemTime,
+    pub newest: SystemTime,
+}
+
+impl Total {
+    pub fn new(modified: SystemTime) -> Total {
+        Total {
+            size: 0,
+            files: 0,
+            oldest: modified,
+            newest: modified,
+        }
+    }
+
+    pub fn add(&mut self, size: u64, modified: SystemTime) {
+        self.size += size;
+        self.files += 1;
+        self.oldest = self.oldest.min(modified);
+        self.newest = self.newest.max(modified);
+    }
+}
+
+pub fn format_duration(secs: u64) -> String {
+    // Only the largest unit; this is for getting an overview
+    match secs {
+        s if s >= 86400 => format!("{}d", s / 86400),
+        s if s >= 3600 => format!("{}h", s / 3600),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+pub fn format_age(modified: SystemTime) -> String {
+    format_duration(
+        clock::now()
+            .duration_since(modified)
+            .unwrap_or_default()
+            .as_secs(),
+    )
+}
+
+/// Print every group of files with the same stem, for checking that the grouping matches
+/// how the files are named
+pub fn print_group_report(
+    base_dir: &Path,
+    files: &[(PathBuf, u64, SystemTime)],
+    required_members: &[String],
+) {
+    let mut groups: HashMap<PathBuf, (Total, Vec<&Path>)> = HashMap::new();
+    for (path, size, modified) in files {
+        let (total, members) = groups
+            .entry(stem_group(path))
+            .or_insert_with(|| (Total::new(*modified), Vec::new()));
+        total.add(*size, *modified);
+        members.push(path);
+    }
+    let mut groups: Vec<(PathBuf, Total, Vec<&str>)> = groups
+        .into_iter()
+        .map(|(group, (total, members))| {
+            let missing = missing_members(members.into_iter(), required_members);
+            (group, total, missing)
+        })
+        .collect();
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+    let singles = groups.iter().filter(|g| g.1.files == 1).count();
+    let incomplete = groups.iter().filter(|g| !g.2.is_empty()).count();
+    println!(
+        "{} groups, {} of them with a single file, {} incomplete",
+        groups.len(),
+        singles,
+        incomplete
+    );
+    println!("  members  size        oldest  newest  spread  group");
+    for (group, total, missing) in &groups {
+        let spread = total
+            .newest
+            .duration_since(total.oldest)
+            .unwrap_or_default()
+            .as_secs();
+        println!(
+            "  {:<7}  {:<10}  {:<6}  {:<6}  {:<6}  {}",
+            total.files,
+            total.size,
+            format_age(total.oldest),
+            format_age(total.newest),
+            format_duration(spread),
+            group.strip_prefix(base_dir).unwrap_or(group).display()
+        );
+        if !missing.is_empty() {
+            println!("      missing: {}", missing.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+
+    #[test]
+    fn groups_by_the_name_before_the_first_dot() {
+        let stem = |path: &str| stem_group(Path::new(path));
+        assert_eq!(stem("/data/run1.csv"), Path::new("/data/run1"));
+        assert_eq!(stem("/data/run1.tar.gz"), Path::new("/data/run1"));
+        assert_eq!(stem("/data/run1"), Path::new("/data/run1"));
+        // Hidden files are their own group
+        assert_eq!(stem("/data/.run1.csv"), Path::new("/data/.run1.csv"));
+    }
+
+    #[test]
+    fn keeps_groups_in_the_order_they_appear() {
+        let paths = ["a/x.csv", "b.csv", "a/x.json", "b.json", "a/y.csv"];
+        let groups = group_by_stem(paths.to_vec(), |path| Path::new(path));
+        assert_eq!(
+            groups,
+            [
+                vec!["a/x.csv", "a/x.json"],
+                vec!["b.csv", "b.json"],
+                vec!["a/y.csv"]
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_groups_with_members_that_stay() {
+        let files = [Path::new("a.csv"), Path::new("a.json"), Path::new("b.csv")];
+        let candidates: HashSet<&Path> = [files[0], files[2]].iter().copied().collect();
+        let split = split_groups(files.iter().copied(), &candidates);
+        assert_eq!(split, iter::once(PathBuf::from("a")).collect());
+    }
+
+    #[test]
+    fn finds_missing_members() {
+        let required = [String::from(".bin"), String::from(".json")];
+        let members = [Path::new("/data/run1.bin"), Path::new("/data/run1.csv")];
+        assert_eq!(
+            missing_members(members.iter().copied(), &required),
+            [".json"]
+        );
+    }
+
+    #[test]
+    fn formats_the_largest_unit() {
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(3599), "59m");
+        assert_eq!(format_duration(2 * 86400 + 3600), "2d");
+    }
+}
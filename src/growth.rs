@@ -0,0 +1,40 @@
+//! How much the directory grew between the scan and the deletions, e.g. from a burst of
+//! writes during a long scan. Only the directories of the newest files are looked at again,
+//! as that is where writers are busy.
+use crate::record::FileRecord;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// How many of the newest files to look at the directories of
+const SAMPLE: usize = 64;
+
+/// The bytes that the directories of the newest files gained since the scan: files that
+/// grew and files that are new. Only files that are counted are.
+pub fn growth_since_scan(files: &[FileRecord], is_counted: impl Fn(&Path) -> bool) -> u64 {
+    let mut newest: Vec<&FileRecord> = files.iter().collect();
+    newest.sort_by_key(|x| std::cmp::Reverse(x.modified));
+    let dirs: HashSet<&Path> = (newest.iter().take(SAMPLE))
+        .filter_map(|x| x.path.parent())
+        .collect();
+    let scanned: HashMap<&Path, u64> = (files.iter())
+        .filter(|x| x.path.parent().is_some_and(|parent| dirs.contains(parent)))
+        .map(|x| (x.path.as_path(), x.len))
+        .collect();
+    let mut growth = 0;
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') || !is_counted(&path) {
+                continue;
+            }
+            let size = entry.metadata().map_or(0, |metadata| metadata.len());
+            growth += size.saturating_sub(scanned.get(path.as_path()).copied().unwrap_or(0));
+        }
+    }
+    growth
+}
@@ -0,0 +1,329 @@
+use crate::groups::format_duration;
+use crate::state::{format_time, parse_time};
+use log::warn;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const HEADER: &str = "dirrotate-history 2";
+/// Version 1 didn't have the retained size
+const HEADER_V1: &str = "dirrotate-history 1";
+
+/// One run, as kept in the history file
+pub struct Record {
+    pub time: SystemTime,
+    pub directory: PathBuf,
+    /// Sizes of the (included) files, if they were measured
+    pub size_before: Option<u64>,
+    pub size_after: Option<u64>,
+    pub limit: Option<u64>,
+    /// Size of the files that rotation isn't allowed to delete (protected, filtered out, ...)
+    pub retained: Option<u64>,
+    pub deleted: usize,
+    pub freed: u64,
+    pub failed: usize,
+    pub duration: Duration,
+}
+
+fn format_optional(value: Option<u64>) -> String {
+    value.map_or_else(|| String::from("-"), |v| v.to_string())
+}
+
+fn parse_optional(s: &str) -> Option<Option<u64>> {
+    match s {
+        "-" => Some(None),
+        s => s.parse().ok().map(Some),
+    }
+}
+
+/// Add the run to the end of the history file, creating it if needed
+pub fn append(path: &Path, record: &Record) {
+    let directory = match record.directory.to_str() {
+        Some(directory) if !directory.contains('\n') => directory,
+        _ => {
+            warn!("Not recording history for {}", record.directory.display());
+            return;
+        }
+    };
+    let line = format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.3}\t{}\n",
+        format_time(record.time),
+        format_optional(record.size_before),
+        format_optional(record.size_after),
+        format_optional(record.limit),
+        format_optional(record.retained),
+        record.deleted,
+        record.freed,
+        record.failed,
+        record.duration.as_secs_f64(),
+        directory
+    );
+    let is_new = !path.exists();
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| {
+            if is_new {
+                writeln!(file, "{}", HEADER)?;
+            }
+            file.write_all(line.as_bytes())
+        });
+    if let Err(why) = written {
+        warn!("Could not write history to {}: {}", path.display(), why);
+    }
+}
+
+/// All runs in the history file, oldest first
+pub fn load(path: &Path) -> Result<Vec<Record>, String> {
+    let content = fs::read_to_string(path).map_err(|why| why.to_string())?;
+    let mut lines = content.lines();
+    let has_retained = match lines.next() {
+        Some(HEADER) => true,
+        Some(HEADER_V1) => false,
+        _ => return Err("Not a dirrotate history file".to_string()),
+    };
+    let mut records = Vec::new();
+    for line in lines {
+        let mut fields = line.splitn(if has_retained { 10 } else { 9 }, '\t');
+        let parsed = (|| {
+            Some(Record {
+                time: parse_time(fields.next()?)?,
+                size_before: parse_optional(fields.next()?)?,
+                size_after: parse_optional(fields.next()?)?,
+                limit: parse_optional(fields.next()?)?,
+                retained: match has_retained {
+                    true => parse_optional(fields.next()?)?,
+                    false => None,
+                },
+                deleted: fields.next()?.parse().ok()?,
+                freed: fields.next()?.parse().ok()?,
+                failed: fields.next()?.parse().ok()?,
+                duration: Duration::from_secs_f64(fields.next()?.parse().ok()?),
+                directory: PathBuf::from(fields.next()?),
+            })
+        })();
+        match parsed {
+            Some(record) => records.push(record),
+            None => warn!("Ignoring malformed line in history file: {}", line),
+        }
+    }
+    Ok(records)
+}
+
+/// How fast data comes into the directory, in bytes per second: what was added between
+/// the end of one run and the start of the next, averaged over the given runs
+/// (of a single directory, oldest first).
+pub fn intake_rate(records: &[&Record]) -> Option<f64> {
+    let mut added: i128 = 0;
+    let mut elapsed = 0.0;
+    for pair in records.windows(2) {
+        if let (Some(after), Some(before)) = (pair[0].size_after, pair[1].size_before) {
+            if let Ok(dt) = pair[1].time.duration_since(pair[0].time) {
+                added += before as i128 - after as i128;
+                elapsed += dt.as_secs_f64();
+            }
+        }
+    }
+    if elapsed > 0.0 {
+        Some(added as f64 / elapsed)
+    } else {
+        None
+    }
+}
+
+/// When the directory will be over its limit even though rotation deletes all it may
+pub enum Forecast {
+    /// Too few runs with the retained size measured
+    Unknown,
+    /// The retained files don't grow
+    Stable,
+    /// The retained files alone are over the limit
+    OverBudget,
+    Exceeds(SystemTime),
+}
+
+/// Extrapolate the growth of the retained files (of a single directory, oldest first)
+/// to the limit of the latest run
+pub fn forecast(records: &[&Record]) -> Forecast {
+    let measured: Vec<(SystemTime, u64, u64)> = records
+        .iter()
+        .filter_map(|r| Some((r.time, r.retained?, r.limit?)))
+        .collect();
+    let (first, last) = match (measured.first(), measured.last()) {
+        (Some(first), Some(last)) if measured.len() >= 2 => (first, last),
+        _ => return Forecast::Unknown,
+    };
+    let (time, retained, limit) = *last;
+    if retained > limit {
+        return Forecast::OverBudget;
+    }
+    let elapsed = match time.duration_since(first.0) {
+        Ok(elapsed) if elapsed > Duration::ZERO => elapsed.as_secs_f64(),
+        _ => return Forecast::Unknown,
+    };
+    let rate = (retained as f64 - first.1 as f64) / elapsed;
+    if rate <= 0.0 {
+        return Forecast::Stable;
+    }
+    let remaining = (limit - retained) as f64 / rate;
+    Forecast::Exceeds(time + Duration::from_secs_f64(remaining.min(1e12)))
+}
+
+/// The latest retained size (of a single directory, oldest first), and how fast it grows
+/// in bytes per second
+fn retained_growth(records: &[&Record]) -> Option<(u64, f64)> {
+    let measured: Vec<(SystemTime, u64)> = records
+        .iter()
+        .filter_map(|r| Some((r.time, r.retained?)))
+        .collect();
+    let (first, last) = (measured.first()?, measured.last()?);
+    let elapsed = last.0.duration_since(first.0).ok()?.as_secs_f64();
+    match elapsed > 0.0 {
+        true => Some((last.1, (last.1 as f64 - first.1 as f64) / elapsed)),
+        false => None,
+    }
+}
+
+/// How long files that rotation may delete stay, oldest first out
+pub enum Retention {
+    /// Too few runs with the sizes measured
+    Unknown,
+    /// No limit ever gets to them
+    Indefinite,
+    /// The retained files alone are over the limit, so each run deletes all it may
+    NextRun,
+    /// For about this long, because of the given limit
+    For(Duration, &'static str),
+}
+
+/// The expected retention time under the limits (or the limit of the latest run), at the
+/// intake of the runs (of a single directory, oldest first). With a size limit, files stay
+/// until the intake of files that rotation may delete has filled what the retained files
+/// leave of the limit.
+pub fn expected_retention(
+    records: &[&Record],
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+) -> Retention {
+    let limit = max_size.or_else(|| records.last()?.limit);
+    let by_size = match (limit, retained_growth(records), intake_rate(records)) {
+        (None, _, _) => None,
+        (Some(_), None, _) | (Some(_), _, None) => match max_age {
+            Some(max_age) => return Retention::For(max_age, "--max-age"),
+            None => return Retention::Unknown,
+        },
+        (Some(limit), Some((retained, _)), _) if retained > limit => return Retention::NextRun,
+        (Some(limit), Some((retained, growth)), Some(intake)) => {
+            let rate = intake - growth;
+            (rate > 0.0)
+                .then(|| Duration::from_secs_f64(((limit - retained) as f64 / rate).min(1e12)))
+        }
+    };
+    match (by_size, max_age) {
+        (Some(by_size), Some(max_age)) if max_age < by_size => Retention::For(max_age, "--max-age"),
+        (Some(by_size), _) => Retention::For(by_size, "--max-size"),
+        (None, Some(max_age)) => Retention::For(max_age, "--max-age"),
+        (None, None) => Retention::Indefinite,
+    }
+}
+
+/// Print the expected retention time for each directory
+pub fn print_simulation(
+    records: &[Record],
+    directory: Option<&Path>,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+) {
+    for dir in directories(records) {
+        if directory.is_some_and(|d| d != dir) {
+            continue;
+        }
+        let runs: Vec<&Record> = records.iter().filter(|r| r.directory == dir).collect();
+        println!("{}", dir.display());
+        let verdict = match expected_retention(&runs, max_size, max_age) {
+            Retention::Unknown => String::from("unknown, not enough runs with a measured size"),
+            Retention::Indefinite => String::from("indefinitely, no limit gets to them"),
+            Retention::NextRun => String::from(
+                "until the next run, the files rotation may not delete alone are over the limit",
+            ),
+            Retention::For(duration, limit) => format!(
+                "about {} (limited by {})",
+                format_duration(duration.as_secs()),
+                limit
+            ),
+        };
+        println!("  Files rotation may delete are kept: {}", verdict);
+        println!("  Files rotation may not delete are kept: for as long as they are protected");
+        if let Some(rate) = intake_rate(&runs) {
+            println!("  Intake: {}", format_bytes_per_day(rate));
+        }
+    }
+}
+
+/// Print the forecast for each directory
+pub fn print_forecast(records: &[Record], directory: Option<&Path>) {
+    for dir in directories(records) {
+        if directory.is_some_and(|d| d != dir) {
+            continue;
+        }
+        let runs: Vec<&Record> = records.iter().filter(|r| r.directory == dir).collect();
+        let verdict = match forecast(&runs) {
+            Forecast::Unknown => String::from("not enough runs with a measured size and limit"),
+            Forecast::Stable => String::from("the files rotation may not delete are not growing"),
+            Forecast::OverBudget => {
+                String::from("already over the limit with only the files rotation may not delete")
+            }
+            Forecast::Exceeds(time) => format!(
+                "over the limit around {}, when the files rotation may not delete outgrow it",
+                humantime::format_rfc3339_seconds(time)
+            ),
+        };
+        println!("{}: {}", dir.display(), verdict);
+    }
+}
+
+fn directories(records: &[Record]) -> Vec<&Path> {
+    let mut directories: Vec<&Path> = Vec::new();
+    for record in records {
+        if !directories.contains(&record.directory.as_path()) {
+            directories.push(&record.directory);
+        }
+    }
+    directories
+}
+
+fn format_bytes_per_day(rate: f64) -> String {
+    format!("{:.0} bytes/day", rate * 86400.0)
+}
+
+/// Print the last runs of each directory, and how fast it grows
+pub fn print_history(records: &[Record], directory: Option<&Path>, last: usize) {
+    for dir in directories(records) {
+        if directory.is_some_and(|d| d != dir) {
+            continue;
+        }
+        let runs: Vec<&Record> = records.iter().filter(|r| r.directory == dir).collect();
+        println!("{}", dir.display());
+        println!(
+            "  time                  before      after       deleted  freed       failed  duration"
+        );
+        for record in runs.iter().skip(runs.len().saturating_sub(last)) {
+            println!(
+                "  {}  {:<10}  {:<10}  {:<7}  {:<10}  {:<6}  {:.1}s",
+                humantime::format_rfc3339_seconds(record.time),
+                format_optional(record.size_before),
+                format_optional(record.size_after),
+                record.deleted,
+                record.freed,
+                record.failed,
+                record.duration.as_secs_f64()
+            );
+        }
+        match intake_rate(&runs) {
+            Some(rate) => println!("  Intake: {}", format_bytes_per_day(rate)),
+            None => println!("  Intake: not enough runs with a measured size"),
+        }
+    }
+}
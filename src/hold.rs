@@ -0,0 +1,86 @@
+//! Holds on deletion: a .dirrotate-hold marker in a directory keeps everything below it,
+//! e.g. while an investigation needs the data. Placed and lifted by dirrotate hold add/remove.
+use crate::clock;
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const MARKER: &str = ".dirrotate-hold";
+
+/// Which directories below the rotated one are on hold, looked up as files are scanned
+pub struct Holds {
+    base_dir: PathBuf,
+    /// Whether the directory itself has a marker
+    marked: HashMap<PathBuf, bool>,
+}
+
+impl Holds {
+    pub fn new(base_dir: &Path) -> Holds {
+        Holds {
+            base_dir: base_dir.to_path_buf(),
+            marked: HashMap::new(),
+        }
+    }
+
+    /// Whether the file is below a marker, up to and including the rotated directory
+    pub fn holds(&mut self, path: &Path) -> bool {
+        for dir in path.ancestors().skip(1) {
+            let marked = match self.marked.get(dir) {
+                Some(&marked) => marked,
+                None => {
+                    let marker = dir.join(MARKER);
+                    let marked = marker.exists();
+                    if marked {
+                        let reason = fs::read_to_string(&marker).unwrap_or_default();
+                        warn!(
+                            "Deletions are on hold below {}: {}",
+                            dir.display(),
+                            reason.lines().collect::<Vec<_>>().join(" ")
+                        );
+                    }
+                    self.marked.insert(dir.to_path_buf(), marked);
+                    marked
+                }
+            };
+            if marked {
+                return true;
+            }
+            if dir == self.base_dir {
+                break;
+            }
+        }
+        false
+    }
+}
+
+/// Put the directory on hold, noting when and why
+pub fn add(dir: &Path, reason: Option<&str>) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+    let mut content = format!(
+        "Held since {}\n",
+        humantime::format_rfc3339_seconds(clock::now())
+    );
+    if let Some(reason) = reason {
+        content.push_str(reason);
+        content.push('\n');
+    }
+    fs::write(dir.join(MARKER), content)
+}
+
+/// Lift the hold on the directory
+pub fn remove(dir: &Path) -> io::Result<()> {
+    fs::remove_file(dir.join(MARKER)).map_err(|why| match why.kind() {
+        io::ErrorKind::NotFound => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not on hold", dir.display()),
+        ),
+        _ => why,
+    })
+}
@@ -0,0 +1,103 @@
+//! A self-contained HTML report of a run (--report-html), e.g. for attaching to email
+use crate::executor::Outcome;
+use std::path::Path;
+use std::time::SystemTime;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse}\
+td,th{padding:2px 8px;text-align:left;border-bottom:1px solid #ddd}\
+.bar{display:flex;width:400px;height:14px;background:#eee}\
+.kept{background:#4a7ebb}.deleted{background:#c0504d}";
+
+/// Text as HTML, with the special characters escaped
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 * 100.0 / whole as f64,
+    }
+}
+
+/// The report: the settings of the run as (name, value), the usage of each subdirectory as
+/// (name, bytes kept, bytes deleted) and a table of what happened to each file
+pub fn render(
+    directory: &Path,
+    time: SystemTime,
+    settings: &[(&str, String)],
+    usage: &[(String, u64, u64)],
+    outcome: Option<&Outcome>,
+) -> String {
+    let directory = escape(&directory.to_string_lossy());
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <title>dirrotate: {}</title><style>{}</style></head><body>\n\
+         <h1>{}</h1>\n<p>Run at {}</p>\n",
+        directory,
+        STYLE,
+        directory,
+        humantime::format_rfc3339_seconds(time)
+    );
+
+    html.push_str("<h2>Settings</h2>\n<table>\n");
+    for (name, value) in settings {
+        html.push_str(&format!(
+            "<tr><th>{}</th><td>{}</td></tr>\n",
+            escape(name),
+            escape(value)
+        ));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<h2>Usage per subdirectory</h2>\n<table>\n\
+         <tr><th>subdirectory</th><th>kept</th><th>deleted</th><th></th></tr>\n",
+    );
+    let largest = usage.iter().map(|(_, k, d)| k + d).max().unwrap_or(0);
+    for (name, kept, deleted) in usage {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td><div class=\"bar\">\
+             <div class=\"kept\" style=\"width:{:.1}%\"></div>\
+             <div class=\"deleted\" style=\"width:{:.1}%\"></div></div></td></tr>\n",
+            escape(name),
+            kept,
+            deleted,
+            percent(*kept, largest),
+            percent(*deleted, largest)
+        ));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<h2>Deletions</h2>\n<table>\n\
+         <tr><th>path</th><th>size</th><th>modified</th><th>result</th></tr>\n",
+    );
+    for (operation, result, error) in outcome.map(Outcome::results).unwrap_or_default() {
+        let result = match error {
+            Some(why) => format!("{}: {}", result, why),
+            None => result.to_string(),
+        };
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape(&operation.path.to_string_lossy()),
+            operation.size,
+            humantime::format_rfc3339_seconds(operation.modified),
+            escape(&result)
+        ));
+    }
+    html.push_str("</table>\n</body></html>\n");
+    html
+}
@@ -0,0 +1,147 @@
+//! A write-ahead journal of the operations of a run. Each operation is recorded before it is
+//! carried out and marked once it is done, so that a run that crashed can be told apart from
+//! one that finished. The next run reports what the crashed one left unfinished; its scan
+//! plans the files that are still there again.
+use crate::clock;
+use crate::executor::Operation;
+use crate::state::{format_time, parse_time};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "dirrotate-journal 1";
+
+/// The journal of the run, hidden so that rotation leaves it alone
+pub fn journal_path(base_dir: &Path) -> PathBuf {
+    base_dir.join(".dirrotate-journal")
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Start a journal, after reporting on the one that an interrupted run left behind
+    pub fn create(path: &Path) -> io::Result<Journal> {
+        if path.exists() {
+            report_interrupted(path);
+        }
+        let mut file = File::create(path)?;
+        writeln!(file, "{}\n{}", HEADER, format_time(clock::now()))?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Record operations before they are carried out, by their index. They are on disk
+    /// once this returns.
+    pub fn planned(&mut self, operations: &[(usize, Operation)]) {
+        let mut lines = String::new();
+        for (index, operation) in operations {
+            let path = operation.path.to_string_lossy();
+            lines.push_str(&format!(
+                "planned\t{}\t{}\t{}\n",
+                index, operation.size, path
+            ));
+        }
+        // One sync for all of them, as on network filesystems each takes a round trip
+        let result = (self.file.write_all(lines.as_bytes())).and_then(|_| self.file.sync_data());
+        if let Err(why) = result {
+            warn!(
+                "Could not write to the journal {}: {}",
+                self.path.display(),
+                why
+            );
+        }
+    }
+
+    /// Mark an operation as done, with what came of it
+    pub fn done(&mut self, index: usize, result: &str) {
+        if let Err(why) = writeln!(self.file, "done\t{}\t{}", index, result) {
+            warn!(
+                "Could not write to the journal {}: {}",
+                self.path.display(),
+                why
+            );
+        }
+    }
+
+    /// The run finished, so the journal is no longer needed
+    pub fn finish(self) {
+        if let Err(why) = fs::remove_file(&self.path) {
+            warn!(
+                "Could not remove the journal {}: {}",
+                self.path.display(),
+                why
+            );
+        }
+    }
+}
+
+/// Log what the run that left the journal behind carried out, and what it didn't get to
+fn report_interrupted(path: &Path) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(why) => {
+            warn!("Could not read the journal {}: {}", path.display(), why);
+            return;
+        }
+    };
+    let mut lines = content.lines();
+    if lines.next() != Some(HEADER) {
+        warn!("Ignoring {}, it is not a journal", path.display());
+        return;
+    }
+    let started = lines.next().and_then(parse_time);
+    let mut planned = Vec::new();
+    let mut results: HashMap<usize, &str> = HashMap::new();
+    // A line cut short by the crash is skipped
+    for line in lines {
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        match fields[..] {
+            ["planned", index, size, path] => {
+                if let (Ok(index), Ok(size)) = (index.parse::<usize>(), size.parse::<u64>()) {
+                    planned.push((index, size, path));
+                }
+            }
+            ["done", index, result] => {
+                if let Ok(index) = index.parse() {
+                    results.insert(index, result);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for result in results.values() {
+        *counts.entry(result).or_default() += 1;
+    }
+    let mut counts: Vec<String> = (counts.into_iter())
+        .map(|(result, count)| format!("{} {}", count, result))
+        .collect();
+    counts.sort();
+    warn!(
+        "The previous run{} was interrupted: {} of {} operations finished ({})",
+        started.map_or_else(String::new, |started| format!(
+            " (started {})",
+            humantime::format_rfc3339_seconds(started)
+        )),
+        results.len(),
+        planned.len(),
+        counts.join(", ")
+    );
+    for (index, size, path) in planned {
+        if results.contains_key(&index) {
+            continue;
+        }
+        // It may have been deleted just before the crash
+        match Path::new(path).exists() {
+            true => info!("Unfinished, still there: {} ({} bytes)", path, size),
+            false => info!("Unfinished, but gone: {} ({} bytes)", path, size),
+        }
+    }
+}
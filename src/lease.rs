@@ -0,0 +1,306 @@
+//! A lease that lets one host at a time delete in a directory that several hosts rotate,
+//! e.g. on a network filesystem (--lease-ttl). The lease is a directory in the target with
+//! an owner file that names its holder and when it expires. It is renewed while the run
+//! goes on, so that a crashed host holds it up for one TTL at most.
+//!
+//! Renaming is atomic even over NFS, so the lease is changed by renames only: it is put in
+//! place whole, and renewing or breaking it first renames the owner file aside, which only
+//! one host can do. Whoever finds another owner aside than they expected puts it back.
+use crate::state::{format_time, parse_time};
+use log::{debug, info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+const HEADER: &str = "dirrotate-lease 1";
+/// How often to look again while waiting for the lease
+const POLL: Duration = Duration::from_secs(5);
+
+/// Whether a lease of this run was taken over by another host
+static LOST: AtomicBool = AtomicBool::new(false);
+
+/// Who holds a lease and until when
+type Owner = (String, SystemTime);
+
+/// The lease of the directory, hidden so that rotation leaves it alone
+pub fn lease_path(base_dir: &Path) -> PathBuf {
+    base_dir.join(".dirrotate-lease")
+}
+
+/// Whether another host took over a lease of this run. Nothing more may be deleted then.
+pub fn lost() -> bool {
+    LOST.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Who holds a lease, as "HOST PID"
+fn holder() -> String {
+    format!("{} {}", hostname(), process::id())
+}
+
+/// A name of this process's own for something put next to the lease or in it
+fn own_name(prefix: &str) -> String {
+    format!("{}.{}", prefix, holder().replace(' ', "."))
+}
+
+fn write_owner(dir: &Path, holder: &str, ttl: Duration) -> io::Result<Owner> {
+    // Renamed into place, so that others never read half of it
+    let expires = SystemTime::now() + ttl;
+    let content = format!("{}\n{}\n{}\n", HEADER, holder, format_time(expires));
+    let tmp_path = dir.join(format!("{}.tmp", own_name("owner")));
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, dir.join("owner"))?;
+    Ok((holder.to_string(), expires))
+}
+
+fn read_owner_file(path: &Path) -> Option<Owner> {
+    let content = fs::read_to_string(path).ok()?;
+    let mut lines = content.lines();
+    if lines.next() != Some(HEADER) {
+        return None;
+    }
+    let holder = lines.next()?.to_string();
+    let expires = parse_time(lines.next()?)?;
+    Some((holder, expires))
+}
+
+fn read_owner(lease: &Path) -> Option<Owner> {
+    read_owner_file(&lease.join("owner"))
+}
+
+fn is_expired(lease: &Path, ttl: Duration) -> bool {
+    // Without an owner, the lease is being renewed or broken right now, unless that was
+    // long ago. Renaming the owner aside touched the directory.
+    let expires = match read_owner(lease) {
+        Some((_, expires)) => Some(expires),
+        None => fs::metadata(lease)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(|modified| modified + ttl),
+    };
+    expires.is_some_and(|expires| expires < SystemTime::now())
+}
+
+/// Rename the owner of the lease aside, if it's still `expected`. Returns where it went.
+fn set_aside(lease: &Path, expected: &Owner) -> io::Result<Option<PathBuf>> {
+    let aside = lease.join(own_name("owner.aside"));
+    match fs::rename(lease.join("owner"), &aside) {
+        Ok(()) => {}
+        // Another host got to it first
+        Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(why) => return Err(why),
+    }
+    if read_owner_file(&aside).as_ref() != Some(expected) {
+        // Someone else's owner, renewed or new since it was read. It goes back, unless
+        // that someone wrote another meanwhile.
+        let _ = fs::hard_link(&aside, lease.join("owner"));
+        let _ = fs::remove_file(&aside);
+        return Ok(None);
+    }
+    Ok(Some(aside))
+}
+
+/// Replace the owner of the lease with this run, if it's still `expected`
+fn seize(lease: &Path, expected: &Owner, holder: &str, ttl: Duration) -> io::Result<Option<Owner>> {
+    let aside = match set_aside(lease, expected)? {
+        Some(aside) => aside,
+        None => return Ok(None),
+    };
+    let owner = write_owner(lease, holder, ttl)?;
+    let _ = fs::remove_file(&aside);
+    Ok(Some(owner))
+}
+
+fn create(lease: &Path, holder: &str, ttl: Duration) -> io::Result<Option<Owner>> {
+    // Put in place with its owner, as renaming onto a lease that has one fails
+    let tmp_path = lease.with_file_name(own_name(".dirrotate-lease.new"));
+    fs::create_dir(&tmp_path)?;
+    let owner = write_owner(&tmp_path, holder, ttl).and_then(|owner| {
+        fs::rename(&tmp_path, lease)?;
+        Ok(owner)
+    });
+    match owner {
+        Ok(owner) => Ok(Some(owner)),
+        Err(why) => {
+            let _ = fs::remove_dir_all(&tmp_path);
+            match lease.exists() {
+                true => Ok(None),
+                false => Err(why),
+            }
+        }
+    }
+}
+
+fn break_ownerless(lease: &Path) -> io::Result<()> {
+    // Only one host gets to rename it away, so only one breaks it
+    let broken = lease.with_file_name(own_name(".dirrotate-lease.broken"));
+    fs::rename(lease, &broken)?;
+    if read_owner(&broken).is_some() {
+        // Another host broke it first and put a lease of its own in place
+        return fs::rename(&broken, lease);
+    }
+    fs::remove_dir_all(&broken)
+}
+
+/// A lease held by this run
+pub struct Lease {
+    path: PathBuf,
+    holder: String,
+    stop: Sender<()>,
+    renewer: JoinHandle<()>,
+}
+
+impl Lease {
+    /// Take the lease of the directory, waiting up to `wait` for another host to give it
+    /// up. Returns nothing if it's still held by then.
+    pub fn acquire(base_dir: &Path, ttl: Duration, wait: Duration) -> io::Result<Option<Lease>> {
+        let path = lease_path(base_dir);
+        let holder = holder();
+        let started = Instant::now();
+        let owner = loop {
+            if let Some(owner) = create(&path, &holder, ttl)? {
+                break owner;
+            }
+            if is_expired(&path, ttl) {
+                match read_owner(&path) {
+                    Some(previous) => {
+                        warn!("Breaking the expired lease of {}", previous.0);
+                        if let Some(owner) = seize(&path, &previous, &holder, ttl)? {
+                            break owner;
+                        }
+                    }
+                    None => match break_ownerless(&path) {
+                        Ok(()) => continue,
+                        // Another host broke it first
+                        Err(why) if why.kind() == io::ErrorKind::NotFound => continue,
+                        Err(why) => return Err(why),
+                    },
+                }
+            }
+            if started.elapsed() >= wait {
+                if let Some((other, expires)) = read_owner(&path) {
+                    info!(
+                        "The lease of {} is held by {} until {}",
+                        base_dir.display(),
+                        other,
+                        humantime::format_rfc3339_seconds(expires)
+                    );
+                }
+                return Ok(None);
+            }
+            thread::sleep(POLL.min(wait.saturating_sub(started.elapsed())));
+        };
+        debug!("Took the lease {}", path.display());
+
+        let (stop, stopped) = channel();
+        let renewer = {
+            let (path, holder) = (path.clone(), holder.clone());
+            thread::spawn(move || {
+                let every = (ttl / 3).max(Duration::from_secs(1));
+                let mut owner = owner;
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                    match seize(&path, &owner, &holder, ttl) {
+                        Ok(Some(renewed)) => owner = renewed,
+                        Ok(None) => {
+                            warn!(
+                                "Lost the lease {} to another host, stopping the deletions",
+                                path.display()
+                            );
+                            LOST.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                        Err(why) => {
+                            warn!("Could not renew the lease {}: {}", path.display(), why)
+                        }
+                    }
+                }
+            })
+        };
+        Ok(Some(Lease {
+            path,
+            holder,
+            stop,
+            renewer,
+        }))
+    }
+
+    /// Give the lease up, unless another host broke it meanwhile
+    pub fn release(self) {
+        let Lease {
+            path,
+            holder,
+            stop,
+            renewer,
+        } = self;
+        drop(stop);
+        let _ = renewer.join();
+        let owner = match read_owner(&path) {
+            Some(owner) if owner.0 == holder => owner,
+            _ => return,
+        };
+        match set_aside(&path, &owner) {
+            Ok(Some(aside)) => {
+                let _ = fs::remove_file(&aside);
+                // Fails if another host already put a lease of its own in place
+                if let Err(why) = fs::remove_dir(&path) {
+                    debug!("Left the lease directory {}: {}", path.display(), why);
+                }
+            }
+            Ok(None) => {}
+            Err(why) => warn!("Could not release the lease {}: {}", path.display(), why),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn a_held_lease_keeps_another_run_out() {
+        let dir = TestDir::new("lease");
+        let lease = Lease::acquire(&dir, HOUR, Duration::ZERO).unwrap().unwrap();
+        assert!(Lease::acquire(&dir, HOUR, Duration::ZERO)
+            .unwrap()
+            .is_none());
+        lease.release();
+        // Free again once released
+        let lease = Lease::acquire(&dir, HOUR, Duration::ZERO).unwrap().unwrap();
+        lease.release();
+        assert!(!lease_path(&dir).exists());
+    }
+
+    #[test]
+    fn breaks_an_expired_lease() {
+        let dir = TestDir::new("lease-expired");
+        let path = lease_path(&dir);
+        create(&path, "crashed 1", Duration::ZERO).unwrap().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let lease = Lease::acquire(&dir, HOUR, Duration::ZERO).unwrap().unwrap();
+        assert_eq!(read_owner(&path).map(|owner| owner.0), Some(holder()));
+        lease.release();
+    }
+}
@@ -0,0 +1,165 @@
+use crate::verbatim::for_programs;
+use log::{info, warn};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Extensions of files that gzip would hardly make smaller
+const COMPRESSED: &[&str] = &[
+    "gz", "bz2", "xz", "zst", "lz4", "zip", "7z", "jpg", "jpeg", "png", "mp4", "mkv", "webm",
+];
+
+fn already_compressed(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    COMPRESSED.contains(&extension.to_ascii_lowercase().as_str())
+}
+
+fn generation(path: &Path, n: usize, compressed: bool) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    if compressed {
+        name.push(".gz");
+    }
+    PathBuf::from(name)
+}
+
+/// Whether the file is itself a rotated generation, e.g. `app.log.3` or `app.log.3.gz`
+pub fn is_generation(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    match name.rsplit_once('.') {
+        Some((_, n)) => !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    }
+}
+
+fn rename(from: &Path, to: &Path, dryrun: bool) {
+    if dryrun {
+        info!("Rename file: {} -> {}", from.display(), to.display());
+    } else if let Err(why) = fs::rename(from, to) {
+        warn!(
+            "Could not rename file: {} -> {} ({})",
+            from.display(),
+            to.display(),
+            why
+        );
+    } else {
+        info!("Renamed file: {} -> {}", from.display(), to.display());
+    }
+}
+
+fn compress(path: &Path, dryrun: bool) {
+    // Same as logrotate's default compresscmd
+    if dryrun {
+        info!("Compress file: {}", path.display());
+        return;
+    }
+    match Command::new("gzip")
+        .arg("-f")
+        .arg(for_programs(path))
+        .status()
+    {
+        Ok(status) if status.success() => info!("Compressed file: {}", path.display()),
+        Ok(status) => warn!("Could not compress file: {} ({})", path.display(), status),
+        Err(why) => warn!("Could not compress file: {} ({})", path.display(), why),
+    }
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2` and so on, dropping the generation
+/// past the last. With compression, generations from 2 and up are gzipped, while
+/// `path.1` stays as is, since the writer may still have it open. Files that are already
+/// compressed (by their extension) are not gzipped again.
+pub fn rotate_file(path: &Path, generations: usize, compressed: bool, dryrun: bool) {
+    if generations == 0 {
+        return;
+    }
+    let compressed = compressed && !already_compressed(path);
+    for old in [
+        generation(path, generations, false),
+        generation(path, generations, true),
+    ] {
+        if old.exists() {
+            if dryrun {
+                info!("Delete file: {}", old.display());
+            } else if let Err(why) = fs::remove_file(&old) {
+                warn!("Could not delete file: {} ({})", old.display(), why);
+            } else {
+                info!("Deleted file: {}", old.display());
+            }
+        }
+    }
+    for n in (1..generations).rev() {
+        let gz = generation(path, n, true);
+        if gz.exists() {
+            rename(&gz, &generation(path, n + 1, true), dryrun);
+        }
+        let plain = generation(path, n, false);
+        if plain.exists() {
+            let next = generation(path, n + 1, false);
+            rename(&plain, &next, dryrun);
+            if compressed {
+                compress(&next, dryrun);
+            }
+        }
+    }
+    rename(path, &generation(path, 1, false), dryrun);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn recognizes_generations() {
+        assert!(is_generation(Path::new("/var/log/app.log.1")));
+        assert!(is_generation(Path::new("/var/log/app.log.12.gz")));
+        assert!(!is_generation(Path::new("/var/log/app.log")));
+        assert!(!is_generation(Path::new("/var/log/app.log.gz")));
+        assert!(!is_generation(Path::new("/var/log/app.")));
+    }
+
+    #[test]
+    fn names_generations() {
+        let path = Path::new("/var/log/app.log");
+        assert_eq!(generation(path, 3, false), Path::new("/var/log/app.log.3"));
+        assert_eq!(
+            generation(path, 3, true),
+            Path::new("/var/log/app.log.3.gz")
+        );
+        assert!(already_compressed(Path::new("capture.MP4")));
+        assert!(!already_compressed(path));
+    }
+
+    #[test]
+    fn shifts_generations_and_drops_the_last() {
+        let dir = TestDir::new("logrotate");
+        let log = dir.join("app.log");
+        for (name, content) in [("app.log", "0"), ("app.log.1", "1"), ("app.log.2", "2")] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        rotate_file(&log, 2, false, false);
+        let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+        assert_eq!(read("app.log"), None);
+        assert_eq!(read("app.log.1").as_deref(), Some("0"));
+        assert_eq!(read("app.log.2").as_deref(), Some("1"));
+        assert_eq!(read("app.log.3"), None);
+    }
+
+    #[test]
+    fn changes_nothing_in_a_dryrun() {
+        let dir = TestDir::new("logrotate-dry");
+        let log = dir.join("app.log");
+        fs::write(&log, "0").unwrap();
+        rotate_file(&log, 2, true, true);
+        assert!(log.exists());
+        assert!(!generation(&log, 1, false).exists());
+    }
+}
@@ -1,199 +1,3867 @@
+mod activation;
+mod argfile;
+mod audit;
+mod budget;
+mod cgroup;
+mod clock;
+mod csv;
+mod datelayout;
+mod deadline;
+mod dirfd;
+mod executor;
+mod explain;
+mod extents;
+mod getdents;
+mod grace;
+mod groups;
+mod growth;
+mod history;
+mod hold;
+mod html;
+mod journal;
+mod json;
+mod lease;
+mod logrotate;
 mod matching;
-use clap::Parser;
+mod matchtest;
+mod messages;
+mod notify;
+mod plan;
+mod preflight;
+mod privileges;
+mod prompt;
+mod protect;
+mod quota;
+mod record;
+mod retention;
+mod root;
+mod safety;
+mod sandbox;
+mod scanerrors;
+mod scanfile;
+mod sha256;
+mod snapshots;
+mod sniff;
+mod space;
+mod spill;
+mod state;
+#[cfg(test)]
+mod testdir;
+mod timemachine;
+mod top;
+mod trash;
+mod undo;
+mod upload;
+mod uring;
+mod verbatim;
+mod whatif;
+use clap::{ArgEnum, Parser, Subcommand};
 use clap_verbosity_flag::Verbosity;
-use parse_size::{parse_size, Error};
+use parse_size::parse_size;
 use path_matchers::PathMatcher;
-use std::fs::{self, *};
+use std::borrow::{Borrow, Cow};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead};
+use std::iter;
 use std::path::{Path, PathBuf};
-use walkdir::{DirEntry, WalkDir};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::WalkDir;
 
-use log::{info, warn};
+use log::{debug, error, info, warn};
 
-use matching::get_path_matcher;
+use budget::{reservation_parser, weight_parser, Reservation, Reservations, Weight, Weights};
+use cgroup::{cgroup_limit_parser, CgroupLimit};
+use executor::{settle_groups, Executor, Operation, Options, Outcome};
+use explain::{print_explanation, Plan};
+use getdents::Kind;
+use grace::GraceMarks;
+use groups::{group_by_stem, missing_members, print_group_report, split_groups, stem_group};
+use growth::growth_since_scan;
+use history::{forecast, print_forecast, print_history, print_simulation, Forecast};
+use hold::Holds;
+use lease::Lease;
+use logrotate::{is_generation, rotate_file};
+use matching::{canonical_path, get_path_matcher, try_glob_matcher, MatchMode, UnmatchedPatterns};
+use matchtest::{print_matches, Patterns};
+use messages::text;
+use notify::{
+    in_desktop_session, publish_mqtt, push_metrics, send_desktop, send_email, send_webhook,
+    EmailSettings, Metric, Summary, WebhookFormat,
+};
+use plan::PlannedFile;
+use preflight::preflight;
+use prompt::{confirm_plan, review_plan};
+use protect::{get_newest_per_pattern, get_protected_paths};
+use quota::project_quota;
+use record::FileRecord;
+use retention::{format_age, retention, widen, Retention};
+use safety::{check_base_dir, check_plan_fraction, Deletable, SOMEONE_ELSES};
+use scanerrors::OnScanError;
+use scanfile::SavedScan;
+use snapshots::{has_snapshots, snapshot_fs};
+use sniff::{mime_type_matches, sniff_mime_type};
+use space::{capacity, free_space, used_space};
+use spill::{Spill, SpilledFile};
+use state::SizeState;
+use top::print_top;
+use trash::Trash;
+use upload::{get_backend, get_encrypted_backend, get_ssh_backend, parse_encryption, Backend};
+use whatif::{print_whatif, Policy};
 
 /// Command-line arguments
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Directory to rotate
-    #[clap()]
-    directory: PathBuf,
+    #[clap(required = true)]
+    directory: Option<PathBuf>,
+
+    /// Maximum filesize of the directory. Supply a number in bytes or with a suffix, e.g. 3K,
+    /// 1.5GiB, etc., or a percentage of the capacity of the filesystem, e.g. 80%.
+    #[clap(
+        name = "max-size",
+        value_name = "MAX_SIZE",
+        parse(try_from_str = size_limit_parser),
+        required_unless_present_any = &["max-age", "rename-rotate", "from-quota", "free-at-least"]
+    )]
+    size_limit: Option<SizeLimit>,
+
+    /// The size limit in bytes, as given or from the capacity of the filesystem or the quota
+    #[clap(skip)]
+    max_size: Option<u64>,
+
+    /// Where the journal goes: the directory, not a parent that --also directories share
+    #[clap(skip)]
+    journal_dir: Option<PathBuf>,
 
-    /// Maximum filesize of the directory. Supply a number in bytes or with a suffix, e.g. 3K, 5MiB, etc.
-    #[clap(parse(try_from_str = size_parser))]
-    max_size: u64,
+    /// Another directory to rotate under the same limit. The files of all directories count
+    /// toward it, and the oldest candidates go first, whichever directory they are in.
+    /// Can be given multiple times.
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "state-file", "rename-rotate", "group", "report-groups", "reserve", "weight",
+            "keep-newest-per-pattern", "quick-check", "from-quota",
+        ]
+    )]
+    also: Vec<PathBuf>,
+
+    /// Delete files older than this regardless of size, e.g. 30d, 12h. Without a max size, only
+    /// this applies and the directory size is never computed.
+    #[clap(long, parse(try_from_str = duration_parser))]
+    max_age: Option<Duration>,
+
+    /// Instead of a max size, delete the oldest files until at least this much is freed, e.g.
+    /// 5G, for when the disk is full right now.
+    #[clap(
+        long,
+        parse(try_from_str = size_parser),
+        conflicts_with_all = &[
+            "max-size", "from-quota", "also", "max-memory", "state-file", "date-layout",
+        ]
+    )]
+    free_at_least: Option<u64>,
 
     /// Dry-run (only print operations)
     #[clap(short, long)]
     dryrun: bool,
 
-    /// Consider files with the same stem as a group and only delete whole groups.
+    /// How to print the plan of a dry-run. The JSON plan goes to stdout and can be carried
+    /// out later with `dirrotate apply`. CSV lists each file and what came of it on stdout
+    /// after the run, dry or not.
+    #[clap(long, arg_enum, default_value = "text")]
+    output: OutputFormat,
+
+    /// Tell for each file of the dry-run plan why it goes (its age or rank, its group, the
+    /// limit it goes for), and for each file that counts toward the size but stays, why.
+    /// Not with --max-memory or --also.
+    #[clap(
+        long,
+        requires = "dryrun",
+        conflicts_with_all = &["output", "max-memory", "also"]
+    )]
+    explain: bool,
+
+    /// Show the summary, confirmation and review in the language of this message catalog.
+    /// See the messages subcommand.
+    #[clap(long)]
+    messages: Option<PathBuf>,
+
+    /// Write a self-contained HTML report of the run to this file: the settings, the usage of
+    /// each subdirectory and what happened to each file
+    #[clap(long)]
+    report_html: Option<PathBuf>,
+
+    /// Consider files with the same stem (the name up to the first dot) as a group and only
+    /// delete whole groups. A group is as old as its newest file.
     #[clap(short, long)]
     group: bool,
 
+    /// List the groups of files with the same stem, with their sizes and ages, before
+    /// planning. Combine with --dryrun to check what --group would do.
+    #[clap(long)]
+    report_groups: bool,
+
+    /// A suffix that a complete group has a member with, e.g. .json. Can be given multiple
+    /// times. What happens to incomplete groups is up to --incomplete-groups.
+    #[clap(long)]
+    require_member: Vec<String>,
+
+    /// What to do with groups that lack a --require-member
+    #[clap(long, arg_enum, default_value = "ignore")]
+    incomplete_groups: IncompleteGroups,
+
+    /// When a member of a group can't be deleted, move the members that already went to
+    /// the trash back, so that no group is left half deleted
+    #[clap(long, requires_all = &["group", "trash"])]
+    rollback_groups: bool,
+
     /// A glob pattern to only consider a subset of files, both in the size estimation and deletion.
+    /// Can be given multiple times to consider files matching any of them.
     #[clap(short, long)]
-    include_only: Option<String>,
+    include_only: Vec<String>,
+
+    /// Match patterns against paths relative to the rotated directory, like .gitignore: A *
+    /// stays within a directory, ** spans directories, and a pattern without a slash
+    /// matches at any depth
+    #[clap(long)]
+    match_relative: bool,
+
+    /// Match patterns against the full canonicalized paths, with the rotated directory
+    /// put in front of them. This is the default.
+    #[clap(long, conflicts_with = "match-relative")]
+    match_absolute: bool,
 
     /// A glob pattern to exclude a subset of files, both in the size estimation and deletion.
     #[clap(short, long, conflicts_with = "include-only")]
     exclude: Option<String>,
 
-    /// A glob pattern to protect a subset of files from deletion
+    /// A glob pattern to select the files that may be deleted.
+    /// Can be given multiple times to select files matching any of them.
     #[clap(short, long)]
-    select_for_op: Option<String>,
+    select_for_op: Vec<String>,
 
     /// A glob pattern to protect a subset of files from deletion
     #[clap(short, long, conflicts_with = "select-for-op")]
     protect_from_op: Option<String>,
 
+    /// Protect files smaller than this size from deletion, e.g. 4K. They still count toward the size.
+    #[clap(long, parse(try_from_str = size_parser))]
+    skip_smaller_than: Option<u64>,
+
+    /// Protect files larger than this size from deletion, e.g. 10G. They still count toward the size.
+    #[clap(long, parse(try_from_str = size_parser))]
+    skip_larger_than: Option<u64>,
+
+    /// Only delete files whose content is detected as this MIME type, e.g. video/*. Can be repeated.
+    #[clap(long)]
+    only_type: Vec<String>,
+
+    /// A file listing paths (one per line) to protect from deletion, e.g. files still referenced.
+    /// A directory protects everything below it.
+    #[clap(long)]
+    protect_from_list: Option<PathBuf>,
+
+    /// A shell command whose output lists paths (one per line) to protect from deletion, like
+    /// --protect-from-list
+    #[clap(long)]
+    protect_from_command: Option<String>,
+
+    /// A file to save directory sizes in between runs. Only directories modified since the last
+    /// run are rescanned when checking the size. Files growing in place are not noticed.
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+
+    /// Take the directories to rotate, and the paths in patterns and lists, as inside this
+    /// directory, e.g. a mounted image: absolute paths and symlinks are resolved from it.
+    /// dirrotate's own files (state, logs, reports) stay outside.
+    #[clap(long)]
+    root: Option<PathBuf>,
+
+    /// chroot into --root before anything else, so that all paths are inside it, also
+    /// dirrotate's own files and the user of --run-as
+    #[clap(long, requires = "root")]
+    chroot: bool,
+
+    /// Allow deleting files in lost+found directories. Hidden directories, like .git, .Trash-1000
+    /// or .snapshot, are never entered either way.
+    #[clap(long)]
+    no_default_protections: bool,
+
+    /// Run in a cgroup of its own with these limits, e.g. "mem=64M,io=10M/s": memory, and
+    /// reads and writes per second each on the disk of the directory. Needs cgroup v2 and
+    /// root, or a delegated cgroup. Linux only.
+    #[clap(long, parse(try_from_str = cgroup_limit_parser))]
+    cgroup_limit: Option<CgroupLimit>,
+
+    /// Switch from root to this user before anything else, with their groups, as a
+    /// service that starts as root
+    #[clap(long)]
+    run_as: Option<String>,
+
+    /// Keep the run from changing files outside the rotated directories, the upload
+    /// directory, the trash and the directories of its own files, and from calls like mount
+    /// (Linux only, with Landlock and seccomp). Commands it runs are held to the same.
+    #[clap(long)]
+    sandbox: bool,
+
+    /// Another place the --sandbox'ed run may change files in, e.g. the state directory of a
+    /// tool run for uploads
+    #[clap(long, requires = "sandbox")]
+    sandbox_allow: Vec<PathBuf>,
+
+    /// Number of files to delete concurrently. Raise this on high-latency network filesystems.
+    #[clap(long, default_value_t = 1)]
+    concurrency: usize,
+
+    /// Only mark files the first time they are selected, and delete them on a later run once
+    /// this grace period is over, e.g. 24h. Files modified in the meantime start over.
+    #[clap(long, parse(try_from_str = duration_parser))]
+    grace: Option<Duration>,
+
+    /// Where to keep track of files in their grace period [default: DIRECTORY/.dirrotate-grace]
+    #[clap(long)]
+    grace_file: Option<PathBuf>,
+
+    /// Keep the plan in this file, and show which files became candidates for deletion and
+    /// which no longer are since the run that kept the previous plan there
+    #[clap(long, conflicts_with = "output")]
+    diff: Option<PathBuf>,
+
+    /// A glob pattern of live files to rotate like logrotate does: app.log is renamed to
+    /// app.log.1, app.log.1 to app.log.2 and so on. Runs before any size or age based deletion.
+    #[clap(long)]
+    rename_rotate: Option<String>,
+
+    /// Number of generations to keep with --rename-rotate
+    #[clap(long, default_value_t = 5)]
+    generations: usize,
+
+    /// Gzip generations from 2 and up with --rename-rotate, unless the file is already
+    /// compressed (e.g. .zst or .jpg)
+    #[clap(long)]
+    compress: bool,
+
+    /// Upload files before deleting them: s3://bucket/prefix, gs://bucket/prefix,
+    /// webdav(s)://host/path, file:///path or zip:///path/archive.zip. Files whose upload fails
+    /// are kept. A file:// directory or zip:// archive inside the rotated directory must be
+    /// hidden (start with a dot). Interrupted copies to file:// and gs:// are resumed on the
+    /// next run.
+    #[clap(long, parse(try_from_str = upload_parser))]
+    upload_to: Option<String>,
+
+    /// Copy files to a remote host over SSH before deleting them, as [user@]host:/path.
+    /// Uses rsync, which resumes interrupted transfers. Files whose copy fails are kept.
+    #[clap(long, conflicts_with = "upload-to", parse(try_from_str = ssh_target_parser))]
+    offload_ssh: Option<String>,
+
+    /// Encrypt files for this recipient before --upload-to or --offload-ssh, as
+    /// age:RECIPIENT or gpg:KEY. The uploads are named like the files plus .age or .gpg.
+    #[clap(long, parse(try_from_str = encryption_parser))]
+    archive_encrypt: Option<String>,
+
+    /// Limit the bandwidth of --upload-to and --offload-ssh, e.g. 10MiB/s.
+    /// The limit is for the whole run and is split between concurrent uploads.
+    #[clap(long, parse(try_from_str = bandwidth_parser))]
+    bandwidth_limit: Option<u64>,
+
+    /// Move files to the trash instead of deleting them: ~/.Trash on macOS, the
+    /// freedesktop.org trash elsewhere. Only frees space once the trash is emptied.
+    #[clap(long)]
+    trash: bool,
+
+    /// Leave files that are excluded from Time Machine backups alone, as if they were
+    /// --exclude'd (macOS only)
+    #[clap(long)]
+    skip_time_machine_excluded: bool,
+
+    /// Append a record of every deletion (and upload) to this file, one JSON object per line
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Before a run, rotate the audit log once it is larger than this, like --rename-rotate:
+    /// to FILE.1, FILE.1 to FILE.2 and so on
+    #[clap(long, requires = "audit-log", parse(try_from_str = size_parser))]
+    audit_log_max_size: Option<u64>,
+
+    /// Number of generations of the audit log to keep with --audit-log-max-size
+    #[clap(long, default_value_t = 5)]
+    audit_log_generations: usize,
+
+    /// Record a checksum of each file's content in the audit log before it is deleted
+    #[clap(long, arg_enum, requires = "audit-log")]
+    checksum: Option<ChecksumAlgorithm>,
+
+    /// Number of files to checksum concurrently with --checksum. Files are read and hashed
+    /// ahead of the deletions, so that both go on at the same time.
+    #[clap(long, default_value_t = 4)]
+    hash_threads: usize,
+
+    /// Email a summary to this address when the run fails, cannot free enough space,
+    /// or frees more than --notify-above. Can be given multiple times.
+    #[clap(long, requires = "smtp-url")]
+    notify_email: Vec<String>,
+
+    /// SMTP server for --notify-email, e.g. smtps://mail.example.com:465
+    #[clap(long)]
+    smtp_url: Option<String>,
+
+    /// Credentials for the SMTP server, in netrc format (default: ~/.netrc)
+    #[clap(long)]
+    smtp_netrc: Option<PathBuf>,
+
+    /// Sender address for --notify-email
+    #[clap(long, default_value = "dirrotate@localhost")]
+    notify_from: String,
+
+    /// POST a summary to this URL when the run fails, cannot free enough space,
+    /// or frees more than --notify-above. Can be given multiple times.
+    #[clap(long)]
+    notify_webhook: Vec<String>,
+
+    /// Payload format for --notify-webhook
+    #[clap(long, arg_enum, default_value = "json")]
+    notify_format: WebhookFormat,
+
+    /// Show a desktop notification summarizing what was deleted. By default only
+    /// when running in a graphical session.
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "auto",
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = "always"
+    )]
+    notify_desktop: DesktopNotify,
+
+    /// Also notify by email and webhook when a run frees more than this
+    #[clap(long, parse(try_from_str = size_parser))]
+    notify_above: Option<u64>,
+
+    /// Publish a retained JSON status message to this MQTT broker (host or host:port)
+    /// after each run
+    #[clap(long, requires = "mqtt-topic")]
+    mqtt_broker: Option<String>,
+
+    /// Topic for --mqtt-broker, e.g. devices/sensor-17/dirrotate
+    #[clap(long)]
+    mqtt_topic: Option<String>,
+
+    /// Push the metrics of each run to this Prometheus Pushgateway, e.g. http://pushgateway:9091
+    #[clap(long)]
+    pushgateway: Option<String>,
+
+    /// Append a record of each run (sizes, deletions, duration) to this file.
+    /// See the history subcommand.
+    #[clap(long)]
+    history_file: Option<PathBuf>,
+
+    /// With --history-file, warn when the forecast says the directory will be over its
+    /// limit within this time, e.g. 7d
+    #[clap(long, default_value = "7d", parse(try_from_str = duration_parser))]
+    forecast_horizon: Duration,
+
+    /// Never delete the N newest files matching each --include-only and --select-for-op
+    /// pattern, counted separately per pattern (or the N newest files overall, if there are
+    /// no patterns)
+    #[clap(long)]
+    keep_newest_per_pattern: Option<usize>,
+
+    /// Set aside part of the size limit for files matching a pattern, e.g. "*.results=2GiB".
+    /// Such files are only deleted to meet the limit while they take up more than that.
+    /// Can be given multiple times.
+    #[clap(long, parse(try_from_str = reservation_parser))]
+    reserve: Vec<Reservation>,
+
+    /// Weigh files matching a pattern, e.g. "*.processed=5". To meet the size limit, files
+    /// with lower weights are deleted first, oldest first within the same weight.
+    /// Files that match no pattern weigh 1. Can be given multiple times.
+    #[clap(long, parse(try_from_str = weight_parser))]
+    weight: Vec<Weight>,
+
+    /// When planning, count only the bytes a file doesn't share with other files (reflinks,
+    /// deduplication) as freed by deleting it. Linux only.
+    #[clap(long)]
+    cow_aware: bool,
+
+    /// Take the usage of the directory's project quota (XFS, ext4) from the kernel, and
+    /// only walk the tree when it is over the limit. Without a max size, the limit is the
+    /// soft limit of the quota (or the hard one). Usually needs root.
+    #[clap(long)]
+    from_quota: bool,
+
+    /// Before walking the tree, estimate the size from what the filesystem uses and from
+    /// the sizes saved by the last run (--state-file). If an estimate is under the limit
+    /// by at least this margin, e.g. 10%, stop there.
+    #[clap(long, parse(try_from_str = fraction_parser))]
+    quick_check: Option<f64>,
+
+    /// Evaluate ages as if it was this time, e.g. 2026-10-14T12:00:00Z or @1791979200
+    /// (seconds since the epoch). For what-if dry-runs, or devices with an unreliable clock.
+    #[clap(long, parse(try_from_str = clock::timestamp_parser))]
+    now: Option<SystemTime>,
+
+    /// Give up on the run after this long, e.g. 60s. A scan that runs out of time deletes
+    /// nothing. Otherwise, deletions in progress are finished, the rest are left for the next
+    /// run, and the exit code is 4.
+    #[clap(long, parse(try_from_str = duration_parser))]
+    timeout: Option<Duration>,
+
+    /// Keep the list of candidates under this much memory, e.g. 64M, sorting them on disk
+    /// (in the temporary directory) when they don't fit.
+    #[clap(
+        long,
+        parse(try_from_str = size_parser),
+        conflicts_with_all = &[
+            "also", "group", "report-groups", "reserve", "weight", "keep-newest-per-pattern",
+        ]
+    )]
+    max_memory: Option<u64>,
+
+    /// How to get the metadata of files during the scan. uring batches the stat calls
+    /// through io_uring (Linux), which is faster for trees of many files on fast disks.
+    #[clap(long, arg_enum, default_value = "std")]
+    io_backend: IoBackend,
+
+    /// How to read directories during the scan. getdents reads many entries per syscall
+    /// (on Linux), which is faster for directories with millions of files.
+    #[clap(long, arg_enum, default_value = "walkdir")]
+    dir_reader: DirReader,
+
+    /// Save the files found by the scan to this file, to plan later runs from with
+    /// --load-scan
+    #[clap(long, conflicts_with = "also")]
+    save_scan: Option<PathBuf>,
+
+    /// Plan from the files of a scan saved with --save-scan instead of walking the
+    /// directory. Ages are measured from the time of the scan, unless --now is given.
+    /// Files that changed since the scan are not deleted.
+    #[clap(long, conflicts_with_all = &["save-scan", "also", "state-file", "rename-rotate"])]
+    load_scan: Option<PathBuf>,
+
+    /// How to treat files with a modification time in the future (clock skew, bad copies).
+    /// They are reported either way.
+    #[clap(long, arg_enum, default_value = "newest")]
+    future_mtime: FutureMtime,
+
+    /// The directory holds dated directories, YYYY with optional MM and DD below. For purges
+    /// by age, only the directories old enough to hold expired files are walked, oldest
+    /// first; files in newer directories are taken not to be expired. Falls back to walking
+    /// everything if the layout doesn't match.
+    #[clap(
+        long,
+        requires = "max-age",
+        conflicts_with_all = &[
+            "max-size", "from-quota", "also", "load-scan", "keep-newest-per-pattern",
+            "rename-rotate",
+        ]
+    )]
+    date_layout: bool,
+
+    /// Claim each file before archiving and deleting it, by renaming it to NAME+SUFFIX, e.g.
+    /// .rotating. A writer that puts files in place by renaming, and renames a file to its
+    /// own suffix (see --writer-suffix) before changing it, then never loses a file it works
+    /// on: whichever rename comes second finds the file changed or gone. Files that an
+    /// interrupted run left claimed are kept.
+    #[clap(long, conflicts_with = "trash")]
+    claim_suffix: Option<String>,
+
+    /// If the directories of the newest files grew by more than 1% of the limit between the
+    /// scan and the deletions, delete that much more. Otherwise, that is only warned about.
+    #[clap(long)]
+    cover_growth: bool,
+
+    /// Keep files modified within this long, e.g. 30s, as they may still be written to. They
+    /// count toward the size all the same.
+    #[clap(long, parse(try_from_str = duration_parser))]
+    settle: Option<Duration>,
+
+    /// Keep files with this suffix, which the writer claims files with while it works on
+    /// them, e.g. .part. Can be given multiple times.
+    #[clap(long)]
+    writer_suffix: Vec<String>,
+
+    /// Wait a random time of up to this long before starting, e.g. 120s, so that many hosts
+    /// on the same schedule don't all hit shared storage at once. Dry-runs start right away.
+    #[clap(long, parse(try_from_str = duration_parser))]
+    jitter: Option<Duration>,
+
+    /// Take turns with other hosts that rotate the same directory, e.g. on a network
+    /// filesystem: only delete while holding its lease (.dirrotate-lease), which lapses this
+    /// long after its holder last renewed it, e.g. 10m. The run is skipped if another host
+    /// holds it, and stops deleting if another host takes it over. Clocks of the hosts need
+    /// to agree to well within this.
+    #[clap(long, parse(try_from_str = duration_parser))]
+    lease_ttl: Option<Duration>,
+
+    /// Wait this long for the lease instead of skipping the run right away
+    #[clap(long, requires = "lease-ttl", parse(try_from_str = duration_parser))]
+    lease_wait: Option<Duration>,
+
+    /// What to do about entries the scan can't read, e.g. directories without permission.
+    /// Whatever they hold is left out of the size, so a run may delete too little.
+    #[clap(long, arg_enum, default_value = "warn")]
+    on_scan_error: OnScanError,
+
+    /// Exit with 5 (partial) if the scan could not read everything, even if the run went
+    /// well otherwise
+    #[clap(long)]
+    partial_on_scan_error: bool,
+
+    /// Refuse to run if the plan deletes more than this fraction of all files, e.g. 90% or 0.9
+    #[clap(long, default_value = "90%", parse(try_from_str = fraction_parser))]
+    max_delete_fraction: f64,
+
+    /// Disable the safety checks: Allow rotating /, a home directory or a filesystem root, and
+    /// allow deleting more than --max-delete-fraction of the files
+    #[clap(long)]
+    yes_i_mean_it: bool,
+
+    /// Show a summary of the plan and ask for confirmation before deleting anything
+    #[clap(long)]
+    interactive: bool,
+
+    /// Review the plan by directory before deleting anything, taking directories or files out
+    #[clap(long, conflicts_with = "interactive")]
+    review: bool,
+
+    /// Answer yes to the --interactive confirmation
+    #[clap(short, long)]
+    yes: bool,
+
     #[clap(flatten)]
     verbose: Verbosity,
 }
 
-fn size_parser(s: &str) -> Result<u64, Error> {
-    parse_size(s)
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the runs recorded with --history-file, and how fast each directory grows
+    History {
+        /// The file given to --history-file
+        history_file: PathBuf,
+
+        /// Only show this directory
+        #[clap(long)]
+        directory: Option<PathBuf>,
+
+        /// Number of runs to show per directory
+        #[clap(long, default_value_t = 20)]
+        last: usize,
+    },
+    /// Estimate from the runs recorded with --history-file when each directory will be
+    /// over its limit even though rotation deletes all it may
+    Forecast {
+        /// The file given to --history-file
+        history_file: PathBuf,
+
+        /// Only show this directory
+        #[clap(long)]
+        directory: Option<PathBuf>,
+    },
+    /// Estimate from the runs recorded with --history-file how long files stay before
+    /// rotation deletes them, at the current intake
+    Simulate {
+        /// The file given to --history-file
+        history_file: PathBuf,
+
+        /// Only show this directory
+        #[clap(long)]
+        directory: Option<PathBuf>,
+
+        /// The size limit to simulate. By default, the limit of the latest run.
+        #[clap(long, parse(try_from_str = size_parser))]
+        max_size: Option<u64>,
+
+        /// The age limit to simulate
+        #[clap(long, parse(try_from_str = duration_parser))]
+        max_age: Option<Duration>,
+    },
+    /// Put the files that the last run with --trash moved to the trash back where they were.
+    /// With --checksum, only files whose content is unchanged are put back. After a run with
+    /// --also, give the first directory: its manifest covers all of them.
+    UndoLast {
+        directory: PathBuf,
+
+        /// Only check what would be put back
+        #[clap(short, long)]
+        dryrun: bool,
+    },
+    /// Print the messages for operators in English, in the format of --messages, to translate
+    Messages,
+    /// Show which of the given patterns each path hits, in the order they are applied. Paths
+    /// are read from stdin, one per line and relative to the directory, unless --sample is
+    /// given.
+    MatchTest {
+        directory: PathBuf,
+
+        /// Check the first N files of the directory instead
+        #[clap(long)]
+        sample: Option<usize>,
+
+        /// Like the main --include-only
+        #[clap(short, long)]
+        include_only: Vec<String>,
+
+        /// Like the main --exclude
+        #[clap(short, long, conflicts_with = "include-only")]
+        exclude: Option<String>,
+
+        /// Like the main --select-for-op
+        #[clap(short, long)]
+        select_for_op: Vec<String>,
+
+        /// Like the main --protect-from-op
+        #[clap(short, long, conflicts_with = "select-for-op")]
+        protect_from_op: Option<String>,
+
+        /// Like the main --match-relative
+        #[clap(long)]
+        match_relative: bool,
+
+        /// Like the main --no-default-protections
+        #[clap(long)]
+        no_default_protections: bool,
+    },
+    /// Check that this user can read every subdirectory and file, and delete from every
+    /// subdirectory, e.g. before deploying a run that is unattended
+    Preflight {
+        directory: PathBuf,
+
+        /// Leave out files that are excluded from Time Machine backups, like the main
+        /// --skip-time-machine-excluded
+        #[clap(long)]
+        skip_time_machine_excluded: bool,
+    },
+    /// Keep everything below a directory from being deleted, e.g. during an investigation,
+    /// or lift that hold again
+    Hold {
+        #[clap(subcommand)]
+        action: HoldAction,
+    },
+    /// Check the settings of a run in a file, as `dirrotate @FILE` takes them, without
+    /// rotating: each option, glob pattern and directory, and how they go together. Errors
+    /// are reported with the line they are on.
+    CheckConfig { file: PathBuf },
+    /// Carry out a plan made with --dryrun --output json. Files that changed since are kept.
+    Apply {
+        plan_file: PathBuf,
+
+        /// Only check the plan against the files
+        #[clap(short, long)]
+        dryrun: bool,
+    },
+    /// Show the largest files, subdirectories and groups of files with the same stem,
+    /// for designing patterns
+    Top {
+        directory: PathBuf,
+
+        /// Number of entries to show in each list
+        #[clap(short, long, default_value_t = 20)]
+        n: usize,
+
+        /// Only consider files matching this glob pattern, like the main --include-only
+        #[clap(short, long)]
+        include_only: Vec<String>,
+
+        /// Leave out files matching this glob pattern, like the main --exclude
+        #[clap(short, long, conflicts_with = "include-only")]
+        exclude: Option<String>,
+    },
+    /// Show what each combination of the given limits would delete from a scan saved with
+    /// --save-scan, and the oldest file it would keep. Ages are from the time of the scan.
+    Whatif {
+        /// The file given to --save-scan
+        #[clap(long)]
+        scan: PathBuf,
+
+        /// A size limit to try
+        #[clap(long, parse(try_from_str = size_parser), required_unless_present = "max-age")]
+        max_size: Vec<u64>,
+
+        /// An age limit to try
+        #[clap(long, parse(try_from_str = duration_parser))]
+        max_age: Vec<Duration>,
+
+        /// Only consider files matching this glob pattern, like the main --include-only
+        #[clap(short, long)]
+        include_only: Vec<String>,
+
+        /// Leave out files matching this glob pattern, like the main --exclude
+        #[clap(short, long, conflicts_with = "include-only")]
+        exclude: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum HoldAction {
+    /// Put a hold on the directory, with a .dirrotate-hold marker file
+    Add {
+        path: PathBuf,
+
+        /// Why the files are held, shown when a run skips them
+        #[clap(long)]
+        reason: Option<String>,
+    },
+    /// Lift the hold on the directory
+    Remove { path: PathBuf },
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ChecksumAlgorithm {
+    Sha256,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+/// What to do with groups that lack required members
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum IncompleteGroups {
+    /// Delete them before the complete groups, whatever their age
+    First,
+    /// Never delete them
+    Protect,
+    /// Treat them like the complete groups (they are still reported)
+    Ignore,
+}
+
+/// How to treat files modified in the future
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum FutureMtime {
+    /// Treat them as the oldest files
+    Oldest,
+    /// Treat them as the newest files (they never expire)
+    Newest,
+    /// Never delete them
+    Protect,
+}
+
+/// How the scan gets the metadata of files
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum IoBackend {
+    /// One stat call at a time
+    Std,
+    /// Batches of statx calls through io_uring, where the kernel supports it
+    Uring,
+}
+
+/// How the scan reads directories
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DirReader {
+    Walkdir,
+    /// getdents64 with a large buffer, on Linux
+    Getdents,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DesktopNotify {
+    Auto,
+    Always,
+    Never,
+}
+
+fn size_parser(s: &str) -> Result<u64, String> {
+    if s.ends_with('%') {
+        return Err(String::from(
+            "only the size limit can be a percentage (of the capacity of the filesystem)",
+        ));
+    }
+    parse_size(s).map_err(|e| format!("{}, expected a size like 5000, 3K or 1.5GiB", e))
+}
+
+/// A size limit as given: in bytes, or as a share of the capacity of the filesystem
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SizeLimit {
+    Bytes(u64),
+    OfCapacity(f64),
+}
+
+fn size_limit_parser(s: &str) -> Result<SizeLimit, String> {
+    match s.ends_with('%') {
+        true => fraction_parser(s).map(SizeLimit::OfCapacity),
+        false => size_parser(s).map(SizeLimit::Bytes),
+    }
+}
+
+/// The one format for durations of all options: a number and a unit, possibly several,
+/// e.g. 90s, 15m, 7d, 2w or 1d12h
+fn duration_parser(s: &str) -> Result<Duration, String> {
+    humantime::parse_duration(s)
+        .map_err(|e| format!("{}, expected a duration like 90s, 15m, 7d, 2w or 1d12h", e))
+}
+
+fn fraction_parser(s: &str) -> Result<f64, String> {
+    let fraction = match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|p| p / 100.0),
+        None => s.parse::<f64>(),
+    }
+    .map_err(|e| e.to_string())?;
+    if (0.0..=1.0).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err(String::from("must be between 0% and 100%"))
+    }
+}
+
+fn bandwidth_parser(s: &str) -> Result<u64, String> {
+    let size = s.strip_suffix("/s").unwrap_or(s);
+    match parse_size(size) {
+        Ok(0) => Err(String::from("must be more than 0 bytes per second")),
+        Ok(bytes_per_sec) => Ok(bytes_per_sec),
+        Err(why) => Err(why.to_string()),
+    }
+}
+
+fn upload_parser(s: &str) -> Result<String, String> {
+    get_backend(s, None).map(|_| s.to_string())
+}
+
+fn ssh_target_parser(s: &str) -> Result<String, String> {
+    get_ssh_backend(s, None).map(|_| s.to_string())
+}
+
+fn encryption_parser(s: &str) -> Result<String, String> {
+    parse_encryption(s).map(|_| s.to_string())
 }
 
-fn file_filter<'a>(
-    items: impl Iterator<Item = (DirEntry, Metadata)> + 'a,
+fn file_filter<'a, T: Borrow<FileRecord> + 'a>(
+    items: impl Iterator<Item = T> + 'a,
     select_pattern: &'a Option<impl PathMatcher>,
     protect_pattern: &'a Option<impl PathMatcher>,
-) -> impl Iterator<Item = (DirEntry, Metadata)> + 'a {
-    // Returns files (not dirs) matching the optional pattern, including file metadata
-
-    items.filter(move |x| {
-        if let Some(p) = select_pattern {
-            p.matches(&x.0.path().canonicalize().expect("Malformed Path")) && x.0.path().is_file()
-        } else if let Some(p) = protect_pattern {
-            !p.matches(&x.0.path().canonicalize().expect("Malformed Path")) && x.0.path().is_file()
-        } else {
-            x.0.path().is_file()
+) -> impl Iterator<Item = T> + 'a {
+    // Returns files (not dirs) matching the optional pattern. Takes records or references
+    // to them, so that filtering a list doesn't copy it.
+
+    items.filter(move |x| path_filter(&x.borrow().path, select_pattern, protect_pattern))
+}
+
+fn path_filter(
+    path: &Path,
+    select_pattern: &Option<impl PathMatcher>,
+    protect_pattern: &Option<impl PathMatcher>,
+) -> bool {
+    if select_pattern.is_none() && protect_pattern.is_none() {
+        return path.is_file();
+    }
+    let canonical = canonical_path(path);
+    matches_patterns(&canonical, select_pattern, protect_pattern) && path.is_file()
+}
+
+fn matches_patterns(
+    canonical: &Path,
+    select_pattern: &Option<impl PathMatcher>,
+    protect_pattern: &Option<impl PathMatcher>,
+) -> bool {
+    if let Some(p) = select_pattern {
+        p.matches(canonical)
+    } else if let Some(p) = protect_pattern {
+        !p.matches(canonical)
+    } else {
+        true
+    }
+}
+
+fn size_in_range(len: u64, min: Option<u64>, max: Option<u64>) -> bool {
+    // Files outside the range are kept, but still count toward the directory size
+    !matches!(min, Some(min) if len < min) && !matches!(max, Some(max) if len > max)
+}
+
+fn is_of_type(path: &Path, type_patterns: &[String]) -> bool {
+    // Whether the sniffed content type matches any of the patterns (if any are given)
+    if type_patterns.is_empty() {
+        return true;
+    }
+    match sniff_mime_type(path) {
+        Some(mime) => type_patterns.iter().any(|p| mime_type_matches(p, mime)),
+        None => false,
+    }
+}
+
+fn keep_reason(
+    file: &FileRecord,
+    canonical: &Path,
+    settings: &Cli,
+    select_pattern: &Option<impl PathMatcher>,
+    protect_pattern: &Option<impl PathMatcher>,
+    protected_paths: &HashSet<PathBuf>,
+) -> Option<&'static str> {
+    // Why a file that counts toward the size is not a candidate, if it isn't.
+    // Content sniffing is the most expensive check, so it goes last.
+    let has_suffix = |suffix: &str| file.path.to_string_lossy().ends_with(suffix);
+    if settings
+        .writer_suffix
+        .iter()
+        .any(|suffix| has_suffix(suffix))
+    {
+        return Some("claimed by the writer (--writer-suffix)");
+    }
+    if settings.claim_suffix.as_deref().is_some_and(has_suffix) {
+        return Some("left claimed by an interrupted run (--claim-suffix)");
+    }
+    if let Some(settle) = settings.settle {
+        if clock::now()
+            .duration_since(file.modified)
+            .unwrap_or_default()
+            < settle
+        {
+            return Some("modified within --settle");
+        }
+    }
+    if !matches_patterns(canonical, select_pattern, protect_pattern) {
+        return match select_pattern.is_some() {
+            true => Some("not matched by --select-for-op"),
+            false => Some("matched by --protect-from-op"),
+        };
+    }
+    if !size_in_range(
+        file.len,
+        settings.skip_smaller_than,
+        settings.skip_larger_than,
+    ) {
+        return Some("outside --skip-smaller-than/--skip-larger-than");
+    }
+    // A listed directory protects everything below it
+    if canonical
+        .ancestors()
+        .any(|path| protected_paths.contains(path))
+    {
+        return Some(
+            "listed by --protect-from-list/--protect-from-command or below a directory listed there, or among --keep-newest-per-pattern",
+        );
+    }
+    if is_from_future(file) {
+        warn!(
+            "File has a modification time in the future: {}",
+            file.path.display()
+        );
+        if settings.future_mtime == FutureMtime::Protect {
+            return Some("modified in the future, with --future-mtime protect");
+        }
+    }
+    if !is_of_type(&file.path, &settings.only_type) {
+        return Some("not of --only-type");
+    }
+    None
+}
+
+/// Of the files listed, those that count toward the size, each tagged with why it may not
+/// be deleted (if it may not). All patterns are matched in this one pass, against a single
+/// canonicalization of the path, noting what is needed once the scan is done.
+fn scan<'a>(
+    settings: &'a Cli,
+    base_directory: &Path,
+    files: Box<dyn Iterator<Item = FileRecord>>,
+    (include_only_pattern, exclude_pattern): (
+        &'a Option<impl PathMatcher>,
+        &'a Option<impl PathMatcher>,
+    ),
+    (select_pattern, protect_pattern): (&'a Option<impl PathMatcher>, &'a Option<impl PathMatcher>),
+    protected_paths: &'a HashSet<PathBuf>,
+    notes: &'a ScanNotes,
+) -> impl Iterator<Item = FileRecord> + 'a {
+    notes.scanning();
+    let needs_canonical = include_only_pattern.is_some()
+        || exclude_pattern.is_some()
+        || select_pattern.is_some()
+        || protect_pattern.is_some()
+        || !protected_paths.is_empty();
+    let mut holds = Holds::new(base_directory);
+    let mut deletable = Deletable::default();
+    let base_dir = base_directory.to_path_buf();
+    files.filter_map(move |mut file| {
+        let canonical = match needs_canonical {
+            true => Cow::Owned(canonical_path(&file.path)),
+            false => Cow::Borrowed(file.path.as_path()),
+        };
+        notes.unmatched.see(&canonical);
+        if !matches_patterns(&canonical, include_only_pattern, exclude_pattern) {
+            return None;
         }
+        notes.count(&file);
+        let kept_by = keep_reason(
+            &file,
+            &canonical,
+            settings,
+            select_pattern,
+            protect_pattern,
+            protected_paths,
+        );
+        file.kept_by = (kept_by)
+            .or_else(|| {
+                (!settings.no_default_protections && is_default_protected(&base_dir, &file.path))
+                    .then_some("in a directory protected by default")
+            })
+            .or_else(|| holds.holds(&file.path).then_some("below a .dirrotate-hold"))
+            .or_else(|| deletable.why_not(&file.path));
+        Some(file)
     })
 }
 
-fn list_all_files(path: &Path) -> impl Iterator<Item = (DirEntry, Metadata)> {
-    fn is_hidden(entry: &DirEntry) -> bool {
-        entry
-            .file_name()
-            .to_str()
-            .map(|s| s.starts_with("."))
-            .unwrap_or(false)
+fn is_from_future(file: &FileRecord) -> bool {
+    file.modified > clock::now()
+}
+
+fn policy_modified(file: &FileRecord, future_mtime: FutureMtime) -> SystemTime {
+    // The modification time to order and expire by
+    if future_mtime == FutureMtime::Oldest && is_from_future(file) {
+        SystemTime::UNIX_EPOCH
+    } else {
+        file.modified
+    }
+}
+
+fn is_expired(file: &FileRecord, settings: &Cli) -> bool {
+    // Files with a modification time in the future have no age, unless they count as oldest
+    is_past_max_age(policy_modified(file, settings.future_mtime), settings)
+}
+
+fn is_past_max_age(modified: SystemTime, settings: &Cli) -> bool {
+    let age = clock::now().duration_since(modified).unwrap_or_default();
+    matches!(settings.max_age, Some(max_age) if age > max_age)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|s| s.starts_with('.'))
+        .unwrap_or(false)
+}
+
+/// Directories whose files are never deleted unless --no-default-protections is given: what
+/// fsck recovers. Version control metadata, trash and snapshots are in hidden directories,
+/// which are never entered anyway.
+const DEFAULT_PROTECTED: &[&str] = &["lost+found"];
+
+fn is_default_protected(base_dir: &Path, path: &Path) -> bool {
+    let relative = path.strip_prefix(base_dir).unwrap_or(path);
+    let dirs = relative.parent().into_iter().flat_map(Path::iter);
+    dirs.filter_map(|name| name.to_str())
+        .any(|name| DEFAULT_PROTECTED.contains(&name))
+}
+
+fn is_skipped(path: &Path, skip_time_machine_excluded: bool) -> bool {
+    // Excluding a directory from Time Machine excludes everything in it
+    is_hidden(path) || (skip_time_machine_excluded && timemachine::is_excluded(path))
+}
+
+fn list_all_files(
+    path: &Path,
+    skip_time_machine_excluded: bool,
+) -> Box<dyn Iterator<Item = FileRecord>> {
+    if let Some(scan) = scanfile::loaded() {
+        let path = path.to_path_buf();
+        return Box::new(
+            (scan.files.iter())
+                .filter(move |x| x.path.starts_with(&path))
+                .cloned(),
+        );
+    }
+    if let Some(leaves) = datelayout::leaves() {
+        let path = path.to_path_buf();
+        return Box::new(
+            (leaves.iter())
+                .filter(move |leaf| leaf.starts_with(&path))
+                .flat_map(move |leaf| walk_files(leaf, skip_time_machine_excluded)),
+        );
+    }
+    walk_files(path, skip_time_machine_excluded)
+}
+
+fn walk_files(
+    path: &Path,
+    skip_time_machine_excluded: bool,
+) -> Box<dyn Iterator<Item = FileRecord>> {
+    // Only symlinks need a stat to tell whether they're (to) a file
+    let mut entries: Box<dyn Iterator<Item = PathBuf>> = if getdents::is_enabled() {
+        // Like walkdir's filter_entry, which also applies to the directory itself
+        let skipped = is_skipped(path, skip_time_machine_excluded);
+        Box::new(
+            getdents::walk(path, move |p| is_skipped(p, skip_time_machine_excluded))
+                .filter(move |_| !skipped)
+                .filter_map(|x| match x {
+                    Ok((path, Kind::File)) => Some(path),
+                    Ok((path, Kind::Symlink)) if path.is_file() => Some(path),
+                    Ok(_) => None,
+                    Err(why) => {
+                        scanerrors::report(why);
+                        None
+                    }
+                }),
+        )
+    } else {
+        Box::new(
+            WalkDir::new(path)
+                .min_depth(1)
+                .into_iter()
+                .filter_entry(move |e| !is_skipped(e.path(), skip_time_machine_excluded))
+                .filter_map(|x| match x {
+                    Ok(e)
+                        if e.file_type().is_file()
+                            || (e.path_is_symlink() && e.path().is_file()) =>
+                    {
+                        Some(e.into_path())
+                    }
+                    Ok(_) => None,
+                    Err(why) => {
+                        scanerrors::report(why);
+                        None
+                    }
+                }),
+        )
+    };
+    let stat = |path: PathBuf| {
+        check_scan_deadline();
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) => Some(FileRecord::new(path, &metadata)),
+            Err(why) => {
+                scanerrors::report(format!("{}: {}", path.display(), why));
+                None
+            }
+        }
+    };
+    let files: Box<dyn Iterator<Item = FileRecord>> = match uring::batcher() {
+        Some(mut batcher) => Box::new(
+            iter::from_fn(move || {
+                let batch: Vec<PathBuf> = entries.by_ref().take(uring::BATCH).collect();
+                (!batch.is_empty()).then_some(batch)
+            })
+            .flat_map(move |batch| {
+                check_scan_deadline();
+                // What the kernel couldn't stat is tried again one by one
+                let records: Vec<FileRecord> = (batcher.stat(batch).into_iter())
+                    .filter_map(|record| record.map_or_else(stat, Some))
+                    .collect();
+                records
+            }),
+        ),
+        None => Box::new(entries.filter_map(stat)),
+    };
+    files
+}
+
+fn check_scan_deadline() {
+    if deadline::passed() {
+        error!("Out of time while scanning, nothing was deleted");
+        process::exit(EXIT_TIMED_OUT);
     }
-    WalkDir::new(path)
-        .min_depth(1)
+}
+
+fn state_fingerprint(settings: &Cli) -> String {
+    // What the saved sizes depend on
+    format!(
+        "include_only={:?} exclude={:?}{}",
+        settings.include_only,
+        settings.exclude,
+        match settings.skip_time_machine_excluded {
+            true => " skip_time_machine_excluded",
+            false => "",
+        }
+    )
+}
+
+fn measure_size(
+    path: &Path,
+    previous: &SizeState,
+    skip_time_machine_excluded: bool,
+    is_counted: impl Fn(&Path) -> bool,
+) -> SizeState {
+    // Like list_all_files, but only files in directories that changed since the previous
+    // state get stat'ed. The directory is stat'ed before its entries are read, so that any
+    // change during the scan is caught next time.
+    let mut state = SizeState::default();
+    let mut unchanged_dirs: HashSet<PathBuf> = HashSet::new();
+    let mut dir_count: usize = 0;
+    for entry in WalkDir::new(path)
         .into_iter()
-        .filter_entry(|e| !is_hidden(e))
-        .filter_map(|x| match x {
-            Ok(e) => {
-                if e.path().is_file() {
-                    Some(e)
-                } else {
-                    None
+        .filter_entry(|e| e.depth() == 0 || !is_skipped(e.path(), skip_time_machine_excluded))
+    {
+        check_scan_deadline();
+        let entry = match entry {
+            Ok(e) => e,
+            Err(why) => {
+                scanerrors::report(why);
+                continue;
+            }
+        };
+        if entry.file_type().is_dir() {
+            // Like the entries we can't read, one gone since it was listed is skipped
+            let modified = match entry.metadata() {
+                Ok(metadata) => metadata
+                    .modified()
+                    .expect("Last Modified Time is not available on this platform"),
+                Err(why) => {
+                    scanerrors::report(why);
+                    continue;
                 }
+            };
+            let cached_size = previous.cached_size(entry.path(), modified);
+            if cached_size.is_some() {
+                unchanged_dirs.insert(entry.path().to_path_buf());
             }
-            Err(why) => {
-                println!("Traversal Error: {}", why);
-                None
+            state.insert(
+                entry.path().to_path_buf(),
+                modified,
+                cached_size.unwrap_or(0),
+            );
+            dir_count += 1;
+        } else if let Some(parent) = entry.path().parent() {
+            if unchanged_dirs.contains(parent) || !is_counted(entry.path()) {
+                continue;
             }
-        })
-        .map(|e| {
-            (
-                e.clone(),
-                e.metadata().expect("Could not get metadata from file"),
-            )
-        })
+            match entry.metadata() {
+                Ok(metadata) => state.add_file(parent, metadata.len()),
+                Err(why) => scanerrors::report(why),
+            }
+        }
+    }
+    info!(
+        "Reused saved sizes for {} of {} directories",
+        unchanged_dirs.len(),
+        dir_count
+    );
+    state
+}
+
+fn freeable_size(file: &FileRecord, cow_aware: bool) -> u64 {
+    // Files that share extents with each other are assumed to outlive their sharers,
+    // so some deletions may free more than counted
+    if !cow_aware {
+        return file.len;
+    }
+    match extents::unshared_size(&file.path) {
+        Ok(unshared) => unshared.min(file.len),
+        Err(why) => {
+            debug!(
+                "Could not map the extents of {}: {}",
+                file.path.display(),
+                why
+            );
+            file.len
+        }
+    }
 }
 
-fn register_operations(mut entries: Vec<(DirEntry, Metadata)>, size_to_free: u64) -> Vec<PathBuf> {
-    // For now: Don't group, just blindly consume.
-    // Assume entries to be sorted such that the ones to keep are first.
+/// Files that are deleted together: a group, or a single file
+type Unit<'a> = Vec<&'a FileRecord>;
+
+fn register_operations(
+    units: &mut Vec<Unit>,
+    size_to_free: u64,
+    reservations: &mut Reservations,
+    cow_aware: bool,
+    reserved: &mut HashSet<PathBuf>,
+) -> Vec<Operation> {
+    // Assume units to be sorted such that the ones to keep are first.
     // As a consequence, we consume from the end of the vector.
     let mut size_freed: u64 = 0;
-    let mut operations: Vec<PathBuf> = Vec::new();
-    while size_freed < size_to_free && entries.len() > 0 {
-        if let Some(e) = entries.pop() {
-            operations.push(e.0.into_path());
-            size_freed += e.1.len();
+    let mut operations: Vec<Operation> = Vec::new();
+    while size_freed < size_to_free && !units.is_empty() {
+        if let Some(unit) = units.pop() {
+            let files: Vec<(&Path, u64)> = unit.iter().map(|x| (x.path.as_path(), x.len)).collect();
+            if !reservations.try_remove(&files) {
+                reserved.extend(unit.iter().map(|x| x.path.clone()));
+                continue;
+            }
+            for e in unit {
+                size_freed += freeable_size(e, cow_aware);
+                operations.push(Operation::of(e));
+            }
         } else {
             // This is unreachable. When {if|while}-let chains are fully stabilized in 1.64
             // (https://github.com/rust-lang/rust/issues/53667), use a while-let chain
             unreachable!("Couldn't pop, but length is not zero!")
         }
     }
-    return operations;
+    operations
 }
 
-fn canonicalize_base_dir(path: &PathBuf) -> PathBuf {
-    path.canonicalize()
-        .expect("Directory path is not a proper path.")
+fn is_incomplete(settings: &Cli, unit: &Unit) -> bool {
+    settings.group
+        && !missing_members(
+            unit.iter().map(|x| x.path.as_path()),
+            &settings.require_member,
+        )
+        .is_empty()
 }
 
-fn main() {
-    // Setup
-    let settings = Cli::parse();
-    env_logger::Builder::new()
-        .filter_level(settings.verbose.log_level_filter())
-        .init();
-
-    // Parse settings
-    let base_directory = canonicalize_base_dir(&settings.directory);
-    info!("Culling directory: {}", base_directory.display());
-
-    if settings.group {
-        warn!("Group-by is still not implemented")
+/// Split the candidates into units to delete. With --group, that's the groups whose
+/// members are all candidates (and that are complete, with --incomplete-groups protect).
+/// The groups that stay are returned as well, with why, to explain it.
+fn deletion_units<'a>(
+    settings: &Cli,
+    files: &[FileRecord],
+    candidates: Vec<&'a FileRecord>,
+) -> (Vec<Unit<'a>>, HashMap<PathBuf, &'static str>) {
+    let mut kept: HashMap<PathBuf, &'static str> = HashMap::new();
+    if !settings.group {
+        return (candidates.into_iter().map(|x| vec![x]).collect(), kept);
     }
-
-    // Canonicalize glob patterns
-    let include_only_matcher = get_path_matcher(&base_directory, &settings.include_only);
-    let exclude_matcher = get_path_matcher(&base_directory, &settings.exclude);
-    let select_matcher = get_path_matcher(&base_directory, &settings.select_for_op);
-    let protect_matcher = get_path_matcher(&base_directory, &settings.protect_from_op);
-
-    // Get vec of all files
-    let files: Vec<(DirEntry, Metadata)> = file_filter(
-        list_all_files(&base_directory),
-        &include_only_matcher,
-        &exclude_matcher,
-    )
-    .collect();
-
-    // Calculate size
-    let current_size: u64 = files.iter().map(|f| f.1.len()).sum();
-    let size_to_free = current_size.saturating_sub(settings.max_size);
-    info!("Size to free: {}", size_to_free);
-    // Possible early out
-    if size_to_free == 0 {
-        return ();
+    let candidate_paths: HashSet<&Path> = candidates.iter().map(|x| x.path.as_path()).collect();
+    for group in split_groups(files.iter().map(|x| x.path.as_path()), &candidate_paths) {
+        kept.insert(
+            group,
+            "in a group with files that may not be deleted (--group)",
+        );
     }
-
-    // Get vec of files available for operation (deletion)
-    let mut deletable: Vec<(DirEntry, Metadata)> =
-        file_filter(files.iter().cloned(), &select_matcher, &protect_matcher).collect();
-    // Sort entries on last_modified
-    deletable.sort_by_key(|x| {
-        x.1.modified()
-            .expect("Last Modified Time is not available on this platform")
+    let whole: Vec<&FileRecord> = candidates
+        .into_iter()
+        .filter(|x| !kept.contains_key(&stem_group(&x.path)))
+        .collect();
+    let mut units = group_by_stem(whole, |x| &x.path);
+    let incomplete = units
+        .iter()
+        .filter(|unit| is_incomplete(settings, unit))
+        .count();
+    if incomplete > 0 {
+        warn!("{} groups lack a --require-member", incomplete);
+    }
+    if settings.incomplete_groups == IncompleteGroups::Protect {
+        units.retain(|unit| {
+            if !is_incomplete(settings, unit) {
+                return true;
+            }
+            kept.insert(
+                stem_group(&unit[0].path),
+                "in an incomplete group, with --incomplete-groups protect",
+            );
+            false
+        });
+    }
+    (units, kept)
+}
+
+fn notify(settings: &Cli, summary: &Summary) {
+    if settings.dryrun {
+        return;
+    }
+    let desktop = match settings.notify_desktop {
+        DesktopNotify::Auto => in_desktop_session(),
+        DesktopNotify::Always => true,
+        DesktopNotify::Never => false,
+    };
+    if desktop && (summary.deleted > 0 || summary.is_failure()) {
+        if let Err(why) = send_desktop(summary) {
+            warn!(
+                "Could not show desktop notification with notify-send: {}",
+                why
+            );
+        }
+    }
+    let above_threshold = matches!(settings.notify_above, Some(above) if summary.freed > above);
+    if !summary.is_failure() && !above_threshold {
+        return;
+    }
+    if let (false, Some(smtp_url)) = (settings.notify_email.is_empty(), &settings.smtp_url) {
+        let email = EmailSettings {
+            smtp_url,
+            netrc: settings.smtp_netrc.as_deref(),
+            from: &settings.notify_from,
+            to: &settings.notify_email,
+        };
+        if let Err(why) = send_email(&email, summary) {
+            warn!("Could not send email notification: {}", why);
+        }
+    }
+    for url in &settings.notify_webhook {
+        if let Err(why) = send_webhook(url, settings.notify_format, summary) {
+            warn!("Could not notify {}: {}", url, why);
+        }
+    }
+}
+
+/// Where to upload files before deleting them, if anywhere
+fn upload_backend(
+    settings: &Cli,
+    bandwidth_limit: Option<u64>,
+) -> Result<Option<Box<dyn Backend>>, String> {
+    let upload =
+        match (&settings.upload_to, &settings.offload_ssh) {
+            (Some(url), _) => Some(get_backend(url, bandwidth_limit).map_err(|why| {
+                format!("--upload-to {} is not a valid upload target: {}", url, why)
+            })?),
+            (_, Some(target)) => Some(get_ssh_backend(target, bandwidth_limit).map_err(|why| {
+                format!(
+                    "--offload-ssh {} is not a valid SSH target: {}",
+                    target, why
+                )
+            })?),
+            _ => None,
+        };
+    match (upload, &settings.archive_encrypt) {
+        (Some(upload), Some(encryption)) => (get_encrypted_backend(upload, encryption))
+            .map(Some)
+            .map_err(|why| {
+                format!(
+                    "--archive-encrypt {} is not a valid encryption: {}",
+                    encryption, why
+                )
+            }),
+        (upload, _) => Ok(upload),
+    }
+}
+
+fn execute(
+    settings: &Cli,
+    base_dir: &Path,
+    operations: impl Iterator<Item = Operation>,
+) -> Outcome {
+    // Concurrent uploads share the limit
+    let bandwidth_limit = settings
+        .bandwidth_limit
+        .map(|limit| (limit / settings.concurrency.max(1) as u64).max(1));
+    let upload = upload_backend(settings, bandwidth_limit)
+        .expect("The upload target was checked with the settings");
+    let options = Options {
+        concurrency: settings.concurrency,
+        dryrun: settings.dryrun,
+        upload,
+        audit_log: settings.audit_log.clone(),
+        checksum: settings.checksum == Some(ChecksumAlgorithm::Sha256),
+        hash_threads: settings.hash_threads,
+        trash: settings
+            .trash
+            .then(|| Trash::open().expect("Could not open the trash")),
+        journal: Some(journal::journal_path(
+            settings.journal_dir.as_deref().unwrap_or(base_dir),
+        )),
+        claim_suffix: settings.claim_suffix.clone(),
+    };
+    let mut executor = Executor::new(base_dir, options);
+    for operation in operations {
+        executor.submit(operation);
+    }
+    let mut outcome = executor.finish();
+    if settings.group && !settings.dryrun {
+        let trash = settings
+            .rollback_groups
+            .then(|| Trash::open().expect("Could not open the trash"));
+        settle_groups(
+            &mut outcome,
+            stem_group,
+            trash.as_ref(),
+            settings.audit_log.as_deref(),
+        );
+    }
+    if settings.trash && !settings.dryrun {
+        let trashed: Vec<undo::Trashed> = (outcome.deleted.iter())
+            .filter_map(|operation| {
+                Some(undo::Trashed {
+                    trashed: outcome.trashed.get(&operation.path)?.clone(),
+                    original: operation.path.clone(),
+                    sha256: outcome.sha256.get(&operation.path).cloned(),
+                })
+            })
+            .collect();
+        // In the directory itself, like the journal, and not a parent that --also
+        // directories share
+        let manifest = undo::manifest_path(settings.journal_dir.as_deref().unwrap_or(base_dir));
+        undo::save_manifest(&manifest, clock::now(), &trashed);
+    }
+    outcome
+}
+
+fn finalize_and_execute(
+    settings: &Cli,
+    base_dir: &Path,
+    operations: Vec<Operation>,
+    size_to_free: Option<u64>,
+) -> Option<Outcome> {
+    // Returns nothing if the plan was abandoned
+    let operations = if let Some(grace) = settings.grace {
+        let grace_file = settings
+            .grace_file
+            .clone()
+            .unwrap_or_else(|| base_dir.join(".dirrotate-grace"));
+        let mut marks = GraceMarks::load(&grace_file);
+        let due = marks.apply(operations, grace, clock::now());
+        if !settings.dryrun {
+            marks.save(&grace_file);
+        }
+        due
+    } else {
+        operations
+    };
+    if let Some(plan_file) = &settings.diff {
+        diff_plan(plan_file, base_dir, &operations);
+    }
+    let operations = if settings.review && !settings.dryrun {
+        review_plan(operations, size_to_free)?
+    } else {
+        operations
+    };
+    if settings.interactive && !settings.yes && !settings.dryrun && !confirm_plan(&operations) {
+        return None;
+    }
+
+    // perform_operations
+    if settings.dryrun {
+        if settings.output == OutputFormat::Json {
+            println!("{}", plan::to_json(base_dir, &operations));
+        }
+        info!("Planned operations:");
+    }
+    Some(execute(settings, base_dir, operations.into_iter()))
+}
+
+fn diff_plan(plan_file: &Path, base_dir: &Path, operations: &[Operation]) {
+    // Compare with the previous plan, then keep this one for the next run
+    let previous: HashSet<PathBuf> = match fs::read_to_string(plan_file) {
+        Ok(content) => match plan::from_json(&content) {
+            Ok(plan) => plan.files.into_iter().map(|file| file.path).collect(),
+            Err(why) => {
+                warn!(
+                    "Ignoring the previous plan in {}: {}",
+                    plan_file.display(),
+                    why
+                );
+                HashSet::new()
+            }
+        },
+        Err(why) => {
+            info!("No previous plan in {} ({})", plan_file.display(), why);
+            HashSet::new()
+        }
+    };
+    let current: HashSet<&Path> = operations.iter().map(|o| o.path.as_path()).collect();
+    let added: Vec<&Operation> = (operations.iter())
+        .filter(|o| !previous.contains(&o.path))
+        .collect();
+    let mut dropped: Vec<&PathBuf> = (previous.iter())
+        .filter(|path| !current.contains(path.as_path()))
+        .collect();
+    dropped.sort();
+    println!(
+        "{} files planned for deletion, {} new and {} no longer planned since the previous run",
+        operations.len(),
+        added.len(),
+        dropped.len()
+    );
+    for operation in added {
+        println!("+ {} ({} bytes)", operation.path.display(), operation.size);
+    }
+    for path in dropped {
+        println!("- {}", path.display());
+    }
+    let tmp_path = plan_file.with_extension("tmp");
+    let result = fs::write(&tmp_path, plan::to_json(base_dir, operations))
+        .and_then(|_| fs::rename(&tmp_path, plan_file));
+    if let Err(why) = result {
+        warn!(
+            "Could not save the plan to {}: {}",
+            plan_file.display(),
+            why
+        );
+    }
+}
+
+fn apply_plan(settings: &Cli, plan_file: &Path) {
+    let content = fs::read_to_string(plan_file).unwrap_or_else(|why| {
+        error!("Could not read the plan {}: {}", plan_file.display(), why);
+        process::exit(1);
+    });
+    let plan = plan::from_json(&content).unwrap_or_else(|why| {
+        error!("Not a valid plan {}: {}", plan_file.display(), why);
+        process::exit(1);
+    });
+    let base_dir = canonicalize_base_dir(&plan.directory);
+    info!(
+        "Applying plan for {} made at {}",
+        base_dir.display(),
+        humantime::format_rfc3339_seconds(plan.created)
+    );
+    let operations: Vec<Operation> = plan
+        .files
+        .iter()
+        .filter_map(|file| {
+            let operation = file.operation(&base_dir);
+            if operation.is_none() {
+                warn!(
+                    "File changed since the plan was made, not deleting: {}",
+                    file.path.display()
+                );
+            }
+            operation
+        })
+        .collect();
+    let outcome = execute(settings, &base_dir, operations.into_iter());
+    if !outcome.failed.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn canonicalize_base_dir(path: &Path) -> PathBuf {
+    root::canonicalize(path).expect("Directory path is not a proper path.")
+}
+
+/// Where a file we write or read would end up, without it having to exist
+fn resolve_file(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            parent
+                .canonicalize()
+                .map_or_else(|_| path.to_path_buf(), |p| p.join(name))
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// What is wrong with the combination of settings, before anything is scanned
+fn check_settings(settings: &Cli, base_directories: &[PathBuf]) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let (Some(smaller), Some(larger)) = (settings.skip_smaller_than, settings.skip_larger_than) {
+        if smaller > larger {
+            problems.push(format!(
+                "--skip-smaller-than {} is above --skip-larger-than {}, so no file may be deleted. Swap them?",
+                smaller, larger
+            ));
+        }
+    }
+    if let (Some(settle), Some(max_age)) = (settings.settle, settings.max_age) {
+        if settle > max_age {
+            problems.push(format!(
+                "--settle {} is above --max-age {}, so files are kept until they are {} old all the same. Lower --settle, or raise --max-age to match?",
+                humantime::format_duration(settle),
+                humantime::format_duration(max_age),
+                humantime::format_duration(settle)
+            ));
+        }
+    }
+    if let (Some(wait), Some(timeout)) = (settings.lease_wait, settings.timeout) {
+        if wait >= timeout {
+            problems.push(format!(
+                "--lease-wait {} is not below --timeout {}, so the run may time out before it gets the lease. Wait for less than the timeout.",
+                humantime::format_duration(wait),
+                humantime::format_duration(timeout)
+            ));
+        }
+    }
+    let patterns = iter::empty()
+        .chain(settings.include_only.iter().map(|p| ("--include-only", p)))
+        .chain(settings.exclude.iter().map(|p| ("--exclude", p)))
+        .chain(
+            settings
+                .select_for_op
+                .iter()
+                .map(|p| ("--select-for-op", p)),
+        )
+        .chain(
+            settings
+                .protect_from_op
+                .iter()
+                .map(|p| ("--protect-from-op", p)),
+        )
+        .chain(
+            settings
+                .rename_rotate
+                .iter()
+                .map(|p| ("--rename-rotate", p)),
+        )
+        .chain(settings.reserve.iter().map(|r| ("--reserve", &r.pattern)))
+        .chain(settings.weight.iter().map(|w| ("--weight", &w.pattern)));
+    for (option, pattern) in patterns {
+        if let Err(why) = try_glob_matcher(&base_directories[0], pattern) {
+            problems.push(format!(
+                "{} {} is not a valid glob pattern: {}. Put [ ] around a literal *, ? or [.",
+                option, pattern, why
+            ));
+        }
+    }
+    // Our own files are fair game for rotation if they're in the tree, unless hidden
+    let own_files = [
+        ("--state-file", &settings.state_file),
+        ("--grace-file", &settings.grace_file),
+        ("--diff", &settings.diff),
+        ("--audit-log", &settings.audit_log),
+        ("--history-file", &settings.history_file),
+        ("--protect-from-list", &settings.protect_from_list),
+        ("--save-scan", &settings.save_scan),
+        ("--report-html", &settings.report_html),
+    ];
+    let rotated_dir_of = |path: &Path| {
+        base_directories
+            .iter()
+            .find(|dir| match path.strip_prefix(dir) {
+                Ok(relative) => !relative.iter().any(|c| is_hidden(Path::new(c))),
+                Err(_) => false,
+            })
+    };
+    for (option, path) in own_files {
+        let path = match path {
+            Some(path) => resolve_file(path),
+            None => continue,
+        };
+        if let Some(dir) = rotated_dir_of(&path) {
+            problems.push(format!(
+                "{} {} is inside {}, where rotation may delete it. Move it out, or give it a name that starts with a dot.",
+                option,
+                path.display(),
+                dir.display()
+            ));
+        }
+    }
+    // Before any walk, rather than when the first file is uploaded
+    if let Err(problem) = upload_backend(settings, None) {
+        problems.push(problem);
+    }
+    if settings.archive_encrypt.is_some()
+        && settings.upload_to.is_none()
+        && settings.offload_ssh.is_none()
+    {
+        problems.push(String::from(
+            "--archive-encrypt only applies to --upload-to or --offload-ssh",
+        ));
+    }
+    // Uploaded files would count toward the size, and be uploaded and deleted in turn
+    let upload_dir = (settings.upload_to.as_deref())
+        .and_then(|url| (url.strip_prefix("file://")).or_else(|| url.strip_prefix("zip://")))
+        .map(|dir| resolve_file(Path::new(dir)));
+    if let Some(upload_dir) = upload_dir {
+        if let Some(dir) = rotated_dir_of(&upload_dir) {
+            problems.push(format!(
+                "--upload-to {} is inside {}, so rotation would take its own uploads for new files. Upload to a directory outside, or one whose name starts with a dot.",
+                upload_dir.display(),
+                dir.display()
+            ));
+        }
+    }
+    if settings.from_quota {
+        for dir in base_directories {
+            match project_quota(dir) {
+                Err(why) => problems.push(format!(
+                    "--from-quota could not read the project quota of {}: {}. Is it in a project on XFS or ext4, and are you root?",
+                    dir.display(),
+                    why
+                )),
+                Ok(quota) if quota.limit.is_none() && settings.size_limit.is_none() => {
+                    problems.push(format!(
+                        "--from-quota takes the limit from the project quota of {}, but it has none. Set a limit on the quota, or give a size.",
+                        dir.display()
+                    ))
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+    // Trashed files would still count toward the size, and be trashed again
+    if let (true, Ok(trash)) = (settings.trash, Trash::open()) {
+        let inside = (trash.dirs().into_iter())
+            .find_map(|trash_dir| rotated_dir_of(trash_dir).map(|dir| (trash_dir, dir)));
+        if let Some((trash_dir, dir)) = inside {
+            problems.push(format!(
+                "--trash moves files to {}, which is inside {}, so they would count toward its size and be trashed again. Rotate a directory that doesn't hold the trash, or delete without --trash.",
+                trash_dir.display(),
+                dir.display()
+            ));
+        }
+    }
+    problems
+}
+
+/// Check the settings in an argument file, reporting what is wrong by line. The directories
+/// are looked up, but nothing in them is read or changed.
+fn check_config(file: &Path) -> bool {
+    let arguments = match argfile::read(file) {
+        Ok(arguments) => arguments,
+        Err(why) => {
+            error!("Could not read {}: {}", file.display(), why);
+            return false;
+        }
+    };
+    let report = |line: Option<usize>, problem: &str| match line {
+        Some(line) => error!("{}:{}: {}", file.display(), line, problem),
+        None => error!("{}: {}", file.display(), problem),
+    };
+    let args = iter::once("dirrotate").chain(arguments.iter().map(|a| a.value.as_str()));
+    let settings = match Cli::try_parse_from(args) {
+        Ok(settings) => settings,
+        Err(why) => {
+            // clap quotes the argument or value it stumbled over
+            let message = why.to_string();
+            let quoted = |value: &str| {
+                message.contains(&format!("'{}'", value))
+                    || message.contains(&format!("\"{}\"", value))
+            };
+            let line = (arguments.iter())
+                .find(|a| quoted(&a.value))
+                .map(|a| a.line);
+            let first = message.lines().next().unwrap_or_default();
+            report(line, first.trim_start_matches("error: "));
+            return false;
+        }
+    };
+    if settings.command.is_some() {
+        report(None, "Holds a subcommand rather than the settings of a run");
+        return false;
+    }
+    if let Some(root) = &settings.root {
+        match root.canonicalize() {
+            Ok(root) => root::set(root),
+            Err(why) => {
+                let problem = format!("Could not find the root {}: {}", root.display(), why);
+                report(argfile::line_of(&arguments, "--root"), &problem);
+                return false;
+            }
+        }
+    }
+    if settings.match_relative {
+        matching::set_mode(MatchMode::Relative);
+    }
+    let directories = settings.directory.iter().chain(&settings.also);
+    let mut base_directories = Vec::new();
+    for directory in directories {
+        let line = (arguments.iter())
+            .find(|a| {
+                Path::new(&a.value) == directory
+                    || a.value.ends_with(&format!("={}", directory.display()))
+            })
+            .map(|a| a.line);
+        match root::canonicalize(directory) {
+            Ok(dir) if dir.is_dir() => base_directories.push(dir),
+            Ok(dir) => report(line, &format!("{} is not a directory", dir.display())),
+            Err(why) => report(
+                line,
+                &format!("Could not find {}: {}", directory.display(), why),
+            ),
+        }
+    }
+    if base_directories.len() < settings.also.len() + 1 {
+        return false;
+    }
+    let problems = check_settings(&settings, &base_directories);
+    for problem in &problems {
+        // Problems start with the option they are about
+        let option = problem
+            .split(' ')
+            .next()
+            .filter(|word| word.starts_with("--"));
+        report(
+            option.and_then(|option| argfile::line_of(&arguments, option)),
+            problem,
+        );
+    }
+    problems.is_empty()
+}
+
+/// What a --sandbox'ed run may change files below
+fn sandbox_paths(settings: &Cli, base_directories: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = base_directories.to_vec();
+    // Written to a temporary file next to them, or rotated with numbered generations
+    let own_files = [
+        &settings.state_file,
+        &settings.grace_file,
+        &settings.diff,
+        &settings.audit_log,
+        &settings.history_file,
+        &settings.save_scan,
+        &settings.report_html,
+    ];
+    paths.extend(
+        (own_files.iter())
+            .filter_map(|path| path.as_deref())
+            .filter_map(|path| resolve_file(path).parent().map(Path::to_path_buf)),
+    );
+    if let Some(url) = &settings.upload_to {
+        if let Some(dir) = url.strip_prefix("file://") {
+            paths.push(PathBuf::from(dir));
+        }
+        if let Some(archive) = url.strip_prefix("zip://") {
+            paths.extend(Path::new(archive).parent().map(Path::to_path_buf));
+        }
+    }
+    if settings.trash {
+        let trash = Trash::open().map_err(|why| {
+            io::Error::new(why.kind(), format!("Could not open the trash: {}", why))
+        })?;
+        paths.extend(trash.dirs().into_iter().map(Path::to_path_buf));
+    }
+    // Spilled candidates and encrypted uploads are written there
+    if settings.max_memory.is_some() || settings.archive_encrypt.is_some() {
+        paths.push(std::env::temp_dir());
+    }
+    // Where commands send their output when it isn't needed
+    paths.push(PathBuf::from("/dev/null"));
+    paths.extend(settings.sandbox_allow.iter().cloned());
+    Ok(paths)
+}
+
+fn main() {
+    // Setup
+    let args = argfile::expand(std::env::args_os()).unwrap_or_else(|why| {
+        eprintln!("Could not read the arguments from a @FILE: {}", why);
+        process::exit(2);
+    });
+    let mut settings = Cli::parse_from(args);
+    env_logger::Builder::new()
+        .filter_level(settings.verbose.log_level_filter())
+        .init();
+
+    if let Some(limit) = &settings.cgroup_limit {
+        // Before --chroot, which leaves /sys behind
+        let directory = settings
+            .directory
+            .as_ref()
+            .map(|directory| match &settings.root {
+                Some(root) => root.join(directory.strip_prefix("/").unwrap_or(directory)),
+                None => directory.clone(),
+            });
+        match cgroup::enter(limit, directory.as_deref()) {
+            Ok(group) => debug!("Running in cgroup {}", group.display()),
+            Err(why) => {
+                error!("Could not limit the run with a cgroup: {}", why);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(root) = &settings.root {
+        let root = root.canonicalize().unwrap_or_else(|why| {
+            error!("Could not find the root {}: {}", root.display(), why);
+            process::exit(1);
+        });
+        match settings.chroot {
+            true => {
+                if let Err(why) = root::enter(&root) {
+                    error!("Could not chroot into {}: {}", root.display(), why);
+                    process::exit(1);
+                }
+            }
+            false => root::set(root),
+        }
+    }
+
+    if let Some(user) = &settings.run_as {
+        if let Err(why) = privileges::run_as(user) {
+            error!("Could not switch to user {}: {}", user, why);
+            process::exit(1);
+        }
+        debug!("Running as user {}", user);
+    }
+
+    if let Some(catalog) = &settings.messages {
+        if let Err(why) = messages::load(catalog) {
+            error!(
+                "Could not load messages from {}: {}",
+                catalog.display(),
+                why
+            );
+            process::exit(1);
+        }
+    }
+
+    // Parse settings
+    match &settings.command {
+        Some(Command::History {
+            history_file,
+            directory,
+            last,
+        }) => {
+            let directory = directory.as_deref().map(canonicalize_base_dir);
+            print_history(&load_history(history_file), directory.as_deref(), *last);
+            return;
+        }
+        Some(Command::Forecast {
+            history_file,
+            directory,
+        }) => {
+            let directory = directory.as_deref().map(canonicalize_base_dir);
+            print_forecast(&load_history(history_file), directory.as_deref());
+            return;
+        }
+        Some(Command::Simulate {
+            history_file,
+            directory,
+            max_size,
+            max_age,
+        }) => {
+            let directory = directory.as_deref().map(canonicalize_base_dir);
+            print_simulation(
+                &load_history(history_file),
+                directory.as_deref(),
+                *max_size,
+                *max_age,
+            );
+            return;
+        }
+        Some(Command::UndoLast { directory, dryrun }) => {
+            if !undo::undo_last(&canonicalize_base_dir(directory), *dryrun) {
+                process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Messages) => {
+            messages::print_catalog();
+            return;
+        }
+        Some(Command::CheckConfig { file }) => {
+            if !check_config(file) {
+                process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Preflight {
+            directory,
+            skip_time_machine_excluded,
+        }) => {
+            let skip = *skip_time_machine_excluded;
+            if !preflight(&canonicalize_base_dir(directory), |p| is_skipped(p, skip)) {
+                process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Hold { action }) => {
+            let result = match action {
+                HoldAction::Add { path, reason } => hold::add(path, reason.as_deref()),
+                HoldAction::Remove { path } => hold::remove(path),
+            };
+            if let Err(why) = result {
+                error!("{}", why);
+                process::exit(1);
+            }
+            return;
+        }
+        Some(Command::MatchTest {
+            directory,
+            sample,
+            include_only,
+            exclude,
+            select_for_op,
+            protect_from_op,
+            match_relative,
+            no_default_protections,
+        }) => {
+            if *match_relative {
+                matching::set_mode(MatchMode::Relative);
+            }
+            let base_directory = canonicalize_base_dir(directory);
+            let patterns = Patterns {
+                include_only,
+                exclude: exclude.as_deref(),
+                select_for_op,
+                protect_from_op: protect_from_op.as_deref(),
+            };
+            let paths: Box<dyn Iterator<Item = PathBuf>> = match sample {
+                Some(n) => Box::new(
+                    list_all_files(&base_directory, settings.skip_time_machine_excluded)
+                        .take(*n)
+                        .map(|x| x.path),
+                ),
+                None => Box::new(
+                    (io::stdin().lock().lines())
+                        .map_while(Result::ok)
+                        .filter(|line| !line.trim().is_empty())
+                        .map(PathBuf::from),
+                ),
+            };
+            for path in paths {
+                let default_protected = !*no_default_protections
+                    && is_default_protected(&base_directory, &base_directory.join(&path));
+                print_matches(&base_directory, &patterns, &path, default_protected);
+            }
+            return;
+        }
+        Some(Command::Apply { plan_file, dryrun }) => {
+            let plan_file = plan_file.clone();
+            settings.dryrun = *dryrun;
+            apply_plan(&settings, &plan_file);
+            return;
+        }
+        Some(Command::Top {
+            directory,
+            n,
+            include_only,
+            exclude,
+        }) => {
+            let base_directory = canonicalize_base_dir(directory);
+            let include_only_matcher = get_path_matcher(&base_directory, include_only);
+            let exclude_matcher = get_path_matcher(&base_directory, exclude.as_slice());
+            let files: Vec<(PathBuf, u64, SystemTime)> = file_filter(
+                list_all_files(&base_directory, false),
+                &include_only_matcher,
+                &exclude_matcher,
+            )
+            .map(|x| (x.path, x.len, x.modified))
+            .collect();
+            print_top(&base_directory, &files, *n);
+            return;
+        }
+        Some(Command::Whatif {
+            scan,
+            max_size,
+            max_age,
+            include_only,
+            exclude,
+        }) => {
+            let scan = scanfile::load(scan).unwrap_or_else(|why| {
+                error!("Could not load a scan from {}: {}", scan.display(), why);
+                process::exit(1);
+            });
+            clock::freeze(settings.now.unwrap_or(scan.scanned_at));
+            let include_only_matcher = get_path_matcher(&scan.root, include_only);
+            let exclude_matcher = get_path_matcher(&scan.root, exclude.as_slice());
+            // The files need not exist anymore, or on this machine
+            let files: Vec<(u64, SystemTime)> = (scan.files.iter())
+                .filter(|x| {
+                    let canonical = canonical_path(&x.path);
+                    matches_patterns(&canonical, &include_only_matcher, &exclude_matcher)
+                })
+                .map(|x| (x.len, x.modified))
+                .collect();
+            let sizes: Vec<Option<u64>> = match max_size.is_empty() {
+                true => vec![None],
+                false => max_size.iter().copied().map(Some).collect(),
+            };
+            let ages: Vec<Option<Duration>> = match max_age.is_empty() {
+                true => vec![None],
+                false => max_age.iter().copied().map(Some).collect(),
+            };
+            let policies: Vec<Policy> = (sizes.iter())
+                .flat_map(|&max_size| {
+                    ages.iter()
+                        .map(move |&max_age| Policy { max_size, max_age })
+                })
+                .collect();
+            print_whatif(&files, &policies);
+            return;
+        }
+        None => {}
+    }
+
+    if let (Some(jitter), false) = (settings.jitter, settings.dryrun) {
+        let delay = random_delay(jitter);
+        debug!("Waiting {:?} before starting (--jitter)", delay);
+        thread::sleep(delay);
+    }
+    if let Some(timeout) = settings.timeout {
+        deadline::set(timeout);
+    }
+    scanerrors::set(settings.on_scan_error);
+    if let Some(now) = settings.now {
+        clock::freeze(now);
+    }
+    if settings.match_relative {
+        matching::set_mode(MatchMode::Relative);
+    }
+    if settings.io_backend == IoBackend::Uring {
+        uring::enable();
+    }
+    if settings.dir_reader == DirReader::Getdents {
+        getdents::enable();
+    }
+    if settings.skip_time_machine_excluded && !cfg!(target_os = "macos") {
+        warn!("--skip-time-machine-excluded only has an effect on macOS");
+    }
+
+    let base_directory =
+        canonicalize_base_dir(settings.directory.as_ref().expect("No directory given"));
+    let base_directories: Vec<PathBuf> = std::iter::once(base_directory.clone())
+        .chain(settings.also.iter().map(|dir| canonicalize_base_dir(dir)))
+        .collect();
+    settings.journal_dir = Some(base_directory.clone());
+    let problems = check_settings(&settings, &base_directories);
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        process::exit(1);
+    }
+    for (i, dir) in base_directories.iter().enumerate() {
+        info!("Culling directory: {}", dir.display());
+        // Nested directories would count their files twice
+        if let Some((_, outer)) = (base_directories.iter().enumerate())
+            .find(|(j, other)| *j != i && dir.starts_with(other))
+        {
+            error!("{} is inside {}", dir.display(), outer.display());
+            process::exit(1);
+        }
+        if settings.yes_i_mean_it {
+            continue;
+        }
+        if let Err(reason) = check_base_dir(dir) {
+            error!(
+                "Refusing to rotate {}: {}. Pass --yes-i-mean-it to do it anyway.",
+                dir.display(),
+                reason
+            );
+            notify(&settings, &Summary::aborted(dir, reason));
+            process::exit(1);
+        }
+    }
+
+    if settings.sandbox {
+        let paths = sandbox_paths(&settings, &base_directories).unwrap_or_else(|why| {
+            error!("Could not sandbox the run: {}", why);
+            process::exit(1);
+        });
+        if let Err(why) = sandbox::restrict(&paths) {
+            error!("Could not sandbox the run: {}", why);
+            process::exit(1);
+        }
+        for path in &paths {
+            debug!("Sandboxed to changing files below {}", path.display());
+        }
+    }
+
+    if let Some(scan_file) = &settings.load_scan {
+        let scan = scanfile::load(scan_file).unwrap_or_else(|why| {
+            error!(
+                "Could not load a scan from {}: {}",
+                scan_file.display(),
+                why
+            );
+            process::exit(1);
+        });
+        if scan.root != base_directory {
+            error!(
+                "{} is a scan of {}, not of {}",
+                scan_file.display(),
+                scan.root.display(),
+                base_directory.display()
+            );
+            process::exit(1);
+        }
+        if scan.skip_time_machine_excluded != settings.skip_time_machine_excluded {
+            warn!(
+                "The scan in {} was saved with --skip-time-machine-excluded {}",
+                scan_file.display(),
+                match scan.skip_time_machine_excluded {
+                    true => "on",
+                    false => "off",
+                }
+            );
+        }
+        if settings.now.is_none() {
+            clock::freeze(scan.scanned_at);
+        }
+        info!(
+            "Planning from the scan of {} files in {}",
+            scan.files.len(),
+            scan_file.display()
+        );
+        scanfile::install(scan);
+    } else if let Some(scan_file) = &settings.save_scan {
+        let scanned_at = clock::now();
+        let scan = SavedScan {
+            root: base_directory.clone(),
+            scanned_at,
+            skip_time_machine_excluded: settings.skip_time_machine_excluded,
+            files: list_all_files(&base_directory, settings.skip_time_machine_excluded).collect(),
+        };
+        scanfile::save(scan_file, &scan);
+        info!(
+            "Saved {} files to {}",
+            scan.files.len(),
+            scan_file.display()
+        );
+        // The run itself plans from the same files, without walking again
+        scanfile::install(scan);
+    }
+
+    if let (Some(audit_log), Some(max_size)) = (&settings.audit_log, settings.audit_log_max_size) {
+        let size = fs::metadata(audit_log).map_or(0, |m| m.len());
+        if size > max_size && !settings.dryrun {
+            rotate_file(audit_log, settings.audit_log_generations, false, false);
+        }
+    }
+
+    // With snapshots, deleted files stay on disk until the snapshots go
+    let snapshot_fs = snapshot_fs(&base_directory);
+    if let Some(fs) = snapshot_fs {
+        match has_snapshots(&base_directory, fs) {
+            Some(true) => warn!(
+                "{} is in {:?} snapshots: Deleting files will not free the space they take up in them",
+                base_directory.display(),
+                fs
+            ),
+            Some(false) => {}
+            None => info!(
+                "{} is on {:?}: If it is in snapshots, deleting files will not free all of their space",
+                base_directory.display(),
+                fs
+            ),
+        }
+    }
+    let free_before = snapshot_fs.and_then(|_| free_space(&base_directory));
+
+    settings.max_size = match settings.size_limit {
+        Some(SizeLimit::Bytes(size)) => Some(size),
+        Some(SizeLimit::OfCapacity(fraction)) => {
+            let capacity = capacity(&base_directory).unwrap_or_else(|| {
+                error!(
+                    "Could not get the capacity of the filesystem of {}",
+                    base_directory.display()
+                );
+                process::exit(1);
+            });
+            let size = (capacity as f64 * fraction) as u64;
+            info!("Size limit: {} of {} bytes", size, capacity);
+            Some(size)
+        }
+        None => None,
+    };
+    let quota_usage = settings.from_quota.then(|| {
+        let quota = project_quota(&base_directory).unwrap_or_else(|why| {
+            error!(
+                "Could not read the project quota of {}: {}",
+                base_directory.display(),
+                why
+            );
+            process::exit(1);
+        });
+        // check_settings made sure the quota has a limit if there's no size
+        if settings.max_size.is_none() {
+            settings.max_size = quota.limit;
+        }
+        quota.usage
+    });
+
+    if let (true, Some(max_age)) = (settings.date_layout, settings.max_age) {
+        datelayout::install(&base_directory, clock::now() - max_age);
+    }
+
+    let connections = activation::activated().unwrap_or_else(|why| {
+        error!(
+            "Could not take the connections that started the run: {}",
+            why
+        );
+        process::exit(1);
+    });
+    let leases = match (settings.lease_ttl, settings.dryrun) {
+        (Some(ttl), false) => match take_leases(&base_directories, ttl, settings.lease_wait) {
+            Some(leases) => leases,
+            None => {
+                warn!("Another run holds the lease, skipping this one");
+                return;
+            }
+        },
+        _ => Vec::new(),
+    };
+
+    let started = Instant::now();
+    let mut run = match quick_size_estimate(&settings, &base_directory, quota_usage) {
+        // Estimates are not the size of the directory, so they're not recorded as such
+        Some((source, size)) => {
+            info!("Size from {}: {}", source, size);
+            Run::default()
+        }
+        None if base_directories.len() > 1 => rotate_pooled(&settings, &base_directories),
+        None => rotate(&settings, &base_directory),
+    };
+    run.duration = started.elapsed();
+    if let (Some(before), Some(after)) = (free_before, free_space(&base_directory)) {
+        let gained = after.saturating_sub(before);
+        info!("Free space grew by {} bytes", gained);
+        if !settings.dryrun && gained < run.freed() / 2 {
+            warn!(
+                "Deleted {} bytes, but free space only grew by {} bytes. Snapshots may still hold the files.",
+                run.freed(),
+                gained
+            );
+        }
+    }
+    for lease in leases {
+        lease.release();
+    }
+    report(&settings, &base_directory, &run);
+    connections.reply(&run.summary(&base_directory).text());
+    if run
+        .outcome
+        .as_ref()
+        .is_some_and(|o| !o.timed_out.is_empty())
+    {
+        process::exit(EXIT_TIMED_OUT);
+    }
+    if run.shortfall() > 0 {
+        process::exit(EXIT_TARGET_NOT_REACHED);
+    }
+    if settings.partial_on_scan_error && scanerrors::count() > 0 {
+        process::exit(EXIT_PARTIAL);
+    }
+}
+
+fn random_delay(max: Duration) -> Duration {
+    // Hashers are seeded at random for each process, which is random enough to spread runs
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+fn take_leases(base_dirs: &[PathBuf], ttl: Duration, wait: Option<Duration>) -> Option<Vec<Lease>> {
+    // The leases of all the directories, or none of them
+    let mut leases = Vec::new();
+    for base_dir in base_dirs {
+        match Lease::acquire(base_dir, ttl, wait.unwrap_or_default()) {
+            Ok(Some(lease)) => leases.push(lease),
+            Ok(None) => {
+                for lease in leases {
+                    lease.release();
+                }
+                return None;
+            }
+            Err(why) => {
+                error!(
+                    "Could not take the lease of {}: {}",
+                    base_dir.display(),
+                    why
+                );
+                process::exit(1);
+            }
+        }
+    }
+    Some(leases)
+}
+
+/// An estimate of the size that shows the directory to be under the limit without walking
+/// it, and where it comes from
+fn quick_size_estimate(
+    settings: &Cli,
+    base_dir: &Path,
+    quota_usage: Option<u64>,
+) -> Option<(&'static str, u64)> {
+    let max_size = settings.max_size?;
+    // The quota and filesystem usage are of now, not of the time of a loaded scan
+    if settings.max_age.is_some()
+        || settings.rename_rotate.is_some()
+        || scanfile::loaded().is_some()
+    {
+        return None;
+    }
+    // The quota covers every file of the directory, so it's an upper bound
+    if let Some(usage) = quota_usage.filter(|&usage| usage <= max_size) {
+        return Some(("project quota", usage));
+    }
+    let margin = settings.quick_check?;
+    let threshold = (max_size as f64 * (1.0 - margin)) as u64;
+    let saved_size = || {
+        let state_file = settings.state_file.as_ref()?;
+        let state = SizeState::load(state_file, &state_fingerprint(settings));
+        Some(state.total()).filter(|_| !state.is_empty())
+    };
+    if let Some(used) = used_space(base_dir).filter(|&used| used <= threshold) {
+        return Some(("filesystem usage", used));
+    }
+    saved_size()
+        .filter(|&size| size <= threshold)
+        .map(|size| ("the last run", size))
+}
+
+/// Exit code for a run that could not get the directory under the limit
+const EXIT_TARGET_NOT_REACHED: i32 = 3;
+/// Exit code for a run that ran out of time (--timeout)
+const EXIT_TIMED_OUT: i32 = 4;
+/// Exit code for a run that could not read all of the directory (--partial-on-scan-error)
+const EXIT_PARTIAL: i32 = 5;
+
+/// What a run found and did
+#[derive(Default)]
+struct Run {
+    /// Size of the (included) files before deleting anything, if it was measured
+    size_before: Option<u64>,
+    size_to_free: u64,
+    /// Size of the files that may not be deleted, if it was measured
+    retained: Option<u64>,
+    /// Files (and bytes) that may be deleted
+    candidates: (usize, u64),
+    /// Why the files that may not be deleted are kept, as (constraint, files, bytes)
+    blocked_by_filters: Vec<(&'static str, usize, u64)>,
+    /// Nothing if there was nothing to do, or the plan was abandoned
+    outcome: Option<Outcome>,
+    /// The oldest and newest files left, if the files were listed
+    retention: Option<Retention>,
+    /// Size of the counted files before the run by subdirectory, if the files were listed
+    /// for --report-html
+    usage: Option<HashMap<String, u64>>,
+    duration: Duration,
+}
+
+impl Run {
+    fn freed(&self) -> u64 {
+        self.outcome
+            .iter()
+            .flat_map(|outcome| &outcome.deleted)
+            .map(|op| op.size)
+            .sum()
+    }
+
+    /// Bytes still to free after the run (or after the planned run, in a dry-run)
+    fn shortfall(&self) -> u64 {
+        let planned: u64 = self
+            .outcome
+            .iter()
+            .flat_map(|outcome| &outcome.planned)
+            .map(|op| op.size)
+            .sum();
+        self.size_to_free
+            .saturating_sub(self.freed())
+            .saturating_sub(planned)
+    }
+
+    /// What kept the run from freeing enough, as (constraint, files, bytes)
+    fn blocked(&self) -> Vec<(&'static str, usize, u64)> {
+        let mut blocked = self.blocked_by_filters.clone();
+        let count = |operations: &mut dyn Iterator<Item = &Operation>| {
+            operations.fold((0, 0), |(n, size), op| (n + 1, size + op.size))
+        };
+        if let Some(outcome) = &self.outcome {
+            let (files, bytes) = count(&mut outcome.failed.iter().map(|x| &x.0));
+            blocked.push(("deletion failed", files, bytes));
+            let (files, bytes) = count(&mut outcome.changed.iter());
+            blocked.push(("changed during the run", files, bytes));
+            let (files, bytes) = count(&mut outcome.restored.iter());
+            blocked.push(("restored, as their group could not go whole", files, bytes));
+            let (files, bytes) = count(&mut outcome.timed_out.iter());
+            blocked.push(("not started before --timeout", files, bytes));
+            let (files, bytes) = count(
+                &mut (outcome.deleted.iter())
+                    .chain(&outcome.changed)
+                    .chain(&outcome.restored)
+                    .chain(&outcome.timed_out)
+                    .chain(&outcome.planned)
+                    .chain(outcome.failed.iter().map(|x| &x.0)),
+            );
+            blocked.push((
+                "left out of the plan (--reserve, grace period, review)",
+                self.candidates.0.saturating_sub(files),
+                self.candidates.1.saturating_sub(bytes),
+            ));
+        }
+        blocked.retain(|x| x.1 > 0);
+        blocked
+    }
+
+    /// The run as a JSON object, for status reporting
+    fn status(&self, settings: &Cli, base_dir: &Path) -> String {
+        let freed = self.freed();
+        let mut status = json::Object::new()
+            .string("directory", &base_dir.to_string_lossy())
+            .string(
+                "last_run",
+                &humantime::format_rfc3339_seconds(clock::now()).to_string(),
+            );
+        if let Some(size_before) = self.size_before {
+            status = status.number("usage", size_before.saturating_sub(freed));
+        }
+        if let Some(max_size) = settings.max_size {
+            status = status.number("limit", max_size);
+        }
+        let summary = self.summary(base_dir);
+        status
+            .number("deleted", summary.deleted as u64)
+            .number("freed", freed)
+            .number("failed", summary.failed as u64)
+            .number("shortfall", summary.shortfall)
+            .number(
+                "partial_groups",
+                self.outcome
+                    .as_ref()
+                    .map_or(0, |outcome| outcome.partial_groups.len()) as u64,
+            )
+            .finish()
+    }
+
+    fn metrics(&self) -> Vec<Metric> {
+        let summary = self.summary(Path::new(""));
+        let now = clock::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut metrics = vec![
+            Metric {
+                name: "dirrotate_last_run_timestamp_seconds",
+                help: "When the last run finished",
+                value: now.as_secs_f64(),
+            },
+            Metric {
+                name: "dirrotate_run_duration_seconds",
+                help: "How long the last run took",
+                value: self.duration.as_secs_f64(),
+            },
+            Metric {
+                name: "dirrotate_deleted_files",
+                help: "Files deleted by the last run",
+                value: summary.deleted as f64,
+            },
+            Metric {
+                name: "dirrotate_freed_bytes",
+                help: "Bytes freed by the last run",
+                value: summary.freed as f64,
+            },
+            Metric {
+                name: "dirrotate_errors",
+                help: "Files the last run failed to delete",
+                value: summary.failed as f64,
+            },
+            Metric {
+                name: "dirrotate_shortfall_bytes",
+                help: "Bytes still over the limit after the last run",
+                value: summary.shortfall as f64,
+            },
+            Metric {
+                name: "dirrotate_scan_errors",
+                help: "Entries the last run could not read while scanning",
+                value: summary.scan_errors as f64,
+            },
+        ];
+        if let Some(retention) = self.retention {
+            metrics.push(Metric {
+                name: "dirrotate_oldest_retained_age_seconds",
+                help: "Age of the oldest file left after the last run",
+                value: retention.age().as_secs_f64(),
+            });
+            metrics.push(Metric {
+                name: "dirrotate_retained_span_seconds",
+                help: "Time from the oldest to the newest file left after the last run",
+                value: retention.span().as_secs_f64(),
+            });
+        }
+        if let Some(size_before) = self.size_before {
+            metrics.push(Metric {
+                name: "dirrotate_usage_bytes",
+                help: "Size of the directory after the last run",
+                value: size_before.saturating_sub(summary.freed) as f64,
+            });
+        }
+        metrics
+    }
+
+    fn summary(&self, base_dir: &Path) -> Summary {
+        let freed = self.freed();
+        Summary {
+            directory: base_dir.to_path_buf(),
+            deleted: self.outcome.as_ref().map_or(0, |o| o.deleted.len()),
+            freed,
+            failed: self.outcome.as_ref().map_or(0, |o| o.failed.len()),
+            shortfall: self.shortfall(),
+            scan_errors: scanerrors::count(),
+            retention: self.retention.map(|r| (r.age(), r.span())),
+            error: None,
+        }
+    }
+}
+
+/// The history for a subcommand that only reads it, exiting if it can't be read
+fn load_history(history_file: &Path) -> Vec<history::Record> {
+    history::load(history_file).unwrap_or_else(|why| {
+        error!(
+            "Could not read the history from {}: {}",
+            history_file.display(),
+            why
+        );
+        process::exit(1);
+    })
+}
+
+fn warn_forecast(settings: &Cli, base_dir: &Path, history_file: &Path) {
+    let records = match history::load(history_file) {
+        Ok(records) => records,
+        Err(why) => {
+            warn!(
+                "Could not read the history from {}: {}",
+                history_file.display(),
+                why
+            );
+            return;
+        }
+    };
+    let runs: Vec<&history::Record> = records.iter().filter(|r| r.directory == base_dir).collect();
+    match forecast(&runs) {
+        Forecast::OverBudget => warn!(
+            "{} stays over the limit even after deleting all that may be deleted",
+            base_dir.display()
+        ),
+        Forecast::Exceeds(time) if time < clock::now() + settings.forecast_horizon => warn!(
+            "At the current intake, {} will be over the limit around {}, even after deleting all that may be deleted",
+            base_dir.display(),
+            humantime::format_rfc3339_seconds(time)
+        ),
+        _ => {}
+    }
+}
+
+/// The subdirectory of the rotated directory that the report counts the file toward, or .
+/// for the files directly in it
+fn subdirectory(base_dir: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(base_dir).unwrap_or(path);
+    match relative.components().count() {
+        1 => String::from("."),
+        _ => relative
+            .iter()
+            .next()
+            .map_or_else(String::new, |c| c.to_string_lossy().into_owned()),
+    }
+}
+
+fn write_html_report(settings: &Cli, base_dir: &Path, run: &Run, report_file: &Path) {
+    let mut policy: Vec<(&str, String)> = vec![("dry-run", settings.dryrun.to_string())];
+    policy.extend(settings.max_size.map(|s| ("max size", s.to_string())));
+    policy
+        .extend((settings.max_age).map(|a| ("max age", humantime::format_duration(a).to_string())));
+    policy.extend(
+        settings
+            .include_only
+            .iter()
+            .map(|p| ("include only", p.clone())),
+    );
+    policy.extend(settings.exclude.iter().map(|p| ("exclude", p.clone())));
+    policy.extend(
+        settings
+            .select_for_op
+            .iter()
+            .map(|p| ("select for op", p.clone())),
+    );
+    policy.extend(
+        settings
+            .protect_from_op
+            .iter()
+            .map(|p| ("protect from op", p.clone())),
+    );
+    if settings.group {
+        policy.push(("group", String::from("true")));
+    }
+
+    // Usage of the counted files after the run: what a dry-run would delete counts as deleted
+    let outcome = run.outcome.as_ref();
+    let mut usage: HashMap<String, (u64, u64)> = HashMap::new();
+    match &run.usage {
+        // What was counted before the run, less what it deleted
+        Some(sizes) => {
+            for (name, size) in sizes {
+                usage.insert(name.clone(), (*size, 0));
+            }
+            for operation in outcome
+                .iter()
+                .flat_map(|o| o.deleted.iter().chain(&o.planned))
+            {
+                let kept = &mut usage
+                    .entry(subdirectory(base_dir, &operation.path))
+                    .or_default()
+                    .0;
+                *kept = kept.saturating_sub(operation.size);
+            }
+        }
+        // The files weren't listed, e.g. as the size came from the saved state
+        None => {
+            let include_only_matcher = get_path_matcher(base_dir, &settings.include_only);
+            let exclude_matcher = get_path_matcher(base_dir, settings.exclude.as_slice());
+            let removed: HashSet<&Path> = outcome
+                .map(|o| {
+                    o.deleted
+                        .iter()
+                        .chain(&o.planned)
+                        .map(|x| x.path.as_path())
+                        .collect()
+                })
+                .unwrap_or_default();
+            for file in file_filter(
+                list_all_files(base_dir, settings.skip_time_machine_excluded),
+                &include_only_matcher,
+                &exclude_matcher,
+            ) {
+                if !removed.contains(file.path.as_path()) {
+                    usage
+                        .entry(subdirectory(base_dir, &file.path))
+                        .or_default()
+                        .0 += file.len;
+                }
+            }
+        }
+    }
+    for operation in outcome
+        .iter()
+        .flat_map(|o| o.deleted.iter().chain(&o.planned))
+    {
+        usage
+            .entry(subdirectory(base_dir, &operation.path))
+            .or_default()
+            .1 += operation.size;
+    }
+    let mut usage: Vec<(String, u64, u64)> = (usage.into_iter())
+        .map(|(name, (kept, deleted))| (name, kept, deleted))
+        .collect();
+    usage.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then_with(|| a.0.cmp(&b.0)));
+
+    let html = html::render(base_dir, clock::now(), &policy, &usage, outcome);
+    if let Err(why) = fs::write(report_file, html) {
+        warn!(
+            "Could not write the report to {}: {}",
+            report_file.display(),
+            why
+        );
+    }
+}
+
+fn report(settings: &Cli, base_dir: &Path, run: &Run) {
+    // Tell whoever wants to know how the run went
+    let scan_errors = scanerrors::count();
+    if scan_errors > 0 {
+        warn!(
+            "{}",
+            text("summary-scan-errors", &[("entries", &scan_errors)])
+        );
+    }
+    if let Some(retention) = run.retention {
+        info!(
+            "{}",
+            text(
+                "summary-retention",
+                &[
+                    ("age", &format_age(retention.age())),
+                    ("span", &format_age(retention.span()))
+                ]
+            )
+        );
+    }
+    let shortfall = run.shortfall();
+    if shortfall > 0 {
+        error!(
+            "Could not reach the target: {} bytes still over the limit after deleting every candidate",
+            shortfall
+        );
+        for (constraint, files, bytes) in run.blocked() {
+            error!("  {} bytes in {} files: {}", bytes, files, constraint);
+        }
+    }
+    if run.outcome.is_some() {
+        notify(settings, &run.summary(base_dir));
+    }
+    if let (OutputFormat::Csv, Some(outcome)) = (settings.output, &run.outcome) {
+        println!("{}", csv::outcome_to_csv(outcome));
+    }
+    if let Some(report_file) = &settings.report_html {
+        write_html_report(settings, base_dir, run, report_file);
+    }
+    if settings.dryrun {
+        return;
+    }
+    if let (Some(broker), Some(topic)) = (&settings.mqtt_broker, &settings.mqtt_topic) {
+        if let Err(why) = publish_mqtt(broker, topic, &run.status(settings, base_dir)) {
+            warn!("Could not publish status to {}: {}", broker, why);
+        }
+    }
+    if let Some(history_file) = &settings.history_file {
+        history::append(
+            history_file,
+            &history::Record {
+                time: clock::now(),
+                directory: base_dir.to_path_buf(),
+                size_before: run.size_before,
+                size_after: run.size_before.map(|size| size.saturating_sub(run.freed())),
+                limit: settings.max_size,
+                retained: run.retained,
+                deleted: run.outcome.as_ref().map_or(0, |o| o.deleted.len()),
+                freed: run.freed(),
+                failed: run.outcome.as_ref().map_or(0, |o| o.failed.len()),
+                duration: run.duration,
+            },
+        );
+        warn_forecast(settings, base_dir, history_file);
+    }
+    if let Some(url) = &settings.pushgateway {
+        if let Err(why) = push_metrics(url, base_dir, &run.metrics()) {
+            warn!("Could not push metrics to {}: {}", url, why);
+        }
+    }
+}
+
+fn check_fraction(settings: &Cli, base_dir: &Path, planned: usize, total: usize) {
+    // Exits if the plan deletes too much
+    if settings.yes_i_mean_it {
+        return;
+    }
+    if let Err(reason) = check_plan_fraction(planned, total, settings.max_delete_fraction) {
+        if settings.dryrun {
+            warn!("This plan will be refused: {}", reason);
+        } else {
+            error!(
+                "Refusing to execute: {}. Pass --yes-i-mean-it to do it anyway.",
+                reason
+            );
+            notify(settings, &Summary::aborted(base_dir, reason));
+            process::exit(1);
+        }
+    }
+}
+
+/// The deepest directory that all the directories are in
+fn common_ancestor(dirs: &[PathBuf]) -> PathBuf {
+    let mut ancestor = dirs[0].clone();
+    while !dirs.iter().all(|dir| dir.starts_with(&ancestor)) {
+        if !ancestor.pop() {
+            break;
+        }
+    }
+    ancestor
+}
+
+/// Like `rotate`, but for several directories under one limit. Their candidates are pooled
+/// and the oldest go first, whichever directory they are in.
+fn rotate_pooled(settings: &Cli, base_directories: &[PathBuf]) -> Run {
+    let (mut file_count, mut current_size): (usize, u64) = (0, 0);
+    let (mut deletable, mut kept): (Vec<FileRecord>, Vec<FileRecord>) = (Vec::new(), Vec::new());
+    let mut stuck = (0, 0);
+    let mut usage = None;
+    for (i, base_directory) in base_directories.iter().enumerate() {
+        let include_only_matcher = get_path_matcher(base_directory, &settings.include_only);
+        let exclude_matcher = get_path_matcher(base_directory, settings.exclude.as_slice());
+        let select_matcher = get_path_matcher(base_directory, &settings.select_for_op);
+        let protect_matcher = get_path_matcher(base_directory, settings.protect_from_op.as_slice());
+        let protected_paths = get_protected_paths(
+            base_directory,
+            &settings.protect_from_list,
+            &settings.protect_from_command,
+        );
+        // The report is of the first directory
+        let notes = ScanNotes::new(settings, base_directory, i == 0);
+        for file in scan(
+            settings,
+            base_directory,
+            list_all_files(base_directory, settings.skip_time_machine_excluded),
+            (&include_only_matcher, &exclude_matcher),
+            (&select_matcher, &protect_matcher),
+            &protected_paths,
+            &notes,
+        ) {
+            file_count += 1;
+            current_size += file.len;
+            add_stuck(&mut stuck, &file);
+            match file.kept_by {
+                None => deletable.push(file),
+                Some(_) => kept.push(file),
+            }
+        }
+        notes.unmatched.warn();
+        usage = usage.or_else(|| notes.usage.into_inner());
+    }
+    log_stuck(stuck);
+
+    let size_to_free = settings
+        .max_size
+        .map_or(0, |max_size| current_size.saturating_sub(max_size));
+    info!("Size to free (all directories): {}", size_to_free);
+    if size_to_free == 0 && settings.max_age.is_none() {
+        return Run {
+            size_before: Some(current_size),
+            retention: retention(kept.iter().chain(&deletable), None),
+            usage,
+            ..Run::default()
+        };
+    }
+    let candidate_size: u64 = deletable.iter().map(|x| x.len).sum();
+    let candidates = (deletable.len(), candidate_size);
+    deletable.sort_by_key(|x| policy_modified(x, settings.future_mtime));
+    deletable.reverse();
+    let first_expired = deletable.partition_point(|x| !is_expired(x, settings));
+    let expired = deletable.split_off(first_expired);
+    let size_freed_by_age: u64 = expired
+        .iter()
+        .map(|x| freeable_size(x, settings.cow_aware))
+        .sum();
+    let mut operations: Vec<Operation> = expired.iter().map(Operation::of).collect();
+    let common_base = common_ancestor(base_directories);
+    operations.extend(register_operations(
+        &mut deletable.iter().map(|x| vec![x]).collect(),
+        size_to_free.saturating_sub(size_freed_by_age),
+        &mut Reservations::new(&common_base, &[], std::iter::empty()),
+        settings.cow_aware,
+        &mut HashSet::new(),
+    ));
+
+    check_fraction(settings, &base_directories[0], operations.len(), file_count);
+    // The executor works relative to a directory that all of them are in
+    let outcome = finalize_and_execute(settings, &common_base, operations, Some(size_to_free));
+    let left = kept.iter().chain(&deletable).chain(&expired);
+    Run {
+        size_before: Some(current_size),
+        size_to_free,
+        retained: Some(current_size - candidate_size),
+        candidates,
+        retention: retention(left, outcome.as_ref()),
+        outcome,
+        usage,
+        ..Run::default()
+    }
+}
+
+/// What the scan of a directory notes for once it is done: which patterns match, and the
+/// size of each subdirectory for --report-html
+struct ScanNotes {
+    unmatched: UnmatchedPatterns,
+    base_dir: PathBuf,
+    /// Only if it is wanted, and from when the scan starts
+    usage: RefCell<Option<HashMap<String, u64>>>,
+    wants_usage: bool,
+}
+
+impl ScanNotes {
+    fn new(settings: &Cli, base_directory: &Path, report: bool) -> ScanNotes {
+        let patterns: Vec<(&str, String)> = (settings.include_only.iter())
+            .map(|p| ("--include-only", p.clone()))
+            .chain(settings.exclude.iter().map(|p| ("--exclude", p.clone())))
+            .chain(
+                settings
+                    .select_for_op
+                    .iter()
+                    .map(|p| ("--select-for-op", p.clone())),
+            )
+            .chain((settings.protect_from_op.iter()).map(|p| ("--protect-from-op", p.clone())))
+            .collect();
+        ScanNotes {
+            unmatched: UnmatchedPatterns::new(base_directory, patterns),
+            base_dir: base_directory.to_path_buf(),
+            usage: RefCell::new(None),
+            wants_usage: report && settings.report_html.is_some(),
+        }
+    }
+
+    fn scanning(&self) {
+        self.unmatched.scanning();
+        if self.wants_usage {
+            self.usage.replace(Some(HashMap::new()));
+        }
+    }
+
+    /// Count a file toward the size of its subdirectory
+    fn count(&self, file: &FileRecord) {
+        if let Some(usage) = self.usage.borrow_mut().as_mut() {
+            *usage
+                .entry(subdirectory(&self.base_dir, &file.path))
+                .or_default() += file.len;
+        }
+    }
+}
+
+fn rotate(settings: &Cli, base_directory: &Path) -> Run {
+    // The patterns that match nothing are only known once the scan is done
+    let notes = ScanNotes::new(settings, base_directory, true);
+    let mut run = rotate_scanning(settings, base_directory, &notes);
+    notes.unmatched.warn();
+    run.usage = notes.usage.into_inner();
+    run
+}
+
+fn rotate_scanning(settings: &Cli, base_directory: &Path, notes: &ScanNotes) -> Run {
+    // Canonicalize glob patterns
+    let include_only_matcher = get_path_matcher(base_directory, &settings.include_only);
+    let exclude_matcher = get_path_matcher(base_directory, settings.exclude.as_slice());
+    let select_matcher = get_path_matcher(base_directory, &settings.select_for_op);
+    let protect_matcher = get_path_matcher(base_directory, settings.protect_from_op.as_slice());
+    let mut protected_paths = get_protected_paths(
+        base_directory,
+        &settings.protect_from_list,
+        &settings.protect_from_command,
+    );
+    // What needs the files before the scan lists them once, and the scan then takes them over
+    let needs_listing = settings.keep_newest_per_pattern.is_some()
+        || settings.report_groups
+        || settings.rename_rotate.is_some();
+    let mut listing: Option<Vec<FileRecord>> = needs_listing
+        .then(|| list_all_files(base_directory, settings.skip_time_machine_excluded).collect());
+    if let Some(keep) = settings.keep_newest_per_pattern {
+        let patterns: Vec<String> = settings
+            .include_only
+            .iter()
+            .chain(&settings.select_for_op)
+            .cloned()
+            .collect();
+        let files = file_filter(
+            listing.iter().flatten(),
+            &include_only_matcher,
+            &exclude_matcher,
+        )
+        .map(|x| (x.path.clone(), x.modified));
+        protected_paths.extend(get_newest_per_pattern(
+            base_directory,
+            &patterns,
+            keep,
+            files,
+        ));
+    }
+
+    if settings.report_groups {
+        let files: Vec<(PathBuf, u64, SystemTime)> = file_filter(
+            listing.iter().flatten(),
+            &include_only_matcher,
+            &exclude_matcher,
+        )
+        .map(|x| (x.path.clone(), x.len, x.modified))
+        .collect();
+        print_group_report(base_directory, &files, &settings.require_member);
+    }
+
+    // Numbered rotation comes first, so that the limits apply to the result
+    if let Some(rename_rotate_matcher) =
+        get_path_matcher(base_directory, settings.rename_rotate.as_slice())
+    {
+        let live_files: Vec<PathBuf> = (listing.iter().flatten())
+            .filter(|x| x.len > 0 && !is_generation(&x.path))
+            .map(|x| x.path.clone())
+            .filter(|path| rename_rotate_matcher.matches(&canonical_path(path)))
+            .collect();
+        for path in &live_files {
+            rotate_file(
+                path,
+                settings.generations,
+                settings.compress,
+                settings.dryrun,
+            );
+        }
+        // The files were renamed, so the scan walks the tree anew
+        if !settings.dryrun && !live_files.is_empty() {
+            listing = None;
+        }
+        if settings.max_size.is_none()
+            && settings.max_age.is_none()
+            && settings.free_at_least.is_none()
+        {
+            return Run::default();
+        }
+    }
+
+    let listing = Cell::new(listing);
+    let listed = || -> Box<dyn Iterator<Item = FileRecord>> {
+        match listing.take() {
+            Some(files) => Box::new(files.into_iter()),
+            None => list_all_files(base_directory, settings.skip_time_machine_excluded),
+        }
+    };
+
+    // Age-only purge: Without a size limit, there is nothing to measure.
+    // Delete expired files as we find them instead (with --yes-i-mean-it, else once the
+    // scan is done, as the share of the files they are is only known then).
+    let max_size = if let Some(max_size) = settings.max_size {
+        max_size
+    } else if settings.free_at_least.is_some() {
+        0
+    } else {
+        let files = || {
+            scan(
+                settings,
+                base_directory,
+                listed(),
+                (&include_only_matcher, &exclude_matcher),
+                (&select_matcher, &protect_matcher),
+                &protected_paths,
+                notes,
+            )
+        };
+        // Files that fail to be deleted are not counted as left
+        let retained = Cell::new(None);
+        let total = Cell::new(0);
+        // Made when used, as the listing goes to the first scan
+        let expired = || {
+            let (retained, total) = (&retained, &total);
+            files()
+                .filter(move |x| {
+                    total.set(total.get() + 1);
+                    let expired = x.kept_by.is_none() && is_expired(x, settings);
+                    if !expired {
+                        retained.set(widen(retained.get(), x.modified));
+                    }
+                    expired
+                })
+                .map(|x| Operation::of(&x))
+        };
+        let outcome = if settings.group || settings.explain {
+            // Groups need all their members seen before any of them go
+            let files: Vec<FileRecord> = files().collect();
+            let candidates = files.iter().filter(|x| x.kept_by.is_none()).collect();
+            let (units, kept_groups) = deletion_units(settings, &files, candidates);
+            let expired: Vec<Operation> = units
+                .into_iter()
+                .filter(|unit| unit.iter().all(|x| is_expired(x, settings)))
+                .flatten()
+                .map(Operation::of)
+                .collect();
+            if settings.explain {
+                let plan = Plan {
+                    operations: &expired,
+                    expired: expired.len(),
+                    max_age: settings.max_age,
+                    size_to_free: 0,
+                    group: settings.group,
+                };
+                print_explanation(base_directory, &plan, &files, &kept_groups, &HashSet::new());
+            }
+            check_fraction(settings, base_directory, expired.len(), files.len());
+            let outcome = finalize_and_execute(settings, base_directory, expired, None);
+            retained.set(retention(&files, outcome.as_ref()));
+            outcome
+        } else if needs_whole_plan(settings) {
+            let expired: Vec<Operation> = expired().collect();
+            check_fraction(settings, base_directory, expired.len(), total.get());
+            finalize_and_execute(settings, base_directory, expired, None)
+        } else if !settings.yes_i_mean_it {
+            // Nothing goes before it's known how much of the tree that is
+            let expired: Vec<Operation> = expired().collect();
+            check_fraction(settings, base_directory, expired.len(), total.get());
+            Some(execute(settings, base_directory, expired.into_iter()))
+        } else {
+            Some(execute(settings, base_directory, expired()))
+        };
+        return Run {
+            outcome,
+            retention: retained.get(),
+            ..Run::default()
+        };
+    };
+
+    // With a saved state, the size can be checked without stat'ing every file
+    let state_fingerprint = state_fingerprint(settings);
+    let mut state = settings.state_file.as_ref().map(|state_file| {
+        let previous = SizeState::load(state_file, &state_fingerprint);
+        measure_size(
+            base_directory,
+            &previous,
+            settings.skip_time_machine_excluded,
+            |path| path_filter(path, &include_only_matcher, &exclude_matcher),
+        )
+    });
+    if let (Some(state), Some(state_file)) = (&state, &settings.state_file) {
+        let current_size = state.total();
+        info!("Size from saved state: {}", current_size);
+        if current_size <= max_size && settings.max_age.is_none() {
+            state.save(state_file, &state_fingerprint);
+            return Run {
+                size_before: Some(current_size),
+                ..Run::default()
+            };
+        }
+    }
+
+    if let Some(max_memory) = settings.max_memory {
+        // It walks the tree again, rather than hold on to all of it
+        drop(listing.take());
+        let run = rotate_spilling(
+            settings,
+            base_directory,
+            (max_size, max_memory),
+            (&include_only_matcher, &exclude_matcher),
+            (&select_matcher, &protect_matcher),
+            &protected_paths,
+            notes,
+        );
+        save_state(
+            settings,
+            state.as_mut(),
+            &state_fingerprint,
+            run.outcome.as_ref(),
+        );
+        return run;
+    }
+
+    // Get vec of all files
+    let files: Vec<FileRecord> = scan(
+        settings,
+        base_directory,
+        listed(),
+        (&include_only_matcher, &exclude_matcher),
+        (&select_matcher, &protect_matcher),
+        &protected_paths,
+        notes,
+    )
+    .collect();
+
+    // Calculate size
+    let current_size: u64 = files.iter().map(|f| f.len).sum();
+    let size_to_free = match settings.free_at_least {
+        Some(amount) => amount,
+        None => current_size.saturating_sub(max_size),
+    };
+    info!("Size to free: {}", size_to_free);
+    let mut stuck = (0, 0);
+    for file in &files {
+        add_stuck(&mut stuck, file);
+    }
+    log_stuck(stuck);
+    // Possible early out
+    if size_to_free == 0 && settings.max_age.is_none() {
+        return Run {
+            size_before: Some(current_size),
+            retention: retention(&files, None),
+            ..Run::default()
+        };
+    }
+
+    // Get the files available for operation (deletion), in the units they go in
+    let candidates: Vec<&FileRecord> = files.iter().filter(|x| x.kept_by.is_none()).collect();
+    let (mut deletable, kept_groups) = deletion_units(settings, &files, candidates);
+    let candidate_size: u64 = deletable.iter().flatten().map(|x| x.len).sum();
+    let retained = current_size - candidate_size;
+    // Only worth explaining if deleting everything isn't enough
+    let mut blocked_by_filters: Vec<(&'static str, usize, u64)> = Vec::new();
+    if candidate_size < size_to_free {
+        let deletable_paths: HashSet<&Path> = deletable
+            .iter()
+            .flatten()
+            .map(|x| x.path.as_path())
+            .collect();
+        for file in &files {
+            if deletable_paths.contains(file.path.as_path()) {
+                continue;
+            }
+            // A candidate that's not deletable is in a group that is kept
+            let constraint = match file.kept_by {
+                Some(why) => why,
+                None => kept_groups[&stem_group(&file.path)],
+            };
+            add_blocked(&mut blocked_by_filters, constraint, file.len);
+        }
+    }
+    let candidates = (deletable.iter().map(Vec::len).sum(), candidate_size);
+    // Sort units on last_modified: A group is as old as its newest member
+    deletable.sort_by_key(|unit| {
+        unit.iter()
+            .map(|x| policy_modified(x, settings.future_mtime))
+            .max()
+            .expect("Empty group")
     });
     // Reverse so that the oldest is at the back
     deletable.reverse();
 
+    // Expired units go regardless of size. Since the oldest are at the back,
+    // they're a suffix of the vector.
+    let first_expired =
+        deletable.partition_point(|unit| !unit.iter().all(|x| is_expired(x, settings)));
+    let expired: Vec<&FileRecord> = deletable
+        .split_off(first_expired)
+        .into_iter()
+        .flatten()
+        .collect();
+    let size_freed_by_age: u64 = expired
+        .iter()
+        .map(|x| freeable_size(x, settings.cow_aware))
+        .sum();
+    // Heavier units go after lighter ones. The sort is stable, so age decides within a weight.
+    let weights = Weights::new(base_directory, &settings.weight);
+    if !weights.is_empty() {
+        let mut weighted: Vec<(f64, Unit)> = deletable
+            .into_iter()
+            .map(|unit| {
+                let weight = unit
+                    .iter()
+                    .map(|x| weights.weight_of(&x.path))
+                    .fold(f64::MIN, f64::max);
+                (weight, unit)
+            })
+            .collect();
+        weighted.sort_by(|a, b| b.0.total_cmp(&a.0));
+        deletable = weighted.into_iter().map(|x| x.1).collect();
+    }
+    if settings.incomplete_groups == IncompleteGroups::First {
+        let (incomplete, complete): (Vec<Unit>, Vec<Unit>) = deletable
+            .into_iter()
+            .partition(|unit| is_incomplete(settings, unit));
+        deletable = complete;
+        deletable.extend(incomplete);
+    }
+    let mut reservations = Reservations::new(
+        base_directory,
+        &settings.reserve,
+        files.iter().map(|x| (x.path.as_path(), x.len)),
+    );
+    for x in &expired {
+        reservations.remove(&x.path, x.len);
+    }
+    let mut operations: Vec<Operation> = expired.into_iter().map(Operation::of).collect();
+    let expired_operations = operations.len();
+
     // register_operations
-    let operations = register_operations(deletable, size_to_free);
-    // perform_operations
+    let mut reserved = HashSet::new();
+    let mut size_to_free_by_size = size_to_free.saturating_sub(size_freed_by_age);
+    operations.extend(register_operations(
+        &mut deletable,
+        size_to_free_by_size,
+        &mut reservations,
+        settings.cow_aware,
+        &mut reserved,
+    ));
+    // Bursts during a long scan would leave the directory over the limit right away
+    let growth = growth_since_scan(&files, |path| {
+        path_filter(path, &include_only_matcher, &exclude_matcher)
+    });
+    if settings.free_at_least.is_none() && growth > max_size / 100 {
+        if settings.cover_growth {
+            info!(
+                "The directory grew by {} bytes since the scan, deleting more to make up for it",
+                growth
+            );
+            size_to_free_by_size += growth;
+            operations.extend(register_operations(
+                &mut deletable,
+                growth,
+                &mut reservations,
+                settings.cow_aware,
+                &mut reserved,
+            ));
+        } else {
+            warn!(
+                "The directory grew by {} bytes since the scan, so it stays over the limit after this run. See --cover-growth.",
+                growth
+            );
+        }
+    }
+    if settings.explain {
+        let plan = Plan {
+            operations: &operations,
+            expired: expired_operations,
+            max_age: settings.max_age,
+            size_to_free: size_to_free_by_size,
+            group: settings.group,
+        };
+        print_explanation(base_directory, &plan, &files, &kept_groups, &reserved);
+    }
 
-    if settings.dryrun {
-        info!("Planned operations:");
-        for op in &operations {
-            info!("Delete file: {}", op.display())
+    check_fraction(settings, base_directory, operations.len(), files.len());
+    let outcome = finalize_and_execute(settings, base_directory, operations, Some(size_to_free));
+    save_state(
+        settings,
+        state.as_mut(),
+        &state_fingerprint,
+        outcome.as_ref(),
+    );
+    Run {
+        size_before: Some(current_size),
+        size_to_free,
+        retained: Some(retained),
+        candidates,
+        blocked_by_filters,
+        retention: retention(&files, outcome.as_ref()),
+        outcome,
+        ..Run::default()
+    }
+}
+
+fn needs_whole_plan(settings: &Cli) -> bool {
+    // Options that look at the plan before any of it is carried out
+    settings.grace.is_some()
+        || settings.review
+        || settings.interactive
+        || settings.output == OutputFormat::Json
+        || settings.diff.is_some()
+}
+
+fn add_stuck(stuck: &mut (usize, u64), file: &FileRecord) {
+    if file.kept_by == Some(SOMEONE_ELSES) {
+        stuck.0 += 1;
+        stuck.1 += file.len;
+    }
+}
+
+fn log_stuck((files, bytes): (usize, u64)) {
+    // Counted toward the size, but no run of ours can free them
+    if files > 0 {
+        warn!(
+            "Stuck: {} files of {} bytes belong to other users in sticky directories",
+            files, bytes
+        );
+    }
+}
+
+fn add_blocked(blocked: &mut Vec<(&'static str, usize, u64)>, constraint: &'static str, size: u64) {
+    match blocked.iter_mut().find(|x| x.0 == constraint) {
+        Some(x) => {
+            x.1 += 1;
+            x.2 += size;
         }
+        None => blocked.push((constraint, 1, size)),
+    }
+}
+
+fn save_state(
+    settings: &Cli,
+    state: Option<&mut SizeState>,
+    fingerprint: &str,
+    outcome: Option<&Outcome>,
+) {
+    if let (Some(state), Some(state_file)) = (state, &settings.state_file) {
+        for operation in outcome.iter().flat_map(|outcome| &outcome.deleted) {
+            state.record_deletion(&operation.path, operation.size);
+        }
+        state.save(state_file, fingerprint);
+    }
+}
+
+/// The sized part of `rotate`, in bounded memory (--max-memory). The tree is walked once,
+/// keeping only totals and the candidates, which are sorted on disk if need be.
+/// Every unit is a single file, without reservations or weights.
+fn rotate_spilling(
+    settings: &Cli,
+    base_directory: &Path,
+    (max_size, max_memory): (u64, u64),
+    (include_only_matcher, exclude_matcher): (&Option<impl PathMatcher>, &Option<impl PathMatcher>),
+    (select_matcher, protect_matcher): (&Option<impl PathMatcher>, &Option<impl PathMatcher>),
+    protected_paths: &HashSet<PathBuf>,
+    notes: &ScanNotes,
+) -> Run {
+    let mut spill = Spill::new(max_memory);
+    let (mut file_count, mut current_size) = (0, 0);
+    let mut candidates: (usize, u64) = (0, 0);
+    let mut blocked_by_filters: Vec<(&'static str, usize, u64)> = Vec::new();
+    let mut stuck = (0, 0);
+    // Once writing a run fails, the scan only goes on to measure
+    let mut spill_error = None;
+    for file in scan(
+        settings,
+        base_directory,
+        list_all_files(base_directory, settings.skip_time_machine_excluded),
+        (include_only_matcher, exclude_matcher),
+        (select_matcher, protect_matcher),
+        protected_paths,
+        notes,
+    ) {
+        file_count += 1;
+        current_size += file.len;
+        add_stuck(&mut stuck, &file);
+        if let Some(why) = file.kept_by {
+            add_blocked(&mut blocked_by_filters, why, file.len);
+            continue;
+        }
+        candidates.0 += 1;
+        candidates.1 += file.len;
+        if spill_error.is_some() {
+            continue;
+        }
+        let pushed = spill.push(SpilledFile {
+            key: policy_modified(&file, settings.future_mtime),
+            file: PlannedFile {
+                size: file.len,
+                modified: file.modified,
+                inode: file.identity.inode(),
+                path: file.path,
+            },
+        });
+        spill_error = pushed.err();
+    }
+    let size_to_free = current_size.saturating_sub(max_size);
+    info!("Size to free: {}", size_to_free);
+    log_stuck(stuck);
+    let unplanned = Run {
+        size_before: Some(current_size),
+        size_to_free,
+        retained: Some(current_size - candidates.1),
+        candidates,
+        ..Run::default()
+    };
+    if size_to_free == 0 && settings.max_age.is_none() {
+        return Run {
+            size_before: Some(current_size),
+            ..Run::default()
+        };
+    }
+    if spill.spilled_runs() > 0 {
+        info!(
+            "Sorting {} candidates on disk, in {} runs",
+            candidates.0,
+            spill.spilled_runs()
+        );
+    }
+    let spill_failed = |why: io::Error| {
+        error!(
+            "Could not sort the candidates in a temporary file, keeping all files: {}",
+            why
+        );
+        unplanned
+    };
+    let sorted = match spill_error {
+        Some(why) => Err(why),
+        None => spill.finish(),
+    };
+    let mut sorted = match sorted {
+        Ok(sorted) => sorted,
+        Err(why) => return spill_failed(why),
+    };
+    // Only worth explaining if deleting everything isn't enough
+    if candidates.1 >= size_to_free {
+        blocked_by_filters.clear();
+    }
+
+    // Expired files go regardless of size, and they are the oldest. So the plan is the
+    // shortest run of the oldest candidates that has all of them and frees enough.
+    let mut planned = 0;
+    let mut size_freed: u64 = 0;
+    let records = match sorted.iter() {
+        Ok(records) => records,
+        Err(why) => return spill_failed(why),
+    };
+    for record in records {
+        let record = match record {
+            Ok(record) => record,
+            Err(why) => return spill_failed(why),
+        };
+        if size_freed >= size_to_free && !is_past_max_age(record.key, settings) {
+            break;
+        }
+        planned += 1;
+        size_freed += match settings.cow_aware {
+            true => fs::symlink_metadata(&record.file.path).map_or(0, |metadata| {
+                freeable_size(&FileRecord::new(record.file.path.clone(), &metadata), true)
+            }),
+            false => record.file.size,
+        };
+    }
+    check_fraction(settings, base_directory, planned, file_count);
+    let records = match sorted.iter() {
+        Ok(records) => records,
+        Err(why) => return spill_failed(why),
+    };
+    let records = records.take(planned).map_while(|record| {
+        record
+            .map_err(|why| {
+                error!(
+                    "Could not read the candidates back from a temporary file, stopping the deletions: {}",
+                    why
+                )
+            })
+            .ok()
+    });
+    let operations = records.filter_map(|record| {
+        let operation = record.file.operation(base_directory);
+        if operation.is_none() {
+            warn!(
+                "File changed since the scan, not deleting: {}",
+                record.file.path.display()
+            );
+        }
+        operation
+    });
+    let outcome = if needs_whole_plan(settings) {
+        finalize_and_execute(
+            settings,
+            base_directory,
+            operations.collect(),
+            Some(size_to_free),
+        )
     } else {
-        for op in &operations {
-            if let Ok(()) = fs::remove_file(op) {
-                info!("Deleted file: {}", op.display())
-            } else {
-                warn!("Could not delete file: {}", op.display())
+        Some(execute(settings, base_directory, operations))
+    };
+    Run {
+        size_before: Some(current_size),
+        size_to_free,
+        retained: Some(current_size - candidates.1),
+        candidates,
+        blocked_by_filters,
+        outcome,
+        ..Run::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNITS: &[(&str, u64)] = &[
+        ("ns", 1),
+        ("us", 1_000),
+        ("ms", 1_000_000),
+        ("s", 1_000_000_000),
+        ("m", 60_000_000_000),
+        ("h", 3_600_000_000_000),
+        ("d", 86_400_000_000_000),
+        ("w", 604_800_000_000_000),
+    ];
+
+    /// The options that take a duration, as the first arguments and the option
+    const DURATION_OPTIONS: &[(&[&str], &str)] = &[
+        (&["dir"], "--max-age"),
+        (&["dir", "1G"], "--grace"),
+        (&["dir", "1G"], "--forecast-horizon"),
+        (&["dir", "1G"], "--timeout"),
+        (&["dir", "1G"], "--settle"),
+        (&["dir", "1G"], "--jitter"),
+        (&["dir", "1G"], "--lease-ttl"),
+        (&["dir", "1G", "--lease-ttl", "1m"], "--lease-wait"),
+        (&["simulate", "history"], "--max-age"),
+        (&["whatif", "--scan", "scan"], "--max-age"),
+    ];
+
+    /// Numbers from a fixed xorshift sequence, so that failures can be reproduced
+    fn numbers(count: usize) -> impl Iterator<Item = u64> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..count).map(move |_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        })
+    }
+
+    #[test]
+    fn parses_each_unit() {
+        for (n, &(unit, nanos)) in numbers(1000).zip(UNITS.iter().cycle()) {
+            let n = n % 1000;
+            let parsed = duration_parser(&format!("{}{}", n, unit)).unwrap();
+            assert_eq!(parsed.as_nanos(), u128::from(n * nanos), "{}{}", n, unit);
+        }
+        assert_eq!(
+            duration_parser("1d12h").unwrap(),
+            Duration::from_secs(36 * 3600)
+        );
+    }
+
+    #[test]
+    fn parses_what_it_formats() {
+        for n in numbers(1000) {
+            // Up to about 100 years, with and without fractions of a second
+            let duration = match n % 2 {
+                0 => Duration::from_secs(n % 3_000_000_000),
+                _ => Duration::new(n % 3_000_000_000, (n % 1_000_000_000) as u32),
+            };
+            let formatted = humantime::format_duration(duration).to_string();
+            assert_eq!(duration_parser(&formatted), Ok(duration), "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn rejects_the_same_for_every_option() {
+        let rejected = [
+            "",
+            "7",
+            "d",
+            "7x",
+            "-1d",
+            "1.5d",
+            "7 days ago",
+            "1d-",
+            "ten days",
+        ];
+        for &(args, option) in DURATION_OPTIONS {
+            let parse = |value: &str| {
+                let mut all = vec!["dirrotate"];
+                all.extend_from_slice(args);
+                // As one argument, so that "-1d" isn't taken for a flag
+                let value = format!("{}={}", option, value);
+                all.push(&value);
+                Cli::try_parse_from(all)
+            };
+            assert!(parse("7d").is_ok(), "{:?} {} 7d", args, option);
+            for input in rejected {
+                let why = parse(input).expect_err(input).to_string();
+                let expected = duration_parser(input).unwrap_err();
+                assert!(why.contains(&expected), "{} {:?}: {}", option, input, why);
             }
         }
     }
@@ -1,18 +1,226 @@
-use log::info;
-use path_matchers::{glob, PathMatcher};
+use crate::root;
+use crate::verbatim;
+use glob::{MatchOptions, Pattern, PatternError};
+use log::{info, warn};
+use path_matchers::{any_of, PathMatcher};
+use std::cell::{Cell, RefCell};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-fn canonicalize_pattern(base_dir: &Path, pattern: &String) -> String {
-    let mut res = String::from(base_dir.to_str().expect("Base dir not valid Unicode."));
+/// What patterns are matched against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchMode {
+    /// The canonicalized path, with the pattern prefixed by the rotated directory.
+    /// Wildcards match across directories.
+    Absolute,
+    /// The path relative to the rotated directory, like .gitignore: * and ? stay within a
+    /// directory, ** spans directories, and a pattern without a slash matches at any depth
+    Relative,
+}
+
+static MODE: OnceLock<MatchMode> = OnceLock::new();
+
+/// Set how patterns are matched for the rest of the run
+pub fn set_mode(mode: MatchMode) {
+    MODE.set(mode).expect("The match mode is already set");
+}
+
+fn mode() -> MatchMode {
+    MODE.get().copied().unwrap_or(MatchMode::Absolute)
+}
+
+/// The path that patterns are matched against. A file that is gone since it was found
+/// (or since a scan was saved) is matched as it was found.
+pub fn canonical_path(path: &Path) -> PathBuf {
+    root::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn escape_glob(s: &str) -> String {
+    // Glob has no escape character, but a bracketed character matches itself
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '?' | '*' | '[' | ']' => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn canonicalize_pattern(base_dir: &Path, pattern: &str) -> String {
+    // The base dir is literal, and may contain glob characters. On Windows, it is in its
+    // usual form, like the paths matched, and glob treats / and \ alike.
+    let base_dir = verbatim::strip(base_dir);
+    let mut res = escape_glob(base_dir.to_str().expect("Base dir not valid Unicode."));
     res.push('/');
-    res.push_str(pattern.as_str());
+    res.push_str(pattern);
     info!("Using a matching pattern: {}", res);
     res
 }
 
-pub fn get_path_matcher(base_dir: &PathBuf, pattern: &Option<String>) -> Option<impl PathMatcher> {
-    pattern
-        .as_ref()
-        .map(|p| canonicalize_pattern(&base_dir, &p))
-        .map(|pattern| glob(&pattern).expect("Not a valid glob pattern"))
+fn relative_pattern(pattern: &str) -> String {
+    // As in .gitignore: A leading or middle slash anchors the pattern to the base dir,
+    // and a trailing slash matches everything in a directory
+    let (body, is_dir) = match pattern.strip_suffix('/') {
+        Some(body) => (body, true),
+        None => (pattern, false),
+    };
+    let mut res = match body.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if body.contains('/') => body.to_string(),
+        None => format!("**/{}", body),
+    };
+    if is_dir {
+        res.push_str("/**");
+    }
+    res
+}
+
+/// Matches paths against a glob pattern, the way the match mode says
+pub enum GlobMatcher {
+    Absolute(Pattern),
+    Relative { base_dir: PathBuf, pattern: Pattern },
+}
+
+impl PathMatcher for GlobMatcher {
+    fn matches(&self, path: &Path) -> bool {
+        let path = verbatim::strip(path);
+        match self {
+            GlobMatcher::Absolute(pattern) => pattern.matches_path(&path),
+            GlobMatcher::Relative { base_dir, pattern } => {
+                let options = MatchOptions {
+                    require_literal_separator: true,
+                    ..MatchOptions::new()
+                };
+                path.strip_prefix(base_dir)
+                    .is_ok_and(|relative| pattern.matches_path_with(relative, options))
+            }
+        }
+    }
+}
+
+/// The pattern as it is matched
+fn effective_pattern(base_dir: &Path, pattern: &str) -> String {
+    match mode() {
+        MatchMode::Absolute => canonicalize_pattern(base_dir, pattern),
+        MatchMode::Relative => {
+            let res = relative_pattern(pattern);
+            info!("Using a matching pattern: {}", res);
+            res
+        }
+    }
+}
+
+/// A matcher for a single pattern, relative to the base dir
+pub fn get_glob_matcher(base_dir: &Path, pattern: &str) -> GlobMatcher {
+    try_glob_matcher(base_dir, pattern).expect("Not a valid glob pattern")
+}
+
+/// A matcher for a single pattern, or why it is no valid glob pattern
+pub fn try_glob_matcher(base_dir: &Path, pattern: &str) -> Result<GlobMatcher, PatternError> {
+    // Checked as given first, so that errors point into the pattern as the user wrote it
+    Pattern::new(pattern)?;
+    let compiled = Pattern::new(&effective_pattern(base_dir, pattern))?;
+    Ok(match mode() {
+        MatchMode::Absolute => GlobMatcher::Absolute(compiled),
+        MatchMode::Relative => GlobMatcher::Relative {
+            base_dir: verbatim::strip(base_dir).into_owned(),
+            pattern: compiled,
+        },
+    })
+}
+
+/// A matcher for any of the patterns, or nothing if there are none
+pub fn get_path_matcher(base_dir: &Path, patterns: &[String]) -> Option<impl PathMatcher> {
+    if patterns.is_empty() {
+        return None;
+    }
+    let matchers: Vec<Box<dyn PathMatcher>> = patterns
+        .iter()
+        .map(|p| Box::new(get_glob_matcher(base_dir, p)) as Box<dyn PathMatcher>)
+        .collect();
+    Some(any_of(matchers))
+}
+
+/// Which patterns match any of the files, noted as the scan finds them, to warn about the
+/// ones that match none once it is done. A pattern with a typo or the wrong prefix silently
+/// matches nothing, which for an exclude or protect pattern means nothing is spared.
+pub struct UnmatchedPatterns {
+    base_dir: PathBuf,
+    /// Each pattern, with the option it was given with
+    patterns: Vec<(&'static str, String)>,
+    matchers: Vec<GlobMatcher>,
+    matched: RefCell<Vec<bool>>,
+    example: RefCell<Option<PathBuf>>,
+    scanned: Cell<bool>,
+}
+
+impl UnmatchedPatterns {
+    pub fn new(base_dir: &Path, patterns: Vec<(&'static str, String)>) -> UnmatchedPatterns {
+        let matchers = (patterns.iter())
+            .map(|(_, pattern)| get_glob_matcher(base_dir, pattern))
+            .collect();
+        UnmatchedPatterns {
+            base_dir: base_dir.to_path_buf(),
+            matched: RefCell::new(vec![false; patterns.len()]),
+            patterns,
+            matchers,
+            example: RefCell::new(None),
+            scanned: Cell::new(false),
+        }
+    }
+
+    /// Note that the files are being scanned, so that finding none of them is worth a warning
+    pub fn scanning(&self) {
+        self.scanned.set(true);
+    }
+
+    /// Note a file the scan found, by its canonical path
+    pub fn see(&self, path: &Path) {
+        let mut matched = self.matched.borrow_mut();
+        if matched.iter().all(|&m| m) {
+            return;
+        }
+        for (matcher, matched) in self.matchers.iter().zip(matched.iter_mut()) {
+            *matched = *matched || matcher.matches(path);
+        }
+        self.example
+            .borrow_mut()
+            .get_or_insert_with(|| path.to_path_buf());
+    }
+
+    /// Warn about the patterns that matched none of the files seen, if they were scanned
+    pub fn warn(&self) {
+        if !self.scanned.get() {
+            return;
+        }
+        let matched = self.matched.borrow();
+        let example = match &*self.example.borrow() {
+            Some(path) => format!(", e.g. {}", path.display()),
+            None => String::from(", but there are no files"),
+        };
+        let hint = match mode() {
+            MatchMode::Absolute => "against the full paths",
+            MatchMode::Relative => "against the paths relative to it",
+        };
+        for ((option, pattern), _) in self
+            .patterns
+            .iter()
+            .zip(matched.iter())
+            .filter(|(_, m)| !**m)
+        {
+            warn!(
+                "{} {} matches no files. Patterns are relative to the rotated directory, so it is matched as {} {}{}. Start it with **/ to match in any subdirectory.",
+                option,
+                pattern,
+                effective_pattern(&self.base_dir, pattern),
+                hint,
+                example
+            );
+        }
+    }
 }
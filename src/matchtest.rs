@@ -0,0 +1,69 @@
+//! Which patterns a path hits, for debugging them (dirrotate match-test)
+use crate::matching::{canonical_path, get_glob_matcher};
+use path_matchers::PathMatcher;
+use std::path::Path;
+
+/// The patterns of a run, in the order they are applied
+pub struct Patterns<'a> {
+    pub include_only: &'a [String],
+    pub exclude: Option<&'a str>,
+    pub select_for_op: &'a [String],
+    pub protect_from_op: Option<&'a str>,
+}
+
+/// Print each pattern the path is checked against and whether it matched, and what that
+/// makes of the file. Checks stop at the first that decides.
+pub fn print_matches(base_dir: &Path, patterns: &Patterns, path: &Path, default_protected: bool) {
+    let canonical = canonical_path(&base_dir.join(path));
+    let hits = |pattern: &str| get_glob_matcher(base_dir, pattern).matches(&canonical);
+    println!("{}", canonical.display());
+    let verdict = (|| {
+        if !patterns.include_only.is_empty() {
+            let mut any = false;
+            for pattern in patterns.include_only {
+                let hit = hits(pattern);
+                println!("  --include-only {}: {}", pattern, describe(hit));
+                any |= hit;
+            }
+            if !any {
+                return "not counted, as no --include-only matches";
+            }
+        }
+        if let Some(pattern) = patterns.exclude {
+            let hit = hits(pattern);
+            println!("  --exclude {}: {}", pattern, describe(hit));
+            if hit {
+                return "not counted, as it is excluded";
+            }
+        }
+        if !patterns.select_for_op.is_empty() {
+            let mut any = false;
+            for pattern in patterns.select_for_op {
+                let hit = hits(pattern);
+                println!("  --select-for-op {}: {}", pattern, describe(hit));
+                any |= hit;
+            }
+            if !any {
+                return "counted, but kept, as no --select-for-op matches";
+            }
+        } else if let Some(pattern) = patterns.protect_from_op {
+            let hit = hits(pattern);
+            println!("  --protect-from-op {}: {}", pattern, describe(hit));
+            if hit {
+                return "counted, but kept, as it is protected";
+            }
+        }
+        if default_protected {
+            return "counted, but kept, as it is in a directory protected by default";
+        }
+        "counted, and may be deleted"
+    })();
+    println!("  => {}", verdict);
+}
+
+fn describe(hit: bool) -> &'static str {
+    match hit {
+        true => "matches",
+        false => "no match",
+    }
+}
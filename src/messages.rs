@@ -0,0 +1,135 @@
+//! The messages for operators (the plan summary, the confirmation and the review), and their
+//! translations (--messages). Catalogs are in a subset of Fluent: `id = text` on each line,
+//! `{ $name }` where an argument goes, and `#` for comments. `dirrotate messages` prints the
+//! English catalog to start a translation from.
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Every message by its id, in English
+pub const ENGLISH: &[(&str, &str)] = &[
+    ("plan-summary", "Planned: Delete { $files } files, { $bytes } bytes"),
+    (
+        "plan-oldest",
+        "Oldest candidate: { $path } (modified { $modified })",
+    ),
+    (
+        "plan-newest",
+        "Newest candidate: { $path } (modified { $modified })",
+    ),
+    ("plan-proceed", "Proceed? [y/N]"),
+    ("review-heading", "Planned deletions by directory:"),
+    (
+        "review-directory",
+        "{ $selected } of { $files } files, { $bytes } bytes",
+    ),
+    ("review-file", "{ $bytes } bytes, modified { $modified }"),
+    ("review-selected", "Selected: { $files } files, { $bytes } bytes"),
+    ("review-short", "(short of the { $bytes } bytes to free)"),
+    (
+        "review-prompt",
+        "<n> toggle directory, <n> l list its files, <n>.<m> toggle file, p print, x execute, q quit:",
+    ),
+    ("review-no-directory", "No such directory: { $n }"),
+    ("review-no-file", "No such file: { $n }"),
+    ("review-unknown", "Unknown command: { $command }"),
+    (
+        "summary-planned",
+        "Would delete { $files } files, freeing { $bytes } bytes",
+    ),
+    (
+        "summary-deleted",
+        "Deleted { $files } files, freeing { $bytes } bytes",
+    ),
+    (
+        "summary-changed",
+        "Skipped { $files } files that changed since the scan",
+    ),
+    (
+        "summary-timed-out",
+        "Out of time: Did not get to { $files } files of { $bytes } bytes",
+    ),
+    (
+        "summary-failed",
+        "Could not delete { $files } files: { $errors }",
+    ),
+    (
+        "summary-scan-errors",
+        "Could not read { $entries } entries while scanning, so the size leaves out what they hold",
+    ),
+    (
+        "summary-retention",
+        "Left { $span } of data, the oldest file modified { $age } ago",
+    ),
+];
+
+static CATALOG: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Use the translations in the catalog for the rest of the run. Messages it lacks stay in
+/// English.
+pub fn load(path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|why| why.to_string())?;
+    let mut catalog = HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id, text) = line
+            .split_once('=')
+            .ok_or_else(|| format!("Not a message: {}", line))?;
+        let id = id.trim();
+        if !ENGLISH.iter().any(|(known, _)| *known == id) {
+            warn!("Ignoring unknown message {} in {}", id, path.display());
+            continue;
+        }
+        catalog.insert(id.to_string(), text.trim().to_string());
+    }
+    CATALOG
+        .set(catalog)
+        .map_err(|_| String::from("A catalog is already loaded"))
+}
+
+/// The message with the arguments filled in, translated if there is a translation
+pub fn text(id: &str, args: &[(&str, &dyn Display)]) -> String {
+    let pattern = match CATALOG.get().and_then(|catalog| catalog.get(id)) {
+        Some(translated) => translated.as_str(),
+        None => {
+            (ENGLISH.iter())
+                .find(|(known, _)| *known == id)
+                .unwrap_or_else(|| panic!("Unknown message {}", id))
+                .1
+        }
+    };
+    let mut out = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+                break;
+            }
+        };
+        let name = rest[start + 1..end].trim().trim_start_matches('$');
+        match args.iter().find(|(arg, _)| *arg == name) {
+            Some((_, value)) => out.push_str(&value.to_string()),
+            // Left as is, so that a mistake in a translation shows
+            None => out.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Print the English catalog
+pub fn print_catalog() {
+    for (id, english) in ENGLISH {
+        println!("{} = {}", id, english);
+    }
+}
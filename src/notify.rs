@@ -0,0 +1,396 @@
+use crate::json::{self, Object};
+use crate::retention::format_age;
+use clap::ArgEnum;
+use log::debug;
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// What a run did, for notifications
+pub struct Summary {
+    pub directory: PathBuf,
+    pub deleted: usize,
+    pub freed: u64,
+    pub failed: usize,
+    /// Bytes still to free after the run
+    pub shortfall: u64,
+    /// Entries the scan could not read
+    pub scan_errors: usize,
+    /// The age of the oldest file left, and the span from it to the newest
+    pub retention: Option<(Duration, Duration)>,
+    /// Why the run was aborted, if it was
+    pub error: Option<String>,
+}
+
+impl Summary {
+    /// A summary of a run that never got to delete anything
+    pub fn aborted(directory: &Path, error: String) -> Summary {
+        Summary {
+            directory: directory.to_path_buf(),
+            deleted: 0,
+            freed: 0,
+            failed: 0,
+            shortfall: 0,
+            scan_errors: 0,
+            retention: None,
+            error: Some(error),
+        }
+    }
+
+    /// Whether something went wrong
+    pub fn is_failure(&self) -> bool {
+        self.error.is_some() || self.failed > 0 || self.shortfall > 0
+    }
+
+    pub fn subject(&self) -> String {
+        let what = if self.error.is_some() {
+            "aborted"
+        } else if self.failed > 0 {
+            "failed"
+        } else if self.shortfall > 0 {
+            "could not reach the target"
+        } else {
+            "freed space"
+        };
+        format!("dirrotate {}: {}", what, self.directory.display())
+    }
+
+    /// The summary as (label, value) pairs
+    fn facts(&self) -> Vec<(&'static str, String)> {
+        let mut facts = vec![("Directory", self.directory.display().to_string())];
+        if let Some(error) = &self.error {
+            facts.push(("Aborted", error.clone()));
+        }
+        facts.push(("Deleted", format!("{} files", self.deleted)));
+        facts.push(("Freed", format!("{} bytes", self.freed)));
+        if self.failed > 0 {
+            facts.push(("Failed", format!("{} files", self.failed)));
+        }
+        if self.shortfall > 0 {
+            facts.push(("Still over the limit", format!("{} bytes", self.shortfall)));
+        }
+        if self.scan_errors > 0 {
+            facts.push(("Unreadable", format!("{} entries", self.scan_errors)));
+        }
+        if let Some((age, span)) = self.retention {
+            facts.push(("Oldest left", format!("{} old", format_age(age))));
+            facts.push(("Span left", format_age(span)));
+        }
+        facts
+    }
+
+    pub fn text(&self) -> String {
+        let mut lines = vec![format!("Directory: {}", self.directory.display())];
+        if let Some(error) = &self.error {
+            lines.push(format!("Aborted: {}", error));
+        }
+        lines.push(format!(
+            "Deleted {} files, freeing {} bytes",
+            self.deleted, self.freed
+        ));
+        if self.failed > 0 {
+            lines.push(format!("Failed to delete {} files", self.failed));
+        }
+        if self.shortfall > 0 {
+            lines.push(format!(
+                "Still {} bytes over the limit after deleting every candidate",
+                self.shortfall
+            ));
+        }
+        if self.scan_errors > 0 {
+            lines.push(format!(
+                "Could not read {} entries while scanning",
+                self.scan_errors
+            ));
+        }
+        if let Some((age, span)) = self.retention {
+            lines.push(format!(
+                "Left {} of data, the oldest file modified {} ago",
+                format_age(span),
+                format_age(age)
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+fn run_with_input(command: &mut Command, input: &str) -> io::Result<()> {
+    // Runs a command with `input` on its stdin, turning a non-zero exit into an error
+    debug!("Running {:?}", command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} failed ({}): {}",
+            command.get_program(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Where and how to send email notifications
+pub struct EmailSettings<'a> {
+    /// smtp://host:port or smtps://host:port
+    pub smtp_url: &'a str,
+    /// Credentials for the SMTP server, in netrc format. Defaults to ~/.netrc.
+    pub netrc: Option<&'a Path>,
+    pub from: &'a str,
+    pub to: &'a [String],
+}
+
+/// Mail the summary through curl, which speaks SMTP (with STARTTLS when the server offers it)
+pub fn send_email(settings: &EmailSettings, summary: &Summary) -> io::Result<()> {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        settings.from,
+        settings.to.join(", "),
+        summary.subject()
+    );
+    for line in summary.text().lines() {
+        // A lone dot would end the message early
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--fail", "--ssl"])
+        .arg("--url")
+        .arg(settings.smtp_url)
+        .arg("--mail-from")
+        .arg(settings.from);
+    match settings.netrc {
+        Some(netrc) => command.arg("--netrc-file").arg(netrc),
+        None => command.arg("--netrc-optional"),
+    };
+    for recipient in settings.to {
+        command.arg("--mail-rcpt").arg(recipient);
+    }
+    command.args(["--upload-file", "-"]);
+    run_with_input(&mut command, &message)
+}
+
+/// Whether we're running inside a graphical session with a session bus to notify on
+pub fn in_desktop_session() -> bool {
+    let has = |var| env::var_os(var).is_some_and(|v| !v.is_empty());
+    has("DBUS_SESSION_BUS_ADDRESS") && (has("DISPLAY") || has("WAYLAND_DISPLAY"))
+}
+
+/// Pop up a freedesktop notification through notify-send
+pub fn send_desktop(summary: &Summary) -> io::Result<()> {
+    let urgency = if summary.is_failure() {
+        "critical"
+    } else {
+        "normal"
+    };
+    let status = Command::new("notify-send")
+        .args(["--app-name", "dirrotate", "--urgency", urgency])
+        .arg(summary.subject())
+        .arg(summary.text())
+        .stdin(Stdio::null())
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("notify-send failed ({})", status)))
+    }
+}
+
+/// What to post to a webhook
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookFormat {
+    /// The summary as a plain JSON object
+    Json,
+    /// Slack incoming webhook with blocks
+    Slack,
+    /// Microsoft Teams incoming webhook with an adaptive card
+    Teams,
+    /// Mattermost incoming webhook with markdown text
+    Mattermost,
+}
+
+fn webhook_payload(format: WebhookFormat, summary: &Summary) -> String {
+    let text_object = |kind: &str, text: &str| {
+        Object::new()
+            .string("type", kind)
+            .string("text", text)
+            .finish()
+    };
+    match format {
+        WebhookFormat::Json => {
+            let mut object = Object::new()
+                .string("directory", &summary.directory.to_string_lossy())
+                .raw("failure", summary.is_failure().to_string())
+                .number("deleted", summary.deleted as u64)
+                .number("freed", summary.freed)
+                .number("failed", summary.failed as u64)
+                .number("shortfall", summary.shortfall)
+                .number("scan_errors", summary.scan_errors as u64);
+            if let Some((age, span)) = summary.retention {
+                object = object
+                    .number("oldest_retained_age_seconds", age.as_secs())
+                    .number("retained_span_seconds", span.as_secs());
+            }
+            object
+                .optional_string("error", summary.error.as_deref())
+                .finish()
+        }
+        WebhookFormat::Slack => {
+            let fields = summary
+                .facts()
+                .into_iter()
+                .map(|(label, value)| text_object("mrkdwn", &format!("*{}*\n{}", label, value)));
+            let blocks = [
+                Object::new()
+                    .string("type", "header")
+                    .raw("text", text_object("plain_text", &summary.subject()))
+                    .finish(),
+                Object::new()
+                    .string("type", "section")
+                    .raw("fields", json::array(fields))
+                    .finish(),
+            ];
+            Object::new()
+                .string("text", &summary.subject())
+                .raw("blocks", json::array(blocks))
+                .finish()
+        }
+        WebhookFormat::Teams => {
+            let facts = summary.facts().into_iter().map(|(label, value)| {
+                Object::new()
+                    .string("title", label)
+                    .string("value", &value)
+                    .finish()
+            });
+            let body = [
+                Object::new()
+                    .string("type", "TextBlock")
+                    .string("text", &summary.subject())
+                    .string("weight", "Bolder")
+                    .string("size", "Medium")
+                    .raw("wrap", String::from("true"))
+                    .finish(),
+                Object::new()
+                    .string("type", "FactSet")
+                    .raw("facts", json::array(facts))
+                    .finish(),
+            ];
+            let card = Object::new()
+                .string(
+                    "$schema",
+                    "http://adaptivecards.io/schemas/adaptive-card.json",
+                )
+                .string("type", "AdaptiveCard")
+                .string("version", "1.4")
+                .raw("body", json::array(body))
+                .finish();
+            let attachment = Object::new()
+                .string("contentType", "application/vnd.microsoft.card.adaptive")
+                .raw("content", card)
+                .finish();
+            Object::new()
+                .string("type", "message")
+                .raw("attachments", json::array([attachment]))
+                .finish()
+        }
+        WebhookFormat::Mattermost => {
+            let mut text = format!("#### {}\n", summary.subject());
+            for (label, value) in summary.facts() {
+                text.push_str(&format!("**{}:** {}\n", label, value));
+            }
+            Object::new().string("text", &text).finish()
+        }
+    }
+}
+
+/// POST the summary to a webhook through curl
+pub fn send_webhook(url: &str, format: WebhookFormat, summary: &Summary) -> io::Result<()> {
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--fail", "--netrc-optional"])
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", "@-"])
+        .arg(url);
+    run_with_input(&mut command, &webhook_payload(format, summary))
+}
+
+/// Publish a retained message through mosquitto_pub, so that subscribers that connect
+/// later still get the latest one. `broker` is host or host:port.
+pub fn publish_mqtt(broker: &str, topic: &str, message: &str) -> io::Result<()> {
+    let mut command = Command::new("mosquitto_pub");
+    match broker.rsplit_once(':') {
+        Some((host, port)) => command.arg("-h").arg(host).arg("-p").arg(port),
+        None => command.arg("-h").arg(broker),
+    };
+    // QoS 1 so that the broker acknowledges it before we exit
+    command.arg("-t").arg(topic).args(["-r", "-q", "1", "-s"]);
+    run_with_input(&mut command, message)
+}
+
+/// A gauge for the Prometheus text format
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: f64,
+}
+
+fn base64_url(data: &[u8]) -> String {
+    // The URL-safe alphabet, as the Pushgateway expects for label values with slashes
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Push the metrics to a Prometheus Pushgateway, grouped under job "dirrotate" and
+/// labeled with the directory. Replaces what the previous run pushed for the directory.
+pub fn push_metrics(url: &str, directory: &Path, metrics: &[Metric]) -> io::Result<()> {
+    let mut body = String::new();
+    for metric in metrics {
+        body.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+            metric.name, metric.help, metric.name, metric.name, metric.value
+        ));
+    }
+    let directory = directory.to_string_lossy();
+    let group = format!(
+        "{}/metrics/job/dirrotate/directory@base64/{}",
+        url.trim_end_matches('/'),
+        base64_url(directory.as_bytes())
+    );
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--fail", "--netrc-optional"])
+        .args(["--request", "PUT", "--data-binary", "@-"])
+        .arg(group);
+    run_with_input(&mut command, &body)
+}
@@ -0,0 +1,206 @@
+//! Plans as JSON, for `--dryrun --output json` and `dirrotate apply`.
+//!
+//! ```text
+//! {
+//!   "schema_version": 1,
+//!   "directory": "/var/data",          the rotated directory, canonicalized
+//!   "created": "2026-10-14T12:00:00Z",
+//!   "operations": [
+//!     {
+//!       "action": "delete",
+//!       "path": "/var/data/run1.bin",
+//!       "size": 1000,
+//!       "modified": "2026-10-01T08:00:00.123456789Z",
+//!       "dev": 2049, "ino": 1234        unix only
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Readers must ignore fields they don't know. Fields are only ever added within a schema
+//! version; anything else bumps it.
+use crate::clock;
+use crate::executor::Operation;
+use crate::json::{self, Object, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// A planned deletion, as written to or read from a plan
+#[derive(Clone)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Device and inode, where there are such
+    pub inode: Option<(u64, u64)>,
+}
+
+pub struct Plan {
+    pub directory: PathBuf,
+    pub created: SystemTime,
+    pub files: Vec<PlannedFile>,
+}
+
+#[cfg(unix)]
+fn inode_of(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode_of(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The plan as JSON. The files are stat'ed again for their device and inode.
+pub fn to_json(directory: &Path, operations: &[Operation]) -> String {
+    let operations = operations.iter().map(|operation| {
+        let mut object = Object::new()
+            .string("action", "delete")
+            .string("path", &operation.path.to_string_lossy())
+            .number("size", operation.size)
+            .string(
+                "modified",
+                &humantime::format_rfc3339_nanos(operation.modified).to_string(),
+            );
+        let inode = fs::symlink_metadata(&operation.path)
+            .ok()
+            .and_then(|m| inode_of(&m));
+        if let Some((dev, ino)) = inode {
+            object = object.number("dev", dev).number("ino", ino);
+        }
+        object.finish()
+    });
+    Object::new()
+        .number("schema_version", SCHEMA_VERSION)
+        .string("directory", &directory.to_string_lossy())
+        .string(
+            "created",
+            &humantime::format_rfc3339_seconds(clock::now()).to_string(),
+        )
+        .raw("operations", json::array(operations))
+        .finish()
+}
+
+fn parse_time(value: Option<&Value>, what: &str) -> Result<SystemTime, String> {
+    let s = value
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("Missing {}", what))?;
+    humantime::parse_rfc3339(s).map_err(|e| format!("Bad {}: {}", what, e))
+}
+
+fn parse_file(value: &Value) -> Result<PlannedFile, String> {
+    match value.get("action").and_then(Value::as_str) {
+        Some("delete") => {}
+        Some(action) => return Err(format!("Unknown action {}", action)),
+        None => return Err(String::from("Missing action")),
+    }
+    let path = value
+        .get("path")
+        .and_then(Value::as_str)
+        .ok_or("Missing path")?;
+    let size = value
+        .get("size")
+        .and_then(Value::as_u64)
+        .ok_or("Missing size")?;
+    let dev = value.get("dev").and_then(Value::as_u64);
+    let ino = value.get("ino").and_then(Value::as_u64);
+    Ok(PlannedFile {
+        path: PathBuf::from(path),
+        size,
+        modified: parse_time(value.get("modified"), "modified")?,
+        inode: dev.zip(ino),
+    })
+}
+
+/// Parse a plan, refusing schema versions we don't know
+pub fn from_json(input: &str) -> Result<Plan, String> {
+    let value = json::parse(input)?;
+    match value.get("schema_version").and_then(Value::as_u64) {
+        Some(SCHEMA_VERSION) => {}
+        Some(version) => return Err(format!("Unsupported schema version {}", version)),
+        None => return Err(String::from("Missing schema_version")),
+    }
+    let directory = value
+        .get("directory")
+        .and_then(Value::as_str)
+        .ok_or("Missing directory")?;
+    let files = value
+        .get("operations")
+        .and_then(Value::as_array)
+        .ok_or("Missing operations")?
+        .iter()
+        .enumerate()
+        .map(|(i, op)| parse_file(op).map_err(|e| format!("Operation {}: {}", i, e)))
+        .collect::<Result<_, _>>()?;
+    Ok(Plan {
+        directory: PathBuf::from(directory),
+        created: parse_time(value.get("created"), "created")?,
+        files,
+    })
+}
+
+impl PlannedFile {
+    /// The operation for the file, if it is still the one that was planned
+    pub fn operation(&self, directory: &Path) -> Option<Operation> {
+        if !self.path.starts_with(directory) {
+            return None;
+        }
+        let metadata = fs::symlink_metadata(&self.path).ok()?;
+        let unchanged = metadata.is_file()
+            && metadata.len() == self.size
+            && metadata.modified().ok() == Some(self.modified)
+            && (self.inode.is_none() || inode_of(&metadata) == self.inode);
+        match unchanged {
+            true => Some(Operation::new(self.path.clone(), &metadata)),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn round_trips() {
+        let dir = TestDir::new("plan");
+        let path = dir.join("run \"1\".bin");
+        fs::write(&path, vec![0; 1000]).unwrap();
+        let operation = Operation::new(path.clone(), &fs::metadata(&path).unwrap());
+        let modified = operation.modified;
+
+        let plan = from_json(&to_json(&dir, &[operation])).unwrap();
+        let file = &plan.files[0];
+        assert_eq!(plan.directory, *dir);
+        assert_eq!(plan.files.len(), 1);
+        assert_eq!(file.path, path);
+        assert_eq!(file.size, 1000);
+        assert_eq!(file.modified, modified);
+        assert_eq!(file.inode.is_some(), cfg!(unix));
+        assert_eq!(file.operation(&dir).map(|op| op.path), Some(path));
+    }
+
+    #[test]
+    fn rejects_other_schema_versions() {
+        let plan = to_json(Path::new("/var/data"), &[]);
+        let other = plan.replace(
+            &format!("\"schema_version\":{}", SCHEMA_VERSION),
+            &format!("\"schema_version\":{}", SCHEMA_VERSION + 1),
+        );
+        assert_ne!(plan, other);
+        assert_eq!(
+            from_json(&other).err(),
+            Some(format!("Unsupported schema version {}", SCHEMA_VERSION + 1))
+        );
+        let missing = plan.replace(&format!("\"schema_version\":{},", SCHEMA_VERSION), "");
+        assert_eq!(
+            from_json(&missing).err(),
+            Some(String::from("Missing schema_version"))
+        );
+    }
+}
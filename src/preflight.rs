@@ -0,0 +1,72 @@
+//! A check that the files in a directory can be rotated by this user, for before it runs
+//! unattended (dirrotate preflight)
+use crate::safety::{dir_access, is_own, DirAccess};
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Walk the directory like a run does, and print each place where reading or deleting would
+/// fail. Returns whether there were none.
+pub fn preflight(dir: &Path, is_skipped: impl Fn(&Path) -> bool) -> bool {
+    let (mut dirs, mut files) = (0, 0);
+    let mut problems = Vec::new();
+    let walk = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !is_skipped(e.path()));
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(why) => {
+                let path = why.path().unwrap_or(dir).display().to_string();
+                match why.io_error() {
+                    Some(io_error) => problems.push(format!("Cannot read {}: {}", path, io_error)),
+                    None => problems.push(format!("Cannot read {}: {}", path, why)),
+                }
+                continue;
+            }
+        };
+        let path = entry.path();
+        if !entry.file_type().is_dir() {
+            files += 1;
+            if let Err(why) = path.symlink_metadata() {
+                problems.push(format!("Cannot stat {}: {}", path.display(), why));
+            }
+            continue;
+        }
+        dirs += 1;
+        match dir_access(path) {
+            DirAccess::Writable => {}
+            DirAccess::Denied(why) => problems.push(format!(
+                "Cannot delete files in {}: {}",
+                path.display(),
+                why
+            )),
+            DirAccess::Sticky => {
+                let others = (path.read_dir().into_iter().flatten().flatten())
+                    .filter(|e| e.file_type().is_ok_and(|t| !t.is_dir()))
+                    .filter(|e| !is_own(&e.path()))
+                    .count();
+                if others > 0 {
+                    problems.push(format!(
+                        "Cannot delete {} files of other users in the sticky directory {}",
+                        others,
+                        path.display()
+                    ));
+                }
+            }
+        }
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!(
+        "Checked {} directories and {} files in {}: {}",
+        dirs,
+        files,
+        dir.display(),
+        match problems.len() {
+            0 => String::from("no problems"),
+            n => format!("{} problems", n),
+        }
+    );
+    problems.is_empty()
+}
@@ -0,0 +1,51 @@
+//! Dropping root for a user (--run-as), for services that are started as root
+use std::io;
+
+/// Become the user, with their group and supplementary groups, and their home directory as
+/// $HOME (where the trash is). Only root can do this, and it can't be undone.
+#[cfg(unix)]
+pub fn run_as(user: &str) -> io::Result<()> {
+    use std::ffi::{CStr, CString};
+    let c_user = CString::new(user)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Not a user name"))?;
+    let mut passwd = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwnam_r(
+            c_user.as_ptr(),
+            passwd.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if found.is_null() {
+        return Err(match status {
+            0 => io::Error::new(io::ErrorKind::NotFound, format!("No user {}", user)),
+            errno => io::Error::from_raw_os_error(errno),
+        });
+    }
+    let passwd = unsafe { passwd.assume_init() };
+    let home = unsafe { CStr::from_ptr(passwd.pw_dir) }
+        .to_string_lossy()
+        .into_owned();
+    // The groups first, as that takes root
+    if unsafe { libc::initgroups(c_user.as_ptr(), passwd.pw_gid as _) } != 0
+        || unsafe { libc::setgid(passwd.pw_gid) } != 0
+        || unsafe { libc::setuid(passwd.pw_uid) } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    std::env::set_var("HOME", home);
+    std::env::set_var("USER", user);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn run_as(_user: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Can't switch users on this platform",
+    ))
+}
@@ -0,0 +1,230 @@
+use crate::executor::Operation;
+use crate::messages::text;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+fn format_time(t: SystemTime) -> humantime::Rfc3339Timestamp {
+    humantime::format_rfc3339_seconds(t)
+}
+
+/// Show a summary of the plan and ask whether to go ahead with it
+pub fn confirm_plan(operations: &[Operation]) -> bool {
+    let total_size: u64 = operations.iter().map(|op| op.size).sum();
+    let mut stderr = io::stderr();
+    let _ = writeln!(
+        stderr,
+        "{}",
+        text(
+            "plan-summary",
+            &[("files", &operations.len()), ("bytes", &total_size)]
+        )
+    );
+    if operations.is_empty() {
+        return false;
+    }
+    let oldest = operations.iter().min_by_key(|op| op.modified);
+    let newest = operations.iter().max_by_key(|op| op.modified);
+    if let (Some(oldest), Some(newest)) = (oldest, newest) {
+        for (id, op) in [("plan-oldest", oldest), ("plan-newest", newest)] {
+            let args: [(&str, &dyn std::fmt::Display); 2] = [
+                ("path", &op.path.display()),
+                ("modified", &format_time(op.modified)),
+            ];
+            let _ = writeln!(stderr, "{}", text(id, &args));
+        }
+    }
+    let _ = write!(stderr, "{} ", text("plan-proceed", &[]));
+    let _ = stderr.flush();
+
+    // Anything but a clear yes (including a closed stdin) means no
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+struct ReviewFile {
+    /// Position in the plan
+    index: usize,
+    operation: Operation,
+    selected: bool,
+}
+
+struct ReviewDir {
+    dir: PathBuf,
+    files: Vec<ReviewFile>,
+}
+
+impl ReviewDir {
+    fn selected(&self) -> impl Iterator<Item = &Operation> {
+        self.files
+            .iter()
+            .filter(|f| f.selected)
+            .map(|f| &f.operation)
+    }
+}
+
+fn print_review(dirs: &[ReviewDir], size_to_free: Option<u64>) {
+    let mut stderr = io::stderr();
+    let _ = writeln!(stderr, "{}", text("review-heading", &[]));
+    for (i, dir) in dirs.iter().enumerate() {
+        let selected = dir.selected().count();
+        let mark = match selected {
+            0 => ' ',
+            n if n == dir.files.len() => 'x',
+            _ => '~',
+        };
+        let size: u64 = dir.selected().map(|op| op.size).sum();
+        let count = text(
+            "review-directory",
+            &[
+                ("selected", &selected),
+                ("files", &dir.files.len()),
+                ("bytes", &size),
+            ],
+        );
+        let _ = writeln!(
+            stderr,
+            "  [{}] {}) {} ({})",
+            mark,
+            i + 1,
+            dir.dir.display(),
+            count
+        );
+    }
+    let count: usize = dirs.iter().map(|d| d.selected().count()).sum();
+    let size: u64 = dirs
+        .iter()
+        .flat_map(|d| d.selected())
+        .map(|op| op.size)
+        .sum();
+    let _ = write!(
+        stderr,
+        "{}",
+        text("review-selected", &[("files", &count), ("bytes", &size)])
+    );
+    match size_to_free {
+        Some(size_to_free) if size < size_to_free => {
+            let short = text("review-short", &[("bytes", &size_to_free)]);
+            let _ = writeln!(stderr, " {}", short);
+        }
+        _ => {
+            let _ = writeln!(stderr);
+        }
+    }
+}
+
+fn print_review_files(dirs: &[ReviewDir], i: usize) {
+    let mut stderr = io::stderr();
+    for (j, file) in dirs[i].files.iter().enumerate() {
+        let op = &file.operation;
+        let name = op.path.file_name().unwrap_or_default().to_string_lossy();
+        let _ = writeln!(
+            stderr,
+            "    [{}] {}.{}) {} ({})",
+            if file.selected { 'x' } else { ' ' },
+            i + 1,
+            j + 1,
+            name,
+            text(
+                "review-file",
+                &[("bytes", &op.size), ("modified", &format_time(op.modified))]
+            )
+        );
+    }
+}
+
+/// Let the operator browse the plan by directory and take directories or individual
+/// files out of it. Returns the operations to execute, or nothing if the operator quits.
+pub fn review_plan(
+    operations: Vec<Operation>,
+    size_to_free: Option<u64>,
+) -> Option<Vec<Operation>> {
+    let mut dirs: Vec<ReviewDir> = Vec::new();
+    for (index, operation) in operations.into_iter().enumerate() {
+        let dir = operation
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let file = ReviewFile {
+            index,
+            operation,
+            selected: true,
+        };
+        match dirs.iter_mut().find(|d| d.dir == dir) {
+            Some(review_dir) => review_dir.files.push(file),
+            None => dirs.push(ReviewDir {
+                dir,
+                files: vec![file],
+            }),
+        }
+    }
+    dirs.sort_by(|a, b| a.dir.cmp(&b.dir));
+
+    let parse_index = |s: &str, len: usize| {
+        s.parse::<usize>()
+            .ok()
+            .filter(|i| (1..=len).contains(i))
+            .map(|i| i - 1)
+    };
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    print_review(&dirs, size_to_free);
+    loop {
+        eprint!("{} ", text("review-prompt", &[]));
+        let _ = io::stderr().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            // A closed stdin means quit
+            _ => return None,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["x"] => break,
+            ["q"] => return None,
+            ["p"] => print_review(&dirs, size_to_free),
+            [n, "l"] => match parse_index(n, dirs.len()) {
+                Some(i) => print_review_files(&dirs, i),
+                None => eprintln!("{}", text("review-no-directory", &[("n", n)])),
+            },
+            [n] if n.contains('.') => {
+                let (n, m) = n.split_once('.').unwrap_or_default();
+                let file = parse_index(n, dirs.len())
+                    .and_then(|i| Some((i, parse_index(m, dirs[i].files.len())?)));
+                match file {
+                    Some((i, j)) => {
+                        dirs[i].files[j].selected = !dirs[i].files[j].selected;
+                        print_review_files(&dirs, i);
+                    }
+                    None => {
+                        let file = format!("{}.{}", n, m);
+                        eprintln!("{}", text("review-no-file", &[("n", &file)]))
+                    }
+                }
+            }
+            [n] => match parse_index(n, dirs.len()) {
+                Some(i) => {
+                    // Toggling a partially selected directory selects all of it
+                    let select = dirs[i].selected().count() < dirs[i].files.len();
+                    for file in &mut dirs[i].files {
+                        file.selected = select;
+                    }
+                    print_review(&dirs, size_to_free);
+                }
+                None => eprintln!("{}", text("review-no-directory", &[("n", n)])),
+            },
+            _ => eprintln!("{}", text("review-unknown", &[("command", &line)])),
+        }
+    }
+    // Execute in plan order, not directory order
+    let mut selected: Vec<ReviewFile> = dirs
+        .into_iter()
+        .flat_map(|d| d.files)
+        .filter(|f| f.selected)
+        .collect();
+    selected.sort_by_key(|f| f.index);
+    Some(selected.into_iter().map(|f| f.operation).collect())
+}
@@ -0,0 +1,99 @@
+use crate::matching::get_glob_matcher;
+use crate::root;
+use crate::verbatim::for_programs;
+use log::{debug, info};
+use path_matchers::PathMatcher;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+fn parse_path_list(base_dir: &Path, list: &str, protected: &mut HashSet<PathBuf>) {
+    // One path per line. Relative paths are relative to the rotated directory.
+    // Paths that don't exist (anymore) can't be deleted anyway, so they're skipped.
+    // Directories are kept as they are, and protect what is below them when matched.
+    for line in list.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match root::canonicalize(&base_dir.join(line)) {
+            Ok(path) => {
+                protected.insert(path);
+            }
+            Err(why) => debug!("Ignoring protected path {}: {}", line, why),
+        }
+    }
+}
+
+/// Collect the set of (canonicalized) paths that must never be deleted
+pub fn get_protected_paths(
+    base_dir: &Path,
+    list_file: &Option<PathBuf>,
+    command: &Option<String>,
+) -> HashSet<PathBuf> {
+    let mut protected = HashSet::new();
+    if let Some(file) = list_file {
+        let list = fs::read_to_string(file).expect("Could not read the list of protected paths");
+        parse_path_list(base_dir, &list, &mut protected);
+    }
+    if let Some(cmd) = command {
+        // If the command fails, we can't know what is referenced. Bail out rather than
+        // deleting something that should have been protected.
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .current_dir(for_programs(base_dir))
+            .output()
+            .expect("Could not run the command listing protected paths");
+        if !output.status.success() {
+            panic!(
+                "The command listing protected paths failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let list = String::from_utf8(output.stdout)
+            .expect("The command listing protected paths did not output valid Unicode");
+        parse_path_list(base_dir, &list, &mut protected);
+    }
+    if list_file.is_some() || command.is_some() {
+        info!("Protecting {} listed paths", protected.len());
+    }
+    protected
+}
+
+/// Collect the (canonicalized) paths of the `keep` newest files matching each pattern.
+/// Each pattern keeps its own newest files, regardless of the other patterns.
+/// Without patterns, the newest files of all are kept.
+pub fn get_newest_per_pattern(
+    base_dir: &Path,
+    patterns: &[String],
+    keep: usize,
+    files: impl Iterator<Item = (PathBuf, SystemTime)>,
+) -> HashSet<PathBuf> {
+    let mut files: Vec<(PathBuf, SystemTime)> = files
+        .filter_map(|(path, modified)| Some((root::canonicalize(&path).ok()?, modified)))
+        .collect();
+    files.sort_by_key(|x| Reverse(x.1));
+    let all = [String::from("**")];
+    let patterns = if patterns.is_empty() { &all } else { patterns };
+    let mut newest = HashSet::new();
+    for pattern in patterns {
+        let matcher = get_glob_matcher(base_dir, pattern);
+        let kept = files
+            .iter()
+            .filter(|(path, _)| matcher.matches(path))
+            .take(keep)
+            .map(|(path, _)| path.clone());
+        let before = newest.len();
+        newest.extend(kept);
+        debug!(
+            "Keeping the {} newest files matching {}",
+            newest.len() - before,
+            pattern
+        );
+    }
+    newest
+}
@@ -0,0 +1,102 @@
+use std::io;
+use std::path::Path;
+
+/// The project quota of a directory, as the kernel keeps it
+pub struct Quota {
+    /// The soft limit, or the hard limit if there is no soft one
+    pub limit: Option<u64>,
+    /// Space used by all files of the project
+    pub usage: u64,
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    /// struct fsxattr, for FS_IOC_FSGETXATTR
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct FsXattr {
+        pub xflags: u32,
+        pub extsize: u32,
+        pub nextents: u32,
+        pub projid: u32,
+        pub cowextsize: u32,
+        pub pad: [u8; 8],
+    }
+
+    pub const FS_IOC_FSGETXATTR: libc::c_ulong = 0x801C_581F;
+    pub const PRJQUOTA: libc::c_int = 2;
+    /// Quota limits are in blocks of 1 KiB
+    pub const QUOTA_BLOCK: u64 = 1024;
+}
+
+#[cfg(target_os = "linux")]
+fn device_of(path: &Path) -> io::Result<String> {
+    // Find the mount by its device number, as the kernel reports it to us
+    use std::os::unix::fs::MetadataExt;
+    let dev = path.metadata()?.dev();
+    let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    mountinfo
+        .lines()
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.get(2) != Some(&format!("{}:{}", major, minor).as_str()) {
+                return None;
+            }
+            // The source comes after the separator and the filesystem type
+            let separator = fields.iter().position(|&f| f == "-")?;
+            fields.get(separator + 2).map(|s| s.to_string())
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not find the mount"))
+}
+
+/// The limit and usage of the project quota the directory belongs to (XFS, ext4)
+#[cfg(target_os = "linux")]
+pub fn project_quota(path: &Path) -> io::Result<Quota> {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use sys::*;
+
+    let dir = File::open(path)?;
+    let mut attr = FsXattr::default();
+    if unsafe { libc::ioctl(dir.as_raw_fd(), FS_IOC_FSGETXATTR as _, &mut attr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if attr.projid == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "The directory is not in a project",
+        ));
+    }
+    let device = CString::new(device_of(path)?)?;
+    let mut quota: libc::dqblk = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::quotactl(
+            libc::QCMD(libc::Q_GETQUOTA, PRJQUOTA),
+            device.as_ptr(),
+            attr.projid as libc::c_int,
+            &mut quota as *mut libc::dqblk as *mut libc::c_char,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let limit = match (quota.dqb_bhardlimit, quota.dqb_bsoftlimit) {
+        (0, 0) => None,
+        (hard, 0) => Some(hard * QUOTA_BLOCK),
+        (_, soft) => Some(soft * QUOTA_BLOCK),
+    };
+    Ok(Quota {
+        limit,
+        usage: quota.dqb_curspace,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn project_quota(_path: &Path) -> io::Result<Quota> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "No project quotas on this platform",
+    ))
+}
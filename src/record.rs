@@ -0,0 +1,29 @@
+use crate::dirfd::FileIdentity;
+use std::fs::Metadata;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A file as found by the walk, with what planning needs of its metadata
+#[derive(Clone, Debug)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub len: u64,
+    pub modified: SystemTime,
+    pub identity: FileIdentity,
+    /// Why the file may not be deleted, if it may not. Set by the scan.
+    pub kept_by: Option<&'static str>,
+}
+
+impl FileRecord {
+    pub fn new(path: PathBuf, metadata: &Metadata) -> FileRecord {
+        FileRecord {
+            path,
+            len: metadata.len(),
+            modified: metadata
+                .modified()
+                .expect("Last Modified Time is not available on this platform"),
+            identity: FileIdentity::of(metadata),
+            kept_by: None,
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! How much data is left after a run: the age of the oldest file kept, and the span from it
+//! to the newest. That is how many days of data the directory still holds.
+use crate::clock;
+use crate::executor::Outcome;
+use crate::record::FileRecord;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// The modification times of the oldest and newest files left
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    pub oldest: SystemTime,
+    pub newest: SystemTime,
+}
+
+impl Retention {
+    /// How long ago the oldest file left was modified
+    pub fn age(&self) -> Duration {
+        clock::now().duration_since(self.oldest).unwrap_or_default()
+    }
+
+    /// From the oldest file left to the newest
+    pub fn span(&self) -> Duration {
+        self.newest.duration_since(self.oldest).unwrap_or_default()
+    }
+}
+
+/// The retention, widened to a file modified then
+pub fn widen(retention: Option<Retention>, modified: SystemTime) -> Option<Retention> {
+    Some(match retention {
+        Some(r) => Retention {
+            oldest: r.oldest.min(modified),
+            newest: r.newest.max(modified),
+        },
+        None => Retention {
+            oldest: modified,
+            newest: modified,
+        },
+    })
+}
+
+/// A duration to the minute, e.g. "12days 3h 5m"
+pub fn format_age(duration: Duration) -> String {
+    let minutes = Duration::from_secs(duration.as_secs() / 60 * 60);
+    humantime::format_duration(minutes).to_string()
+}
+
+/// The retention of the files that the outcome left, or would leave in a dry-run
+pub fn retention<'a>(
+    files: impl IntoIterator<Item = &'a FileRecord>,
+    outcome: Option<&Outcome>,
+) -> Option<Retention> {
+    let gone: HashSet<&Path> = (outcome.iter())
+        .flat_map(|outcome| outcome.deleted.iter().chain(&outcome.planned))
+        .map(|op| op.path.as_path())
+        .collect();
+    (files.into_iter())
+        .filter(|file| !gone.contains(file.path.as_path()))
+        .fold(None, |retention, file| widen(retention, file.modified))
+}
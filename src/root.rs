@@ -0,0 +1,91 @@
+//! Rotating inside another root (--root), e.g. a mounted firmware image. Paths are resolved
+//! as they would be with the root as /: absolute symlinks and .. stay inside it.
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Symlinks followed in one path before giving up, like the kernel's limit
+const MAX_LINKS: usize = 40;
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Resolve paths inside the (canonicalized) root for the rest of the run
+pub fn set(root: PathBuf) {
+    ROOT.set(root).expect("The root is already set");
+}
+
+/// Like Path::canonicalize, but inside the root if there is one. Paths already below the
+/// root are taken as they are; other absolute paths, and relative ones, are from the root.
+pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    let root = match ROOT.get() {
+        Some(root) => root,
+        None => return path.canonicalize(),
+    };
+    let inside = path.strip_prefix(root).unwrap_or(path);
+    let mut pending: VecDeque<OsString> = components(inside);
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+    while let Some(name) = pending.pop_front() {
+        match name.to_str() {
+            Some("..") => {
+                resolved.pop();
+                continue;
+            }
+            Some(".") => continue,
+            _ => {}
+        }
+        let candidate = root.join(&resolved).join(&name);
+        if !fs::symlink_metadata(&candidate)?.file_type().is_symlink() {
+            resolved.push(name);
+            continue;
+        }
+        links += 1;
+        if links > MAX_LINKS {
+            return Err(io::Error::other(format!(
+                "Too many levels of symbolic links in {}",
+                path.display()
+            )));
+        }
+        let target = fs::read_link(&candidate)?;
+        if target.is_absolute() {
+            resolved.clear();
+        }
+        for (i, component) in components(&target).into_iter().enumerate() {
+            pending.insert(i, component);
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => None,
+        })
+        .collect()
+}
+
+/// Make the root / for the rest of the run (--chroot), which takes root
+#[cfg(unix)]
+pub fn enter(root: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_root = std::ffi::CString::new(root.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Not a path"))?;
+    if unsafe { libc::chroot(c_root.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    std::env::set_current_dir("/")
+}
+
+#[cfg(not(unix))]
+pub fn enter(_root: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "chroot is not supported on this platform",
+    ))
+}
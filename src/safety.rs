@@ -0,0 +1,146 @@
+use log::warn;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+fn is_filesystem_root(path: &Path) -> bool {
+    // A mount point lives on a different device than its parent directory
+    // (and "/" is its own parent)
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let (Ok(dir), Ok(parent)) = (path.metadata(), path.join("..").metadata()) {
+            return dir.dev() != parent.dev() || dir.ino() == parent.ino();
+        }
+    }
+    path.parent().is_none()
+}
+
+fn is_home(path: &Path) -> bool {
+    ["HOME", "USERPROFILE"]
+        .iter()
+        .filter_map(env::var_os)
+        .filter_map(|home| Path::new(&home).canonicalize().ok())
+        .any(|home| home == path)
+}
+
+/// Check that the (canonicalized) directory is something it's sane to rotate
+pub fn check_base_dir(path: &Path) -> Result<(), String> {
+    if path.parent().is_none() {
+        Err(String::from("it is the root directory"))
+    } else if is_home(path) {
+        Err(String::from("it is a home directory"))
+    } else if is_filesystem_root(path) {
+        Err(String::from("it is the root of a filesystem"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the plan doesn't wipe (almost) all of the files
+pub fn check_plan_fraction(planned: usize, total: usize, max_fraction: f64) -> Result<(), String> {
+    if total == 0 {
+        return Ok(());
+    }
+    let fraction = planned as f64 / total as f64;
+    if fraction > max_fraction {
+        Err(format!(
+            "the plan deletes {} of {} files ({:.0}%), more than the allowed {:.0}%",
+            planned,
+            total,
+            fraction * 100.0,
+            max_fraction * 100.0
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether files can be deleted from each directory, looked up as files are scanned, so
+/// that the plan leaves out what deletion would fail on
+#[derive(Default)]
+pub struct Deletable {
+    /// With whether the directory was reported
+    dirs: HashMap<PathBuf, (DirAccess, bool)>,
+}
+
+/// Why a file in a sticky directory is kept
+pub const SOMEONE_ELSES: &str = "someone else's, in a sticky directory";
+
+/// What the effective user may delete from a directory
+pub enum DirAccess {
+    Writable,
+    /// Writable, but only files of our own can be deleted, like in /tmp
+    Sticky,
+    Denied(io::Error),
+}
+
+#[cfg(unix)]
+pub fn dir_access(dir: &Path) -> DirAccess {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    let c_dir = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(c_dir) => c_dir,
+        Err(_) => return DirAccess::Writable,
+    };
+    // With the effective user, which deletes the files
+    let flags = libc::W_OK | libc::X_OK;
+    if unsafe { libc::faccessat(libc::AT_FDCWD, c_dir.as_ptr(), flags, libc::AT_EACCESS) } != 0 {
+        return DirAccess::Denied(io::Error::last_os_error());
+    }
+    let euid = unsafe { libc::geteuid() };
+    match dir.metadata() {
+        // The sticky bit
+        Ok(m) if m.mode() & 0o1000 != 0 && euid != 0 && m.uid() != euid => DirAccess::Sticky,
+        _ => DirAccess::Writable,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn dir_access(_dir: &Path) -> DirAccess {
+    DirAccess::Writable
+}
+
+/// Whether the effective user owns the file
+#[cfg(unix)]
+pub fn is_own(file: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let euid = unsafe { libc::geteuid() };
+    file.symlink_metadata().map_or(true, |m| m.uid() == euid)
+}
+
+#[cfg(not(unix))]
+pub fn is_own(_file: &Path) -> bool {
+    true
+}
+
+impl Deletable {
+    /// Why the file can't be deleted, if it can't. Each directory is reported once.
+    pub fn why_not(&mut self, file: &Path) -> Option<&'static str> {
+        let dir = file.parent()?;
+        let (access, reported) = (self.dirs)
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| (dir_access(dir), false));
+        let why_not = match access {
+            DirAccess::Writable => return None,
+            DirAccess::Sticky if is_own(file) => return None,
+            DirAccess::Sticky => SOMEONE_ELSES,
+            DirAccess::Denied(_) => "in a directory it may not delete from",
+        };
+        if !*reported {
+            match access {
+                DirAccess::Denied(why) => {
+                    warn!("Cannot delete files in {}: {}", dir.display(), why)
+                }
+                _ => warn!(
+                    "Cannot delete the files of other users in the sticky directory {}",
+                    dir.display()
+                ),
+            }
+            *reported = true;
+        }
+        Some(why_not)
+    }
+}
@@ -0,0 +1,209 @@
+//! Sandboxing the run itself (--sandbox, on Linux): Landlock keeps it from changing files
+//! anywhere but the given places, and a seccomp filter from calls a rotation never needs, like
+//! mounting. Both hold for the rest of the run, and for the commands it runs.
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    pub const SYS_CREATE_RULESET: libc::c_long = 444;
+    pub const SYS_ADD_RULE: libc::c_long = 445;
+    pub const SYS_RESTRICT_SELF: libc::c_long = 446;
+    pub const CREATE_RULESET_VERSION: u32 = 1;
+    pub const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    pub const WRITE_FILE: u64 = 1 << 1;
+    pub const REMOVE_DIR: u64 = 1 << 4;
+    pub const REMOVE_FILE: u64 = 1 << 5;
+    /// MAKE_CHAR, MAKE_DIR, MAKE_REG, MAKE_SOCK, MAKE_FIFO, MAKE_BLOCK and MAKE_SYM
+    pub const MAKE_ANY: u64 = 0b111_1111 << 6;
+    /// Renaming and linking across directories, from ABI 2
+    pub const REFER: u64 = 1 << 13;
+    /// From ABI 3
+    pub const TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    pub struct RulesetAttr {
+        pub handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    pub struct PathBeneathAttr {
+        pub allowed_access: u64,
+        pub parent_fd: i32,
+    }
+}
+
+/// The place itself, or the closest parent that exists, as rules need something to open and
+/// a place that doesn't exist yet is created below it
+fn existing(path: &Path) -> Option<&Path> {
+    path.ancestors().find(|p| p.exists())
+}
+
+/// Calls that are refused with EPERM
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const DENIED_CALLS: &[libc::c_long] = &[
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_ptrace,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+];
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn install_seccomp() -> io::Result<()> {
+    let statement = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let ret = libc::BPF_RET | libc::BPF_K;
+    // struct seccomp_data: the call's number at 0, the architecture at 4
+    let mut program = vec![
+        statement(load, 4),
+        jump(AUDIT_ARCH, 1, 0),
+        statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(load, 0),
+    ];
+    for &call in DENIED_CALLS {
+        program.push(jump(call as u32, 0, 1));
+        program.push(statement(ret, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+    program.push(statement(ret, libc::SECCOMP_RET_ALLOW));
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    if unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &fprog) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+fn install_seccomp() -> io::Result<()> {
+    log::warn!("No seccomp filter for this architecture, only Landlock applies");
+    Ok(())
+}
+
+/// Restrict the rest of the run to changing files below the given places. Fails if the
+/// kernel can't enforce it, rather than running without.
+#[cfg(target_os = "linux")]
+pub fn restrict(writable: &[PathBuf]) -> io::Result<()> {
+    use landlock::*;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let abi = unsafe {
+        libc::syscall(
+            SYS_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Landlock is not available ({})", io::Error::last_os_error()),
+        ));
+    }
+    let mut handled = WRITE_FILE | REMOVE_DIR | REMOVE_FILE | MAKE_ANY;
+    if abi >= 2 {
+        handled |= REFER;
+    }
+    if abi >= 3 {
+        handled |= TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            SYS_CREATE_RULESET,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = ruleset as libc::c_int;
+    let result = (|| {
+        for path in writable.iter().filter_map(|p| existing(p)) {
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Not a path"))?;
+            let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Files only take the rights that apply to files
+            let allowed = match path.is_dir() {
+                true => handled,
+                false => handled & (WRITE_FILE | TRUNCATE),
+            };
+            let rule = PathBeneathAttr {
+                allowed_access: allowed,
+                parent_fd: fd,
+            };
+            let added =
+                unsafe { libc::syscall(SYS_ADD_RULE, ruleset, RULE_PATH_BENEATH, &rule, 0) };
+            unsafe { libc::close(fd) };
+            if added != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::syscall(SYS_RESTRICT_SELF, ruleset, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        install_seccomp()
+    })();
+    unsafe { libc::close(ruleset) };
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict(_writable: &[PathBuf]) -> io::Result<()> {
+    let _ = existing;
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Sandboxing is only supported on Linux",
+    ))
+}
@@ -0,0 +1,59 @@
+//! What to do about entries that the scan can't read (--on-scan-error). They are counted
+//! either way, as the size leaves out whatever they hold.
+use clap::ArgEnum;
+use log::{debug, error, warn};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::process;
+use std::sync::{Mutex, OnceLock};
+
+/// How to treat an entry that can't be read
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnScanError {
+    /// Go on without it, only logging it at debug level
+    Skip,
+    /// Go on without it, with a warning
+    Warn,
+    /// Stop the run
+    Fail,
+}
+
+static POLICY: OnceLock<OnScanError> = OnceLock::new();
+/// The errors so far. A run may walk the tree more than once, and each error counts once.
+static SEEN: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Treat errors like this for the rest of the run. Without it, they are warned about.
+pub fn set(policy: OnScanError) {
+    POLICY
+        .set(policy)
+        .expect("The scan error policy is already set");
+}
+
+/// Deal with an entry that could not be read
+pub fn report(why: impl Display) {
+    let why = why.to_string();
+    let new = SEEN
+        .lock()
+        .expect("Scan errors poisoned")
+        .get_or_insert_with(HashSet::new)
+        .insert(why.clone());
+    if !new {
+        return;
+    }
+    match POLICY.get().copied().unwrap_or(OnScanError::Warn) {
+        OnScanError::Skip => debug!("Traversal error: {}", why),
+        OnScanError::Warn => warn!("Traversal error: {}", why),
+        OnScanError::Fail => {
+            error!("Traversal error, stopping the run: {}", why);
+            process::exit(1);
+        }
+    }
+}
+
+/// How many entries could not be read
+pub fn count() -> usize {
+    SEEN.lock()
+        .expect("Scan errors poisoned")
+        .as_ref()
+        .map_or(0, HashSet::len)
+}
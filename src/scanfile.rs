@@ -0,0 +1,128 @@
+//! The files found by a scan, saved to reuse for later runs (--save-scan, --load-scan)
+//! instead of walking the directory again
+use crate::dirfd::FileIdentity;
+use crate::record::FileRecord;
+use crate::spill::{time_from_parts, time_to_parts};
+use log::warn;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+const HEADER: &str = "dirrotate-scan 1";
+
+static LOADED: OnceLock<SavedScan> = OnceLock::new();
+
+/// The files below a directory, as they were at one point in time
+pub struct SavedScan {
+    pub root: PathBuf,
+    pub scanned_at: SystemTime,
+    pub skip_time_machine_excluded: bool,
+    pub files: Vec<FileRecord>,
+}
+
+fn format_time(t: SystemTime) -> String {
+    let (secs, nanos) = time_to_parts(t);
+    format!("{}.{:09}", secs, nanos)
+}
+
+fn parse_time(s: &str) -> Option<SystemTime> {
+    let (secs, nanos) = s.split_once('.')?;
+    Some(time_from_parts(secs.parse().ok()?, nanos.parse().ok()?))
+}
+
+fn parse_file(line: &str) -> Option<FileRecord> {
+    let mut fields = line.splitn(5, '\t');
+    let modified = parse_time(fields.next()?)?;
+    let len = fields.next()?.parse().ok()?;
+    let dev = fields.next()?.parse().ok()?;
+    let ino = fields.next()?.parse().ok()?;
+    let path = PathBuf::from(fields.next()?);
+    Some(FileRecord {
+        path,
+        len,
+        modified,
+        identity: FileIdentity::from_saved(Some((dev, ino)), modified, len),
+        kept_by: None,
+    })
+}
+
+/// Write the scan to a file. Like with the state file, a temporary file is renamed into
+/// place, so that a crash never leaves a truncated scan.
+pub fn save(path: &Path, scan: &SavedScan) {
+    let mut content = format!(
+        "{}\n{}\n{}\n{}\n",
+        HEADER,
+        scan.root
+            .to_str()
+            .expect("The directory path is not valid UTF-8"),
+        format_time(scan.scanned_at),
+        match scan.skip_time_machine_excluded {
+            true => "skip_time_machine_excluded",
+            false => "",
+        }
+    );
+    let mut unsaved = 0;
+    for file in &scan.files {
+        let (dev, ino) = file.identity.inode().unwrap_or((0, 0));
+        match file.path.to_str() {
+            Some(p) if !p.contains('\n') => content.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                format_time(file.modified),
+                file.len,
+                dev,
+                ino,
+                p
+            )),
+            _ => unsaved += 1,
+        }
+    }
+    if unsaved > 0 {
+        warn!(
+            "Not saving {} files whose paths can't be represented in the scan",
+            unsaved
+        );
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(content.as_bytes()))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .expect("Could not save the scan");
+}
+
+/// Read a scan back from a file
+pub fn load(path: &Path) -> Result<SavedScan, String> {
+    let content = fs::read_to_string(path).map_err(|why| why.to_string())?;
+    let mut lines = content.lines();
+    if lines.next() != Some(HEADER) {
+        return Err("Not a saved scan".to_string());
+    }
+    let root = PathBuf::from(lines.next().ok_or("No directory")?);
+    let scanned_at = lines
+        .next()
+        .and_then(parse_time)
+        .ok_or("No time of the scan")?;
+    let skip_time_machine_excluded = lines.next() == Some("skip_time_machine_excluded");
+    let files = lines
+        .map(|line| parse_file(line).ok_or_else(|| format!("Malformed line: {}", line)))
+        .collect::<Result<_, _>>()?;
+    Ok(SavedScan {
+        root,
+        scanned_at,
+        skip_time_machine_excluded,
+        files,
+    })
+}
+
+/// List the files of this scan instead of walking the directory, for the rest of the run
+pub fn install(scan: SavedScan) {
+    if LOADED.set(scan).is_err() {
+        panic!("A scan is already loaded");
+    }
+}
+
+/// The installed scan, if there is one
+pub fn loaded() -> Option<&'static SavedScan> {
+    LOADED.get()
+}
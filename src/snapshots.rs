@@ -0,0 +1,111 @@
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Filesystems whose snapshots keep deleted files on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotFs {
+    Btrfs,
+    Zfs,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+fn statfs(path: &Path) -> Option<libc::statfs> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statfs(path.as_ptr(), &mut stat) } {
+        0 => Some(stat),
+        _ => None,
+    }
+}
+
+/// The filesystem the path lies on, if it is one with snapshots
+#[cfg(target_os = "linux")]
+pub fn snapshot_fs(path: &Path) -> Option<SnapshotFs> {
+    const BTRFS_SUPER_MAGIC: i64 = 0x9123_683E;
+    const ZFS_SUPER_MAGIC: i64 = 0x2FC1_2FC1;
+    #[allow(clippy::unnecessary_cast)]
+    match statfs(path)?.f_type as i64 {
+        BTRFS_SUPER_MAGIC => Some(SnapshotFs::Btrfs),
+        ZFS_SUPER_MAGIC => Some(SnapshotFs::Zfs),
+        _ => None,
+    }
+}
+
+/// The filesystem the path lies on, if it is one with snapshots
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn snapshot_fs(path: &Path) -> Option<SnapshotFs> {
+    let stat = statfs(path)?;
+    let name: Vec<u8> = stat
+        .f_fstypename
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    match name.as_slice() {
+        b"zfs" => Some(SnapshotFs::Zfs),
+        _ => None,
+    }
+}
+
+/// The filesystem the path lies on, if it is one with snapshots
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub fn snapshot_fs(_path: &Path) -> Option<SnapshotFs> {
+    None
+}
+
+fn mount_root(path: &Path) -> PathBuf {
+    // The topmost ancestor on the same device
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let mut root = path.to_path_buf();
+        if let Ok(dev) = path.metadata().map(|m| m.dev()) {
+            while let Some(parent) = root.parent() {
+                if parent.metadata().map(|m| m.dev()).ok() != Some(dev) {
+                    break;
+                }
+                root = parent.to_path_buf();
+            }
+        }
+        root
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_path_buf()
+    }
+}
+
+/// Whether the directory is known to be in snapshots. None if there is no telling
+/// (listing Btrfs snapshots usually takes root).
+pub fn has_snapshots(path: &Path, fs: SnapshotFs) -> Option<bool> {
+    match fs {
+        SnapshotFs::Zfs => {
+            // .zfs/snapshot is there in the root of each dataset, even when hidden
+            let snapshots = mount_root(path).join(".zfs/snapshot");
+            let mut entries = fs::read_dir(snapshots).ok()?;
+            Some(entries.next().is_some())
+        }
+        SnapshotFs::Btrfs => {
+            let output = Command::new("btrfs")
+                .args(["subvolume", "show"])
+                .arg(path)
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .ok()?;
+            if !output.status.success() {
+                debug!("Could not list the Btrfs snapshots of {}", path.display());
+                return None;
+            }
+            // The snapshots of the subvolume are listed (indented) below "Snapshot(s):"
+            let output = String::from_utf8_lossy(&output.stdout);
+            let mut lines = output.lines().skip_while(|l| l.trim() != "Snapshot(s):");
+            lines.next()?;
+            Some(lines.any(|l| !l.trim().is_empty()))
+        }
+    }
+}
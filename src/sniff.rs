@@ -0,0 +1,163 @@
+use log::warn;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Number of leading bytes needed by the signatures below
+const HEADER_LEN: usize = 512;
+
+fn detect(header: &[u8]) -> &'static str {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(4, b"ftyp") {
+        return match header.get(8..12) {
+            Some(b"qt  ") => "video/quicktime",
+            Some(b"M4A ") => "audio/mp4",
+            Some(b"heic") | Some(b"heix") | Some(b"mif1") => "image/heif",
+            _ => "video/mp4",
+        };
+    }
+    if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        let is_webm = header.windows(4).any(|w| w == b"webm");
+        return if is_webm {
+            "video/webm"
+        } else {
+            "video/x-matroska"
+        };
+    }
+    if at(0, b"RIFF") {
+        return match header.get(8..12) {
+            Some(b"AVI ") => "video/x-msvideo",
+            Some(b"WAVE") => "audio/wav",
+            Some(b"WEBP") => "image/webp",
+            _ => "application/octet-stream",
+        };
+    }
+    if header.len() > 188 && header[0] == 0x47 && header[188] == 0x47 {
+        return "video/mp2t";
+    }
+
+    // Simple prefix signatures
+    const SIGNATURES: &[(usize, &[u8], &str)] = &[
+        (0, &[0x00, 0x00, 0x01, 0xBA], "video/mpeg"),
+        (0, &[0x00, 0x00, 0x01, 0xB3], "video/mpeg"),
+        (0, b"FLV", "video/x-flv"),
+        (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+        (0, &[0xFF, 0xD8, 0xFF], "image/jpeg"),
+        (0, b"GIF87a", "image/gif"),
+        (0, b"GIF89a", "image/gif"),
+        (0, b"II*\0", "image/tiff"),
+        (0, b"MM\0*", "image/tiff"),
+        (0, b"BM", "image/bmp"),
+        (0, b"ID3", "audio/mpeg"),
+        (0, &[0xFF, 0xFB], "audio/mpeg"),
+        (0, &[0xFF, 0xF3], "audio/mpeg"),
+        (0, &[0xFF, 0xF2], "audio/mpeg"),
+        (0, b"fLaC", "audio/flac"),
+        (0, b"OggS", "audio/ogg"),
+        (0, b"%PDF-", "application/pdf"),
+        (0, b"PK\x03\x04", "application/zip"),
+        (0, &[0x1F, 0x8B], "application/gzip"),
+        (0, b"BZh", "application/x-bzip2"),
+        (0, &[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00], "application/x-xz"),
+        (0, &[0x28, 0xB5, 0x2F, 0xFD], "application/zstd"),
+        (
+            0,
+            &[0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C],
+            "application/x-7z-compressed",
+        ),
+        (257, b"ustar", "application/x-tar"),
+        (0, b"SQLite format 3\0", "application/vnd.sqlite3"),
+        (0, b"\x89HDF\r\n\x1a\n", "application/x-hdf5"),
+        (0, &[0xD4, 0xC3, 0xB2, 0xA1], "application/vnd.tcpdump.pcap"),
+        (0, &[0xA1, 0xB2, 0xC3, 0xD4], "application/vnd.tcpdump.pcap"),
+        (0, &[0x0A, 0x0D, 0x0D, 0x0A], "application/x-pcapng"),
+        (0, b"\x7fELF", "application/x-executable"),
+    ];
+    for (offset, magic, mime) in SIGNATURES {
+        if at(*offset, magic) {
+            return mime;
+        }
+    }
+
+    // No binary signature: Call it text, if it looks like text. The header may cut a
+    // multi-byte character in half, which is fine.
+    let is_utf8 = match std::str::from_utf8(header) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if !header.contains(&0) && is_utf8 {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// Detect the MIME type of a file from its leading bytes
+pub fn sniff_mime_type(path: &Path) -> Option<&'static str> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    let read = File::open(path).and_then(|f| f.take(HEADER_LEN as u64).read_to_end(&mut header));
+    match read {
+        Ok(_) => Some(detect(&header)),
+        Err(why) => {
+            warn!(
+                "Could not read file {} for type detection: {}",
+                path.display(),
+                why
+            );
+            None
+        }
+    }
+}
+
+/// Match a MIME type against a pattern like `video/*`, `*/*` or `image/png`
+pub fn mime_type_matches(pattern: &str, mime: &str) -> bool {
+    let (pattern_type, pattern_subtype) = pattern.split_once('/').unwrap_or((pattern, "*"));
+    let (mime_type, mime_subtype) = mime.split_once('/').unwrap_or((mime, ""));
+    let part_matches =
+        |pattern: &str, part: &str| pattern == "*" || pattern.eq_ignore_ascii_case(part);
+    part_matches(pattern_type, mime_type) && part_matches(pattern_subtype, mime_subtype)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_signatures() {
+        assert_eq!(detect(b"\x00\x00\x00\x18ftypisom"), "video/mp4");
+        assert_eq!(detect(b"\x00\x00\x00\x14ftypqt  "), "video/quicktime");
+        assert_eq!(detect(b"\x1a\x45\xdf\xa3\x01\x00webm"), "video/webm");
+        assert_eq!(detect(b"RIFF\x00\x00\x00\x00WAVEfmt "), "audio/wav");
+        assert_eq!(detect(b"\x89PNG\r\n\x1a\n\x00\x00"), "image/png");
+        assert_eq!(detect(b"\x1f\x8b\x08\x00"), "application/gzip");
+        let mut tar = vec![0; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(detect(&tar), "application/x-tar");
+        let mut ts = vec![0; 200];
+        (ts[0], ts[188]) = (0x47, 0x47);
+        assert_eq!(detect(&ts), "video/mp2t");
+    }
+
+    #[test]
+    fn tells_text_from_binary() {
+        assert_eq!(detect(b"2026-10-14 12:00:00 started\n"), "text/plain");
+        // Cut off in the middle of a character by the header length
+        assert_eq!(
+            detect("temp 21 °".as_bytes().split_last().unwrap().1),
+            "text/plain"
+        );
+        assert_eq!(detect(b"data\x00more"), "application/octet-stream");
+        assert_eq!(detect(b"\xff\xfe\xfd"), "application/octet-stream");
+    }
+
+    #[test]
+    fn matches_type_patterns() {
+        assert!(mime_type_matches("video/*", "video/mp4"));
+        assert!(mime_type_matches("*/*", "text/plain"));
+        assert!(mime_type_matches("video", "video/webm"));
+        assert!(mime_type_matches("Image/PNG", "image/png"));
+        assert!(!mime_type_matches("video/*", "audio/mp4"));
+        assert!(!mime_type_matches("image/png", "image/jpeg"));
+    }
+}
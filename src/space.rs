@@ -0,0 +1,52 @@
+use std::path::Path;
+
+#[cfg(unix)]
+fn statvfs(path: &Path) -> Option<libc::statvfs> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        0 => Some(stat),
+        _ => None,
+    }
+}
+
+/// Space available to unprivileged users on the filesystem of the path
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    let stat = statvfs(path)?;
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Space in use on the filesystem of the path, which no directory on it can exceed
+#[cfg(unix)]
+pub fn used_space(path: &Path) -> Option<u64> {
+    let stat = statvfs(path)?;
+    #[allow(clippy::unnecessary_cast)]
+    Some((stat.f_blocks as u64).saturating_sub(stat.f_bfree as u64) * stat.f_frsize as u64)
+}
+
+/// Size of the filesystem of the path
+#[cfg(unix)]
+pub fn capacity(path: &Path) -> Option<u64> {
+    let stat = statvfs(path)?;
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_blocks as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn capacity(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn used_space(_path: &Path) -> Option<u64> {
+    None
+}
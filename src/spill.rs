@@ -0,0 +1,411 @@
+//! Sorting candidates in bounded memory (--max-memory): an external merge sort, with
+//! sorted runs written to unlinked temporary files
+use crate::plan::PlannedFile;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A candidate, with the time it's ordered by
+#[derive(Clone)]
+pub struct SpilledFile {
+    pub key: SystemTime,
+    pub file: PlannedFile,
+}
+
+impl SpilledFile {
+    /// Roughly what it takes up in memory
+    fn footprint(&self) -> usize {
+        std::mem::size_of::<SpilledFile>() + self.file.path.as_os_str().len()
+    }
+}
+
+pub fn time_to_parts(t: SystemTime) -> (i64, u32) {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            // Before the epoch: whole seconds back, then nanoseconds forward
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                nanos => (-(d.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+
+pub fn time_from_parts(secs: i64, nanos: u32) -> SystemTime {
+    let t = match secs >= 0 {
+        true => UNIX_EPOCH + Duration::from_secs(secs as u64),
+        false => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+    };
+    t + Duration::from_nanos(nanos as u64)
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &std::path::Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &std::path::Path) -> Vec<u8> {
+    path.to_string_lossy().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn write_record(out: &mut impl Write, record: &SpilledFile) -> io::Result<()> {
+    let (key_secs, key_nanos) = time_to_parts(record.key);
+    let (secs, nanos) = time_to_parts(record.file.modified);
+    let (dev, ino) = record.file.inode.unwrap_or((0, 0));
+    let path = path_to_bytes(&record.file.path);
+    out.write_all(&key_secs.to_le_bytes())?;
+    out.write_all(&key_nanos.to_le_bytes())?;
+    out.write_all(&secs.to_le_bytes())?;
+    out.write_all(&nanos.to_le_bytes())?;
+    out.write_all(&record.file.size.to_le_bytes())?;
+    out.write_all(&[record.file.inode.is_some() as u8])?;
+    out.write_all(&dev.to_le_bytes())?;
+    out.write_all(&ino.to_le_bytes())?;
+    out.write_all(&(path.len() as u64).to_le_bytes())?;
+    out.write_all(&path)
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// The next record of a run, or nothing at its end
+fn read_record(input: &mut impl Read) -> io::Result<Option<SpilledFile>> {
+    let key_secs = match read_u64(input) {
+        Ok(secs) => secs as i64,
+        Err(why) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(why) => return Err(why),
+    };
+    let key_nanos = read_u32(input)?;
+    let secs = read_u64(input)? as i64;
+    let nanos = read_u32(input)?;
+    let size = read_u64(input)?;
+    let mut has_inode = [0; 1];
+    input.read_exact(&mut has_inode)?;
+    let (dev, ino) = (read_u64(input)?, read_u64(input)?);
+    let mut path = vec![0; read_u64(input)? as usize];
+    input.read_exact(&mut path)?;
+    Ok(Some(SpilledFile {
+        key: time_from_parts(key_secs, key_nanos),
+        file: PlannedFile {
+            path: path_from_bytes(path),
+            size,
+            modified: time_from_parts(secs, nanos),
+            inode: (has_inode[0] != 0).then_some((dev, ino)),
+        },
+    }))
+}
+
+/// Runs of the same length are merged into one once there are this many, so that few stay
+/// open even with a small budget on a big tree
+const FAN_IN: usize = 16;
+/// Most runs open at once. With more, they are all merged into one.
+const MAX_OPEN_RUNS: usize = 64;
+
+fn temporary_file(n: usize) -> io::Result<File> {
+    let path = env::temp_dir().join(format!("dirrotate-spill-{}-{}", process::id(), n));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    // It stays around while open. Unlinking it right away means it never outlives the run.
+    let _ = fs::remove_file(&path);
+    Ok(file)
+}
+
+/// The records of sorted runs, merged into one sorted sequence
+struct Merge<'a> {
+    readers: Vec<BufReader<&'a File>>,
+    heads: Vec<Option<SpilledFile>>,
+    heap: BinaryHeap<Reverse<(SystemTime, usize)>>,
+    failed: bool,
+}
+
+impl<'a> Merge<'a> {
+    fn new(runs: &'a mut [File]) -> io::Result<Merge<'a>> {
+        for run in runs.iter_mut() {
+            run.seek(SeekFrom::Start(0))?;
+        }
+        let runs: &'a [File] = runs;
+        let mut merge = Merge {
+            readers: runs.iter().map(BufReader::new).collect(),
+            heads: Vec::new(),
+            heap: BinaryHeap::new(),
+            failed: false,
+        };
+        for (i, reader) in merge.readers.iter_mut().enumerate() {
+            let head = read_record(reader)?;
+            if let Some(head) = &head {
+                merge.heap.push(Reverse((head.key, i)));
+            }
+            merge.heads.push(head);
+        }
+        Ok(merge)
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<SpilledFile>;
+
+    fn next(&mut self) -> Option<io::Result<SpilledFile>> {
+        if self.failed {
+            return None;
+        }
+        let Reverse((_, i)) = self.heap.pop()?;
+        match read_record(&mut self.readers[i]) {
+            Ok(next) => {
+                if let Some(next) = &next {
+                    self.heap.push(Reverse((next.key, i)));
+                }
+                std::mem::replace(&mut self.heads[i], next).map(Ok)
+            }
+            Err(why) => {
+                self.failed = true;
+                Some(Err(why))
+            }
+        }
+    }
+}
+
+/// Collects candidates, writing them out in sorted runs when they take more than the budget
+pub struct Spill {
+    budget: usize,
+    buffer: Vec<SpilledFile>,
+    buffered: usize,
+    /// Sorted runs and how often they were merged, longest first
+    runs: Vec<(File, usize)>,
+    /// Temporary files created so far, for their names
+    created: usize,
+}
+
+impl Spill {
+    pub fn new(budget: u64) -> Spill {
+        Spill {
+            budget: budget as usize,
+            buffer: Vec::new(),
+            buffered: 0,
+            runs: Vec::new(),
+            created: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: SpilledFile) -> io::Result<()> {
+        self.buffered += record.footprint();
+        self.buffer.push(record);
+        match self.buffered > self.budget {
+            true => self.spill(),
+            false => Ok(()),
+        }
+    }
+
+    fn new_run(&mut self) -> io::Result<BufWriter<File>> {
+        self.created += 1;
+        Ok(BufWriter::new(temporary_file(self.created)?))
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        self.buffer.sort_by_key(|record| record.key);
+        let mut file = self.new_run()?;
+        for record in self.buffer.drain(..) {
+            write_record(&mut file, &record)?;
+        }
+        self.runs
+            .push((file.into_inner().map_err(|e| e.into_error())?, 0));
+        self.buffer.shrink_to_fit();
+        self.buffered = 0;
+        // Like carrying when counting up, at most FAN_IN - 1 runs of each length are left
+        while let Some(&(_, merges)) = self.runs.last() {
+            let same = (self.runs.iter().rev())
+                .take_while(|(_, m)| *m == merges)
+                .count();
+            if same < FAN_IN {
+                break;
+            }
+            self.merge_from(self.runs.len() - FAN_IN, merges + 1)?;
+        }
+        if self.runs.len() >= MAX_OPEN_RUNS {
+            let merges = self.runs[0].1 + 1;
+            self.merge_from(0, merges)?;
+        }
+        Ok(())
+    }
+
+    /// Merge the runs from `start` on into one
+    fn merge_from(&mut self, start: usize, merges: usize) -> io::Result<()> {
+        let mut runs: Vec<File> = (self.runs.split_off(start).into_iter())
+            .map(|(file, _)| file)
+            .collect();
+        let mut file = self.new_run()?;
+        for record in Merge::new(&mut runs)? {
+            write_record(&mut file, &record?)?;
+        }
+        self.runs
+            .push((file.into_inner().map_err(|e| e.into_error())?, merges));
+        Ok(())
+    }
+
+    /// All candidates, ready to be read oldest first
+    pub fn finish(mut self) -> io::Result<Sorted> {
+        if !self.runs.is_empty() && !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        self.buffer.sort_by_key(|record| record.key);
+        Ok(Sorted {
+            memory: self.buffer,
+            runs: self.runs.into_iter().map(|(file, _)| file).collect(),
+        })
+    }
+
+    /// How many sorted runs went to disk
+    pub fn spilled_runs(&self) -> usize {
+        self.runs.len()
+    }
+}
+
+/// The sorted candidates, in memory or in runs on disk
+pub struct Sorted {
+    memory: Vec<SpilledFile>,
+    runs: Vec<File>,
+}
+
+impl Sorted {
+    /// The candidates, oldest first. Each call starts over.
+    pub fn iter(&mut self) -> io::Result<Box<dyn Iterator<Item = io::Result<SpilledFile>> + '_>> {
+        if self.runs.is_empty() {
+            return Ok(Box::new(self.memory.iter().cloned().map(Ok)));
+        }
+        Ok(Box::new(Merge::new(&mut self.runs)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: SystemTime, name: &str) -> SpilledFile {
+        SpilledFile {
+            key,
+            file: PlannedFile {
+                path: PathBuf::from(format!("/data/{}", name)),
+                size: 1000,
+                modified: key + Duration::from_nanos(1),
+                inode: Some((2049, 1234)),
+            },
+        }
+    }
+
+    #[test]
+    fn splits_times_around_the_epoch() {
+        for t in [
+            UNIX_EPOCH,
+            UNIX_EPOCH + Duration::new(1_791_979_200, 123_456_789),
+            UNIX_EPOCH - Duration::new(5, 0),
+            UNIX_EPOCH - Duration::new(5, 250_000_000),
+        ] {
+            let (secs, nanos) = time_to_parts(t);
+            assert!(nanos < 1_000_000_000);
+            assert_eq!(time_from_parts(secs, nanos), t);
+        }
+        assert_eq!(
+            time_to_parts(UNIX_EPOCH - Duration::new(5, 250_000_000)),
+            (-6, 750_000_000)
+        );
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let mut original = record(UNIX_EPOCH + Duration::new(1000, 5), "run [1] é.bin");
+        let mut bytes = Vec::new();
+        write_record(&mut bytes, &original).unwrap();
+        original.file.inode = None;
+        write_record(&mut bytes, &original).unwrap();
+
+        let mut input = &bytes[..];
+        let first = read_record(&mut input).unwrap().unwrap();
+        let second = read_record(&mut input).unwrap().unwrap();
+        assert!(read_record(&mut input).unwrap().is_none());
+        for read in [&first, &second] {
+            assert_eq!(read.key, original.key);
+            assert_eq!(read.file.path, original.file.path);
+            assert_eq!(read.file.size, original.file.size);
+            assert_eq!(read.file.modified, original.file.modified);
+        }
+        assert_eq!(first.file.inode, Some((2049, 1234)));
+        assert_eq!(second.file.inode, None);
+    }
+
+    fn keys(sorted: &mut Sorted) -> Vec<u64> {
+        (sorted.iter().unwrap())
+            .map(|r| r.unwrap().key.duration_since(UNIX_EPOCH).unwrap().as_secs())
+            .collect()
+    }
+
+    #[test]
+    fn merges_spilled_runs_oldest_first() {
+        // A budget this small spills every few records
+        let mut spill = Spill::new(500);
+        for key in (0..100).map(|i| (i * 37) % 100) {
+            let key = UNIX_EPOCH + Duration::from_secs(key);
+            spill.push(record(key, "a")).unwrap();
+        }
+        assert!(spill.spilled_runs() > 0);
+        let mut sorted = spill.finish().unwrap();
+        for _ in 0..2 {
+            assert_eq!(keys(&mut sorted), (0..100).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn keeps_few_runs_open() {
+        // Every record is a run of its own
+        let mut spill = Spill::new(0);
+        for key in (0..5000).map(|i| (i * 7919) % 5000) {
+            let key = UNIX_EPOCH + Duration::from_secs(key);
+            spill.push(record(key, "a")).unwrap();
+            assert!(spill.spilled_runs() < MAX_OPEN_RUNS);
+        }
+        let mut sorted = spill.finish().unwrap();
+        assert_eq!(keys(&mut sorted), (0..5000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sorts_in_memory_within_the_budget() {
+        let mut spill = Spill::new(1 << 20);
+        for key in [3, 1, 2] {
+            spill
+                .push(record(UNIX_EPOCH + Duration::from_secs(key), "a"))
+                .unwrap();
+        }
+        assert_eq!(spill.spilled_runs(), 0);
+        assert_eq!(keys(&mut spill.finish().unwrap()), [1, 2, 3]);
+    }
+}
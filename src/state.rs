@@ -0,0 +1,146 @@
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HEADER: &str = "dirrotate-state 1";
+
+/// Total size of the files directly inside a directory (not in its subdirectories).
+/// It stays valid for as long as the directory's modification time is unchanged.
+struct DirState {
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Directory sizes saved between runs, so that files in unchanged directories
+/// don't have to be stat'ed again
+#[derive(Default)]
+pub struct SizeState {
+    dirs: HashMap<PathBuf, DirState>,
+}
+
+pub fn parse_time(s: &str) -> Option<SystemTime> {
+    let (secs, nanos) = s.split_once('.')?;
+    Some(UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?))
+}
+
+pub fn format_time(t: SystemTime) -> String {
+    let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.{:09}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    )
+}
+
+impl SizeState {
+    /// Load the state file. The fingerprint describes the settings that affect which files
+    /// are counted; a state saved with other settings is discarded.
+    pub fn load(path: &Path, fingerprint: &str) -> SizeState {
+        let mut state = SizeState::default();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(why) => {
+                info!(
+                    "No usable state in {} ({}), doing a full scan",
+                    path.display(),
+                    why
+                );
+                return state;
+            }
+        };
+        let mut lines = content.lines();
+        if lines.next() != Some(HEADER) || lines.next() != Some(fingerprint) {
+            info!("State in {} is outdated, doing a full scan", path.display());
+            return state;
+        }
+        for line in lines {
+            let mut fields = line.splitn(3, '\t');
+            let parsed = (|| {
+                let modified = parse_time(fields.next()?)?;
+                let size = fields.next()?.parse().ok()?;
+                let dir = PathBuf::from(fields.next()?);
+                Some((dir, DirState { modified, size }))
+            })();
+            match parsed {
+                Some((dir, dir_state)) => {
+                    state.dirs.insert(dir, dir_state);
+                }
+                None => warn!("Ignoring malformed line in state file: {}", line),
+            }
+        }
+        state
+    }
+
+    pub fn save(&self, path: &Path, fingerprint: &str) {
+        // Write to a temporary file and rename, so that a crash never leaves a truncated state
+        let mut content = format!("{}\n{}\n", HEADER, fingerprint);
+        for (dir, dir_state) in &self.dirs {
+            // Directories that can't be represented are simply rescanned next time
+            match dir.to_str() {
+                Some(dir) if !dir.contains('\n') => content.push_str(&format!(
+                    "{}\t{}\t{}\n",
+                    format_time(dir_state.modified),
+                    dir_state.size,
+                    dir
+                )),
+                _ => debug!("Not saving state for {}", dir.display()),
+            }
+        }
+        let tmp_path = path.with_extension("tmp");
+        let result = fs::File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(why) = result {
+            warn!("Could not save state to {}: {}", path.display(), why);
+        }
+    }
+
+    /// The saved size of a directory, if it hasn't been modified since
+    pub fn cached_size(&self, dir: &Path, modified: SystemTime) -> Option<u64> {
+        self.dirs
+            .get(dir)
+            .filter(|dir_state| dir_state.modified == modified)
+            .map(|dir_state| dir_state.size)
+    }
+
+    pub fn insert(&mut self, dir: PathBuf, modified: SystemTime, size: u64) {
+        self.dirs.insert(dir, DirState { modified, size });
+    }
+
+    pub fn add_file(&mut self, dir: &Path, size: u64) {
+        if let Some(dir_state) = self.dirs.get_mut(dir) {
+            dir_state.size += size;
+        }
+    }
+
+    /// Journal a deletion we made ourselves, so that it doesn't invalidate the state of
+    /// the parent directory
+    pub fn record_deletion(&mut self, path: &Path, size: u64) {
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => return,
+        };
+        if let Some(dir_state) = self.dirs.get_mut(parent) {
+            match fs::metadata(parent).and_then(|m| m.modified()) {
+                Ok(modified) => {
+                    dir_state.modified = modified;
+                    dir_state.size = dir_state.size.saturating_sub(size);
+                }
+                Err(_) => {
+                    self.dirs.remove(parent);
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.dirs.values().map(|dir_state| dir_state.size).sum()
+    }
+}
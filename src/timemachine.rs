@@ -0,0 +1,31 @@
+use std::path::Path;
+
+/// Whether Time Machine is told to leave the file (or directory) out of backups,
+/// e.g. with `tmutil addexclusion`. Always false outside macOS.
+#[cfg(target_os = "macos")]
+pub fn is_excluded(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let name = b"com.apple.metadata:com_apple_backup_excludeItem\0";
+    // Only whether the attribute is there matters, not its value
+    let size = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr() as *const libc::c_char,
+            std::ptr::null_mut(),
+            0,
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+    size >= 0
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn is_excluded(_path: &Path) -> bool {
+    false
+}
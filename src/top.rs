@@ -0,0 +1,65 @@
+use crate::groups::{format_age, stem_group, Total};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+fn print_largest(title: &str, base_dir: &Path, totals: HashMap<PathBuf, Total>, n: usize) {
+    let mut totals: Vec<(PathBuf, Total)> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.size.cmp(&a.1.size).then_with(|| a.0.cmp(&b.0)));
+    println!("{}", title);
+    println!("  size        files    oldest  newest  path");
+    for (path, total) in totals.iter().take(n) {
+        println!(
+            "  {:<10}  {:<7}  {:<6}  {:<6}  {}",
+            total.size,
+            total.files,
+            format_age(total.oldest),
+            format_age(total.newest),
+            path.strip_prefix(base_dir).unwrap_or(path).display()
+        );
+    }
+}
+
+/// Print the largest files, subdirectories (with everything in them) and groups of files
+/// with the same stem
+pub fn print_top(base_dir: &Path, files: &[(PathBuf, u64, SystemTime)], n: usize) {
+    let mut largest_files: Vec<&(PathBuf, u64, SystemTime)> = files.iter().collect();
+    largest_files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    println!("Largest files");
+    println!("  size        age     path");
+    for (path, size, modified) in largest_files.into_iter().take(n) {
+        println!(
+            "  {:<10}  {:<6}  {}",
+            size,
+            format_age(*modified),
+            path.strip_prefix(base_dir).unwrap_or(path).display()
+        );
+    }
+
+    let mut directories: HashMap<PathBuf, Total> = HashMap::new();
+    let mut groups: HashMap<PathBuf, Total> = HashMap::new();
+    for (path, size, modified) in files {
+        for dir in path.ancestors().skip(1) {
+            if dir == base_dir || !dir.starts_with(base_dir) {
+                break;
+            }
+            directories
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| Total::new(*modified))
+                .add(*size, *modified);
+        }
+        groups
+            .entry(stem_group(path))
+            .or_insert_with(|| Total::new(*modified))
+            .add(*size, *modified);
+    }
+    println!();
+    print_largest("Largest subdirectories", base_dir, directories, n);
+    println!();
+    print_largest(
+        "Largest groups (files with the same stem)",
+        base_dir,
+        groups,
+        n,
+    );
+}
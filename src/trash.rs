@@ -0,0 +1,199 @@
+use crate::clock;
+use crate::dirfd::FileIdentity;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The user's trash, to move files to instead of deleting them. Only files on the same
+/// filesystem as the trash can be moved there; others fail and are kept.
+pub struct Trash {
+    files: PathBuf,
+    /// Where the freedesktop.org trash keeps the original path of each file
+    info: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+fn home() -> io::Result<PathBuf> {
+    env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))
+}
+
+fn numbered(name: &OsString, n: usize) -> OsString {
+    // The first try keeps the name as is
+    let mut numbered = name.clone();
+    if n > 1 {
+        numbered.push(format!(".{}", n));
+    }
+    numbered
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn local_time(t: std::time::SystemTime) -> String {
+    // The freedesktop.org spec wants local time, without a timezone
+    let secs = t
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return humantime::format_rfc3339_seconds(t)
+            .to_string()
+            .trim_end_matches('Z')
+            .to_string();
+    }
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn percent_encode(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut encoded = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn trash_info(path: &Path) -> String {
+    format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        percent_encode(path),
+        local_time(clock::now())
+    )
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn trash_info(path: &Path) -> String {
+    format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        path.display(),
+        humantime::format_rfc3339_seconds(clock::now())
+    )
+}
+
+impl Trash {
+    /// The trash in the home directory: ~/.Trash on macOS, and the freedesktop.org
+    /// trash ($XDG_DATA_HOME/Trash) elsewhere
+    pub fn open() -> io::Result<Trash> {
+        #[cfg(target_os = "macos")]
+        {
+            let files = home()?.join(".Trash");
+            fs::create_dir_all(&files)?;
+            Ok(Trash { files, info: None })
+        }
+        #[cfg(all(unix, not(target_os = "macos")))]
+        {
+            let data_home = match env::var_os("XDG_DATA_HOME") {
+                Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+                _ => home()?.join(".local/share"),
+            };
+            let trash = data_home.join("Trash");
+            let (files, info) = (trash.join("files"), trash.join("info"));
+            fs::create_dir_all(&files)?;
+            fs::create_dir_all(&info)?;
+            Ok(Trash {
+                files,
+                info: Some(info),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "No trash support on this platform",
+            ))
+        }
+    }
+
+    /// The directories the trash writes to
+    pub fn dirs(&self) -> Vec<&Path> {
+        std::iter::once(&self.files)
+            .chain(&self.info)
+            .map(PathBuf::as_path)
+            .collect()
+    }
+
+    /// Move the file to the trash, unless it is no longer the one identified at scan time.
+    /// Returns where it ended up, or nothing if it changed.
+    pub fn put(&self, path: &Path, expected: &FileIdentity) -> io::Result<Option<PathBuf>> {
+        if &FileIdentity::of(&fs::symlink_metadata(path)?) != expected {
+            return Ok(None);
+        }
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No file name"))?
+            .to_os_string();
+        for n in 1.. {
+            let name = numbered(&name, n);
+            let target = self.files.join(&name);
+            // Claim the name with the info file first, as the spec says
+            let info_file = match &self.info {
+                Some(info) => {
+                    let mut info_name = name.clone();
+                    info_name.push(".trashinfo");
+                    let info_file = info.join(info_name);
+                    match OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&info_file)
+                    {
+                        Ok(file) => Some((info_file, file)),
+                        Err(why) if why.kind() == io::ErrorKind::AlreadyExists => continue,
+                        Err(why) => return Err(why),
+                    }
+                }
+                None => None,
+            };
+            if target.symlink_metadata().is_ok() {
+                if let Some((info_file, _)) = info_file {
+                    let _ = fs::remove_file(info_file);
+                }
+                continue;
+            }
+            if let Some((info_file, mut file)) = info_file {
+                let written = file.write_all(trash_info(path).as_bytes());
+                if let Err(why) = written.and_then(|_| fs::rename(path, &target)) {
+                    let _ = fs::remove_file(info_file);
+                    return Err(why);
+                }
+            } else {
+                fs::rename(path, &target)?;
+            }
+            return Ok(Some(target));
+        }
+        unreachable!("Ran out of names in the trash")
+    }
+
+    /// Move a file that `put` moved to the trash back to where it was
+    pub fn restore(&self, trashed: &Path, original: &Path) -> io::Result<()> {
+        if original.symlink_metadata().is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Another file took its place",
+            ));
+        }
+        fs::rename(trashed, original)?;
+        if let (Some(info), Some(name)) = (&self.info, trashed.file_name()) {
+            let mut info_name = name.to_os_string();
+            info_name.push(".trashinfo");
+            let _ = fs::remove_file(info.join(info_name));
+        }
+        Ok(())
+    }
+}
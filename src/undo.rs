@@ -0,0 +1,147 @@
+//! Putting back what the last run moved to the trash (dirrotate undo-last). With --trash,
+//! each run leaves a manifest of where its files went in the rotated directory.
+use crate::sha256::sha256_file;
+use crate::state::{format_time, parse_time};
+use crate::trash::Trash;
+use log::{info, warn};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const HEADER: &str = "dirrotate-manifest 1";
+
+/// The manifest of the last run, hidden so that rotation leaves it alone
+pub fn manifest_path(base_dir: &Path) -> PathBuf {
+    base_dir.join(".dirrotate-last-run")
+}
+
+/// A file in the trash, and where it came from
+pub struct Trashed {
+    pub trashed: PathBuf,
+    pub original: PathBuf,
+    /// Of the file before it was moved, with --checksum
+    pub sha256: Option<String>,
+}
+
+/// Replace the manifest with the files of this run
+pub fn save_manifest(path: &Path, time: SystemTime, files: &[Trashed]) {
+    let mut content = format!("{}\n{}\n", HEADER, format_time(time));
+    for file in files {
+        match (file.trashed.to_str(), file.original.to_str()) {
+            (Some(trashed), Some(original))
+                if !format!("{}{}", trashed, original).contains(['\t', '\n']) =>
+            {
+                content.push_str(&format!(
+                    "{}\t{}\t{}\n",
+                    trashed,
+                    original,
+                    file.sha256.as_deref().unwrap_or("-")
+                ))
+            }
+            _ => warn!(
+                "Not recording {} in the manifest, undo-last will not restore it",
+                file.original.display()
+            ),
+        }
+    }
+    let tmp_path = path.with_extension("tmp");
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(content.as_bytes()))
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if let Err(why) = result {
+        warn!("Could not save the manifest to {}: {}", path.display(), why);
+    }
+}
+
+fn load_manifest(path: &Path) -> Result<(SystemTime, Vec<Trashed>), String> {
+    let content = fs::read_to_string(path).map_err(|why| why.to_string())?;
+    let mut lines = content.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(String::from("Not a manifest"));
+    }
+    let time = lines
+        .next()
+        .and_then(parse_time)
+        .ok_or("No time of the run")?;
+    let files = lines
+        .map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let parsed = (|| {
+                Some(Trashed {
+                    trashed: PathBuf::from(fields.next()?),
+                    original: PathBuf::from(fields.next()?),
+                    sha256: match fields.next()? {
+                        "-" => None,
+                        sha256 => Some(sha256.to_string()),
+                    },
+                })
+            })();
+            parsed.ok_or_else(|| format!("Malformed line: {}", line))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((time, files))
+}
+
+/// Put the files of the last run back where they were. Files whose checksum doesn't match
+/// stay in the trash. Returns whether all files were put back.
+pub fn undo_last(base_dir: &Path, dryrun: bool) -> bool {
+    let path = manifest_path(base_dir);
+    let (time, files) = match load_manifest(&path) {
+        Ok(manifest) => manifest,
+        Err(why) => {
+            warn!("Nothing to undo in {}: {}", path.display(), why);
+            return false;
+        }
+    };
+    info!(
+        "Restoring {} files from the run at {}",
+        files.len(),
+        humantime::format_rfc3339_seconds(time)
+    );
+    let trash = Trash::open().expect("Could not open the trash");
+    let mut left = Vec::new();
+    for file in files {
+        let verified = match &file.sha256 {
+            Some(expected) => sha256_file(&file.trashed)
+                .map_err(|why| why.to_string())
+                .and_then(|actual| match &actual == expected {
+                    true => Ok(()),
+                    false => Err(String::from("Its checksum does not match")),
+                }),
+            None => Ok(()),
+        };
+        let restored = match verified {
+            Ok(()) if dryrun => {
+                info!("Restore file: {}", file.original.display());
+                continue;
+            }
+            Ok(()) => trash
+                .restore(&file.trashed, &file.original)
+                .map_err(|why| why.to_string()),
+            Err(why) => Err(why),
+        };
+        match restored {
+            Ok(()) => info!("Restored file: {}", file.original.display()),
+            Err(why) => {
+                warn!(
+                    "Could not restore file: {} ({})",
+                    file.original.display(),
+                    why
+                );
+                left.push(file);
+            }
+        }
+    }
+    if dryrun {
+        return true;
+    }
+    // What couldn't be put back can be tried again
+    match left.is_empty() {
+        true => {
+            let _ = fs::remove_file(&path);
+        }
+        false => save_manifest(&path, time, &left),
+    }
+    left.is_empty()
+}
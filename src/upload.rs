@@ -0,0 +1,770 @@
+use crate::extents::data_segments;
+use crate::sha256::{sha256_file, Sha256};
+use crate::verbatim::for_programs;
+use log::{debug, warn};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Somewhere to copy files to before they are deleted locally
+pub trait Backend: Send + Sync {
+    /// Copy the file to `key` (a relative, '/'-separated path) and verify the copy.
+    /// The local file is only deleted if this succeeds.
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()>;
+
+    /// Where `key` ends up, for logging
+    fn destination(&self, key: &str) -> String;
+}
+
+fn run(command: &mut Command) -> io::Result<String> {
+    // Runs a command, turning a non-zero exit into an error. Returns its output.
+    debug!("Running {:?}", command);
+    let output = command.stdin(Stdio::null()).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(io::Error::other(format!(
+            "{:?} failed ({}): {}",
+            command.get_program(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Sleeps whenever a copy gets ahead of its limit
+struct Throttle {
+    bytes_per_sec: u64,
+    start: Instant,
+    copied: u64,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            start: Instant::now(),
+            copied: 0,
+        }
+    }
+
+    fn copied(&mut self, bytes: usize) {
+        self.copied += bytes as u64;
+        let due = Duration::from_secs_f64(self.copied as f64 / self.bytes_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+fn run_throttled(command: &mut Command, path: &Path, bytes_per_sec: u64) -> io::Result<()> {
+    // Runs a command with the file on its stdin, fed at the limit
+    debug!("Running {:?} < {}", command, path.display());
+    let mut file = File::open(path)?;
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("The command has no stdin");
+    let mut throttle = Throttle::new(bytes_per_sec);
+    let mut buf = vec![0; 64 * 1024];
+    let fed = loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(why) => break Err(why),
+        };
+        if let Err(why) = stdin.write_all(&buf[..n]) {
+            break Err(why);
+        }
+        throttle.copied(n);
+    };
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        // Its error says more than the broken pipe
+        return Err(io::Error::other(format!(
+            "{:?} failed ({}): {}",
+            command.get_program(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    fed
+}
+
+fn verify_size(path: &Path, remote: &str, remote_size: &str) -> io::Result<()> {
+    let local_size = fs::metadata(path)?.len();
+    match remote_size.trim().parse::<u64>() {
+        Ok(size) if size == local_size => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} has size {}, expected {}",
+                remote,
+                remote_size.trim(),
+                local_size
+            ),
+        )),
+    }
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}/{}", prefix.trim_end_matches('/'), key)
+    }
+}
+
+/// A directory, e.g. a mounted NAS share. The copy is compared byte for byte. Files are
+/// copied to a hidden partial file first, which an interrupted copy leaves behind for the next
+/// run to resume from. Holes in sparse files stay holes in the copy.
+struct LocalBackend {
+    dir: PathBuf,
+    /// In bytes per second
+    bandwidth_limit: Option<u64>,
+}
+
+fn partial_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(".{}.partial", name))
+}
+
+fn is_sparse(file: &File) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = file.metadata()?;
+        Ok(metadata.blocks() * 512 < metadata.len())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        Ok(false)
+    }
+}
+
+fn resumed_copy(from: &Path, to: &Path, bytes_per_sec: Option<u64>) -> io::Result<()> {
+    // Appends to what an earlier copy got through, copying only the data of sparse files.
+    // Sleeps whenever the copy gets ahead of the limit.
+    let mut reader = File::open(from)?;
+    let len = reader.metadata()?.len();
+    let done = match fs::metadata(to) {
+        Ok(partial) if partial.len() <= len => partial.len(),
+        _ => 0,
+    };
+    if done == 0 && bytes_per_sec.is_none() && !is_sparse(&reader)? {
+        fs::copy(from, to)?;
+        return Ok(());
+    }
+    let mut writer = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(to)?;
+    writer.set_len(done)?;
+    if done > 0 {
+        debug!("Resuming the copy to {} at {} bytes", to.display(), done);
+    }
+    let mut buf = vec![0; 64 * 1024];
+    let mut throttle = bytes_per_sec.map(Throttle::new);
+    for (offset, length) in data_segments(&reader, done, len)? {
+        reader.seek(SeekFrom::Start(offset))?;
+        writer.seek(SeekFrom::Start(offset))?;
+        let mut segment = (&mut reader).take(length);
+        loop {
+            let n = segment.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buf[..n])?;
+            if let Some(throttle) = &mut throttle {
+                throttle.copied(n);
+            }
+        }
+    }
+    // A hole at the end
+    writer.set_len(len)?;
+    writer.set_permissions(reader.metadata()?.permissions())
+}
+
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    same_stream(File::open(a)?, File::open(b)?)
+}
+
+fn same_stream(a: impl Read, b: impl Read) -> io::Result<bool> {
+    let (mut a, mut b) = (BufReader::new(a), BufReader::new(b));
+    let (mut buf_a, mut buf_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(b.read(&mut buf_b)? == 0);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+impl Backend for LocalBackend {
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()> {
+        let target = self.dir.join(key);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = partial_path(&target);
+        resumed_copy(path, &partial, self.bandwidth_limit)?;
+        if same_content(path, &partial)? {
+            fs::rename(&partial, &target)
+        } else {
+            // Start over on the next run
+            let _ = fs::remove_file(&partial);
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} differs from the original", target.display()),
+            ))
+        }
+    }
+
+    fn destination(&self, key: &str) -> String {
+        self.dir.join(key).display().to_string()
+    }
+}
+
+/// A directory that holds the file under `key`, for the tools that name what they copy after
+/// its local path. That's the base if the file is there under its key, e.g. unless it was
+/// claimed (--claim-suffix). Otherwise it's a hidden directory next to the file with a hard
+/// link to it, which takes no space and is removed again when this is dropped.
+struct KeyRoot {
+    dir: PathBuf,
+    staged: bool,
+}
+
+impl KeyRoot {
+    fn new(path: &Path, key: &str) -> io::Result<KeyRoot> {
+        static STAGED: AtomicUsize = AtomicUsize::new(0);
+        if path.ends_with(key) {
+            let dir = path
+                .ancestors()
+                .nth(key.split('/').count())
+                .unwrap_or_else(|| Path::new("/"));
+            return Ok(KeyRoot {
+                dir: dir.to_path_buf(),
+                staged: false,
+            });
+        }
+        let n = STAGED.fetch_add(1, Ordering::Relaxed);
+        let root = KeyRoot {
+            dir: path.with_file_name(format!(".dirrotate-staging.{}.{}", process::id(), n)),
+            staged: true,
+        };
+        let link = root.dir.join(key);
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::hard_link(path, &link)?;
+        Ok(root)
+    }
+}
+
+impl Drop for KeyRoot {
+    fn drop(&mut self) {
+        if self.staged {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// A zip archive through the zip CLI, e.g. for consumers on Windows. Files are added under
+/// their key, and the copy in the archive is compared byte for byte. Files that are already
+/// compressed are stored as they are.
+struct ZipBackend {
+    archive: PathBuf,
+}
+
+/// Escapes the wildcards of unzip's file name patterns
+fn zip_pattern(key: &str) -> String {
+    let mut pattern = String::with_capacity(key.len());
+    for c in key.chars() {
+        match c {
+            '*' | '?' | '[' => pattern.push_str(&format!("[{}]", c)),
+            c => pattern.push(c),
+        }
+    }
+    pattern
+}
+
+impl Backend for ZipBackend {
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()> {
+        // Run from where the file is under its key, so that the entry is named after it
+        let root = KeyRoot::new(path, key)?;
+        run(Command::new("zip")
+            .args([
+                "-q",
+                "-X",
+                "-n",
+                ".gz:.bz2:.xz:.zst:.zip:.7z:.jpg:.png:.mp4",
+            ])
+            .arg(for_programs(&self.archive))
+            .arg(key)
+            .current_dir(for_programs(&root.dir)))?;
+        let mut extract = Command::new("unzip")
+            .arg("-p")
+            .arg(for_programs(&self.archive))
+            .arg(zip_pattern(key))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let same = same_stream(
+            File::open(path)?,
+            extract.stdout.take().expect("unzip has no stdout"),
+        );
+        let status = extract.wait()?;
+        match (same, status.success()) {
+            (Ok(true), true) => Ok(()),
+            (Err(why), _) => Err(why),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} differs from the original", self.destination(key)),
+            )),
+        }
+    }
+
+    fn destination(&self, key: &str) -> String {
+        format!("{}:{}", self.archive.display(), key)
+    }
+}
+
+/// S3 through the aws CLI, which verifies the MD5 of each uploaded part.
+/// The size of the stored object is checked on top of that. With a bandwidth limit, the
+/// file is fed to the CLI at that rate.
+struct S3Backend {
+    bucket: String,
+    prefix: String,
+    /// In bytes per second
+    bandwidth_limit: Option<u64>,
+}
+
+impl Backend for S3Backend {
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()> {
+        let key = join(&self.prefix, key);
+        let remote = format!("s3://{}/{}", self.bucket, key);
+        let mut upload = Command::new("aws");
+        upload.args(["s3", "cp", "--only-show-errors"]);
+        match self.bandwidth_limit {
+            Some(limit) => {
+                // Large streams need their size up front, to be split into parts right
+                let size = fs::metadata(path)?.len();
+                upload
+                    .arg("--expected-size")
+                    .arg(size.to_string())
+                    .arg("-")
+                    .arg(&remote);
+                run_throttled(&mut upload, path, limit)?;
+            }
+            None => {
+                run(upload.arg(for_programs(path)).arg(&remote))?;
+            }
+        }
+        let remote_size = run(Command::new("aws").args([
+            "s3api",
+            "head-object",
+            "--bucket",
+            &self.bucket,
+            "--key",
+            &key,
+            "--query",
+            "ContentLength",
+            "--output",
+            "text",
+        ]))?;
+        verify_size(path, &remote, &remote_size)
+    }
+
+    fn destination(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, join(&self.prefix, key))
+    }
+}
+
+/// Google Cloud Storage through gsutil. gsutil resumes interrupted uploads of large files
+/// on its own. With a bandwidth limit, the file is fed to gsutil at that rate. Either way,
+/// the MD5 of the stored object is compared to that of the file afterwards, or its CRC32C
+/// for composite objects, which have no MD5.
+struct GcsBackend {
+    url: String,
+    /// In bytes per second
+    bandwidth_limit: Option<u64>,
+}
+
+impl Backend for GcsBackend {
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()> {
+        let remote = self.destination(key);
+        let mut upload = Command::new("gsutil");
+        upload.args(["-q", "cp"]);
+        match self.bandwidth_limit {
+            Some(limit) => run_throttled(upload.arg("-").arg(&remote), path, limit)?,
+            None => {
+                run(upload.arg(for_programs(path)).arg(&remote))?;
+            }
+        }
+        // Both in base64
+        let local = run(Command::new("gsutil").arg("hash").arg(for_programs(path)))?;
+        let stored = run(Command::new("gsutil").arg("stat").arg(&remote))?;
+        let (local_md5, local_crc32c) = gcs_hashes(&local);
+        let hashes = match gcs_hashes(&stored) {
+            (Some(md5), _) => (md5, local_md5),
+            (None, Some(crc32c)) => (crc32c, local_crc32c),
+            (None, None) => ("none", None),
+        };
+        match hashes {
+            (stored, Some(local)) if stored == local => Ok(()),
+            (stored, local) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has hash {}, expected {}",
+                    remote,
+                    stored,
+                    local.unwrap_or("none")
+                ),
+            )),
+        }
+    }
+
+    fn destination(&self, key: &str) -> String {
+        join(&self.url, key)
+    }
+}
+
+/// WebDAV through curl (PUT). The size reported by the server is checked afterwards, and the
+/// copy is downloaded again to compare its SHA-256 to that of the file, as servers have no
+/// checksum they all report. Collections for subdirectories are created as needed (MKCOL).
+struct WebDavBackend {
+    url: String,
+    /// In bytes per second
+    bandwidth_limit: Option<u64>,
+}
+
+/// The MD5 and CRC32C in the output of gsutil hash or gsutil stat
+fn gcs_hashes(output: &str) -> (Option<&str>, Option<&str>) {
+    let hash = |name: &str| {
+        (output.lines())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(field, _)| field.trim() == name)
+            .map(|(_, value)| value.trim())
+    };
+    (hash("Hash (md5)"), hash("Hash (crc32c)"))
+}
+
+fn http_status(command: &mut Command) -> io::Result<u16> {
+    // Runs curl, returning the status of the response. The status comes after the body.
+    let output = run(command
+        .args(["--silent", "--show-error", "--netrc-optional"])
+        .arg("--write-out")
+        .arg("\n%{http_code}"))?;
+    let status = output.lines().last().unwrap_or_default();
+    status
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::other(format!("Not an HTTP status: {}", status)))
+}
+
+impl WebDavBackend {
+    fn put(&self, path: &Path, remote: &str) -> io::Result<u16> {
+        let mut upload = Command::new("curl");
+        if let Some(limit) = self.bandwidth_limit {
+            upload.arg("--limit-rate").arg(limit.to_string());
+        }
+        http_status(
+            upload
+                .arg("--upload-file")
+                .arg(for_programs(path))
+                .arg(remote),
+        )
+    }
+
+    /// The SHA-256 of what the server has at the URL, as lowercase hex
+    fn sha256(&self, remote: &str) -> io::Result<String> {
+        let mut download = Command::new("curl");
+        if let Some(limit) = self.bandwidth_limit {
+            download.arg("--limit-rate").arg(limit.to_string());
+        }
+        download
+            .args(["--silent", "--show-error", "--fail", "--netrc-optional"])
+            .arg(remote);
+        debug!("Running {:?}", download);
+        let mut child = download
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdout = child.stdout.take().expect("The command has no stdout");
+        let mut hasher = Sha256::default();
+        let mut buf = vec![0; 64 * 1024];
+        let read = loop {
+            match stdout.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => hasher.update(&buf[..n]),
+                Err(why) => break Err(why),
+            }
+        };
+        drop(stdout);
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "GET {} failed ({}): {}",
+                remote,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        read.map(|_| hasher.finish())
+    }
+
+    /// Create the collections that the key is in. Existing ones answer 405.
+    fn make_collections(&self, key: &str) -> io::Result<()> {
+        let dirs: Vec<&str> = key.split('/').collect();
+        for depth in 1..dirs.len() {
+            let collection = format!("{}/", self.destination(&dirs[..depth].join("/")));
+            let status = http_status(
+                Command::new("curl")
+                    .args(["--request", "MKCOL"])
+                    .arg(&collection),
+            )?;
+            if !(200..300).contains(&status) && status != 405 {
+                return Err(io::Error::other(format!(
+                    "MKCOL {} failed ({})",
+                    collection, status
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for WebDavBackend {
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()> {
+        let remote = self.destination(key);
+        let mut status = self.put(path, &remote)?;
+        // Servers don't create missing collections on their own
+        if status == 409 {
+            self.make_collections(key)?;
+            status = self.put(path, &remote)?;
+        }
+        if !(200..300).contains(&status) {
+            return Err(io::Error::other(format!(
+                "PUT {} failed ({})",
+                remote, status
+            )));
+        }
+        let headers = run(Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--netrc-optional"])
+            .arg("--head")
+            .arg(&remote))?;
+        let remote_size = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value)
+            .unwrap_or_default();
+        verify_size(path, &remote, remote_size)?;
+        let (stored, local) = (self.sha256(&remote)?, sha256_file(path)?);
+        if stored == local {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has SHA-256 {}, expected {}", remote, stored, local),
+            ))
+        }
+    }
+
+    fn destination(&self, key: &str) -> String {
+        join(&self.url, key)
+    }
+}
+
+/// A remote host over SSH through rsync. Interrupted transfers are resumed from the
+/// partial file on the next run, rsync verifies each transfer with a whole-file
+/// checksum, and a final checksum comparison confirms the remote copy.
+struct RsyncBackend {
+    /// In rsync syntax: [user@]host:/path
+    target: String,
+    /// In bytes per second
+    bandwidth_limit: Option<u64>,
+}
+
+impl Backend for RsyncBackend {
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()> {
+        // "root/./key" makes rsync recreate the key's directories on the remote
+        let root = KeyRoot::new(path, key)?;
+        let source = for_programs(&root.dir).join(".").join(key);
+        let target = format!("{}/", self.target.trim_end_matches('/'));
+        let rsync = || {
+            let mut command = Command::new("rsync");
+            command.args(["--relative", "--protect-args", "--rsh", "ssh"]);
+            command
+        };
+        let mut transfer = rsync();
+        if let Some(limit) = self.bandwidth_limit {
+            // rsync counts in KiB/s
+            transfer.arg(format!("--bwlimit={}", (limit / 1024).max(1)));
+        }
+        run(transfer
+            .args(["--partial", "--append-verify"])
+            .arg(&source)
+            .arg(&target))?;
+        let differences = run(rsync()
+            .args(["--checksum", "--dry-run", "--itemize-changes"])
+            .arg(&source)
+            .arg(&target))?;
+        if differences.trim().is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} differs from the original", self.destination(key)),
+            ))
+        }
+    }
+
+    fn destination(&self, key: &str) -> String {
+        join(&self.target, key)
+    }
+}
+
+/// Encrypts each file before handing it to another backend, so that only the recipient can
+/// read what is uploaded. The encrypted file is written to the temporary directory, outside
+/// the rotated tree and its full disk, named like the key plus .age or .gpg, and removed
+/// after the upload.
+struct EncryptedBackend {
+    inner: Box<dyn Backend>,
+    /// age or gpg
+    tool: &'static str,
+    recipient: String,
+}
+
+impl Backend for EncryptedBackend {
+    fn upload(&self, path: &Path, key: &str) -> io::Result<()> {
+        static ENCRYPTED: AtomicUsize = AtomicUsize::new(0);
+        let n = ENCRYPTED.fetch_add(1, Ordering::Relaxed);
+        let dir = env::temp_dir().join(format!("dirrotate-encrypt.{}.{}", process::id(), n));
+        let encrypted_key = format!("{}.{}", key, self.tool);
+        // Under its key, so that nothing needs staging for the upload
+        let encrypted = dir.join(&encrypted_key);
+        if let Some(parent) = encrypted.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut command = Command::new(self.tool);
+        match self.tool {
+            "age" => command.arg("--recipient").arg(&self.recipient),
+            _ => command
+                .args(["--batch", "--yes", "--trust-model", "always", "--recipient"])
+                .arg(&self.recipient)
+                .arg("--encrypt"),
+        };
+        let result = run(command
+            .arg("--output")
+            .arg(for_programs(&encrypted))
+            .arg(for_programs(path)))
+        .and_then(|_| self.inner.upload(&encrypted, &encrypted_key));
+        if let Err(why) = fs::remove_dir_all(&dir) {
+            warn!("Could not remove {}: {}", dir.display(), why);
+        }
+        result
+    }
+
+    fn destination(&self, key: &str) -> String {
+        self.inner.destination(&format!("{}.{}", key, self.tool))
+    }
+}
+
+/// The tool and recipient of age:RECIPIENT or gpg:KEY
+pub fn parse_encryption(encryption: &str) -> Result<(&'static str, &str), String> {
+    match encryption.split_once(':') {
+        Some(("age", recipient)) if !recipient.is_empty() => Ok(("age", recipient)),
+        Some(("gpg", recipient)) if !recipient.is_empty() => Ok(("gpg", recipient)),
+        _ => Err(format!(
+            "Expected age:RECIPIENT or gpg:KEY, got {}",
+            encryption
+        )),
+    }
+}
+
+/// Encrypt uploads with age:RECIPIENT or gpg:KEY before they leave
+pub fn get_encrypted_backend(
+    inner: Box<dyn Backend>,
+    encryption: &str,
+) -> Result<Box<dyn Backend>, String> {
+    let (tool, recipient) = parse_encryption(encryption)?;
+    Ok(Box::new(EncryptedBackend {
+        inner,
+        tool,
+        recipient: recipient.to_string(),
+    }))
+}
+
+/// Offload to a remote host over SSH, given as [user@]host:/path.
+/// Each upload is limited to `bandwidth_limit` bytes per second.
+pub fn get_ssh_backend(
+    target: &str,
+    bandwidth_limit: Option<u64>,
+) -> Result<Box<dyn Backend>, String> {
+    if !target.contains(':') {
+        return Err(format!("Expected [user@]host:/path, got {}", target));
+    }
+    Ok(Box::new(RsyncBackend {
+        target: target.to_string(),
+        bandwidth_limit,
+    }))
+}
+
+/// Pick a backend from the URL: s3://bucket/prefix, gs://bucket/prefix,
+/// webdav://host/path (or webdavs:// for https), file:///path or zip:///path/archive.zip.
+/// Each upload is limited to `bandwidth_limit` bytes per second, where supported.
+pub fn get_backend(url: &str, bandwidth_limit: Option<u64>) -> Result<Box<dyn Backend>, String> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("Not a URL: {}", url))?;
+    match scheme {
+        "file" => Ok(Box::new(LocalBackend {
+            dir: PathBuf::from(rest),
+            bandwidth_limit,
+        })),
+        "zip" => {
+            if bandwidth_limit.is_some() {
+                warn!("The bandwidth limit does not apply to zip archives");
+            }
+            Ok(Box::new(ZipBackend {
+                archive: PathBuf::from(rest),
+            }))
+        }
+        "s3" => {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            Ok(Box::new(S3Backend {
+                bucket: bucket.to_string(),
+                prefix: prefix.trim_matches('/').to_string(),
+                bandwidth_limit,
+            }))
+        }
+        "gs" => Ok(Box::new(GcsBackend {
+            url: url.trim_end_matches('/').to_string(),
+            bandwidth_limit,
+        })),
+        "webdav" | "webdavs" => {
+            let http = if scheme == "webdavs" { "https" } else { "http" };
+            Ok(Box::new(WebDavBackend {
+                url: format!("{}://{}", http, rest.trim_end_matches('/')),
+                bandwidth_limit,
+            }))
+        }
+        _ => Err(format!("Unsupported upload target: {}", url)),
+    }
+}
@@ -0,0 +1,374 @@
+//! Batched statx through io_uring, for the metadata of the scan (--io-backend uring).
+//! Only on Linux (with glibc). Elsewhere, or where the kernel refuses a ring, the scan
+//! stats files one by one.
+use crate::record::FileRecord;
+use log::warn;
+use std::path::PathBuf;
+use std::sync::{Once, OnceLock};
+
+static ENABLED: OnceLock<()> = OnceLock::new();
+static SETUP_FAILED: Once = Once::new();
+
+/// How many statx calls go to the kernel at once
+pub const BATCH: usize = 256;
+
+/// Use io_uring for the rest of the run, where it works
+pub fn enable() {
+    let _ = ENABLED.set(());
+}
+
+/// Stats batches of files
+pub struct Batcher {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    ring: ring::Ring,
+}
+
+/// A batcher, if io_uring is enabled and the kernel lets us set up a ring
+pub fn batcher() -> Option<Batcher> {
+    ENABLED.get()?;
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        match ring::Ring::new(BATCH as u32) {
+            Ok(ring) => Some(Batcher { ring }),
+            Err(why) => {
+                SETUP_FAILED.call_once(|| {
+                    warn!(
+                        "Could not set up io_uring, stat'ing files one by one: {}",
+                        why
+                    )
+                });
+                None
+            }
+        }
+    }
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    {
+        SETUP_FAILED.call_once(|| warn!("io_uring is only supported on Linux"));
+        None
+    }
+}
+
+impl Batcher {
+    /// The records of the files, like `symlink_metadata` would give them, or back the path
+    /// of each file that the kernel could not stat
+    pub fn stat(&mut self, paths: Vec<PathBuf>) -> Vec<Result<FileRecord, PathBuf>> {
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        {
+            self.ring.statx_all(paths)
+        }
+        #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+        {
+            paths.into_iter().map(Err).collect()
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod ring {
+    use crate::dirfd::FileIdentity;
+    use crate::record::FileRecord;
+    use std::ffi::CString;
+    use std::io;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::ptr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const IORING_OP_STATX: u8 = 21;
+    const IORING_ENTER_GETEVENTS: u32 = 1;
+    const IORING_OFF_SQ_RING: i64 = 0;
+    const IORING_OFF_CQ_RING: i64 = 0x8000000;
+    const IORING_OFF_SQES: i64 = 0x10000000;
+
+    // The kernel's structures, from linux/io_uring.h
+    #[repr(C)]
+    #[derive(Default)]
+    struct SqringOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        flags: u32,
+        dropped: u32,
+        array: u32,
+        resv1: u32,
+        user_addr: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct CqringOffsets {
+        head: u32,
+        tail: u32,
+        ring_mask: u32,
+        ring_entries: u32,
+        overflow: u32,
+        cqes: u32,
+        flags: u32,
+        resv1: u32,
+        user_addr: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Params {
+        sq_entries: u32,
+        cq_entries: u32,
+        flags: u32,
+        sq_thread_cpu: u32,
+        sq_thread_idle: u32,
+        features: u32,
+        wq_fd: u32,
+        resv: [u32; 3],
+        sq_off: SqringOffsets,
+        cq_off: CqringOffsets,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Sqe {
+        opcode: u8,
+        flags: u8,
+        ioprio: u16,
+        fd: i32,
+        off: u64,
+        addr: u64,
+        len: u32,
+        op_flags: u32,
+        user_data: u64,
+        buf_index: u16,
+        personality: u16,
+        splice_fd_in: i32,
+        addr3: u64,
+        pad: u64,
+    }
+
+    #[repr(C)]
+    struct Cqe {
+        user_data: u64,
+        res: i32,
+        flags: u32,
+    }
+
+    struct Mmap {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    impl Mmap {
+        fn new(fd: i32, len: usize, offset: i64) -> io::Result<Mmap> {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_POPULATE,
+                    fd,
+                    offset,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Mmap {
+                ptr: ptr as *mut u8,
+                len,
+            })
+        }
+
+        fn atomic(&self, offset: u32) -> &AtomicU32 {
+            unsafe { &*(self.ptr.add(offset as usize) as *const AtomicU32) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+        }
+    }
+
+    pub struct Ring {
+        fd: i32,
+        params: Params,
+        sq: Mmap,
+        cq: Mmap,
+        sqes: Mmap,
+    }
+
+    impl Drop for Ring {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+
+    fn to_system_time(secs: i64, nanos: u32) -> SystemTime {
+        let t = match secs >= 0 {
+            true => UNIX_EPOCH + Duration::from_secs(secs as u64),
+            false => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+        };
+        t + Duration::from_nanos(nanos as u64)
+    }
+
+    fn to_record(path: PathBuf, stx: &libc::statx) -> FileRecord {
+        let dev = unsafe { libc::makedev(stx.stx_dev_major, stx.stx_dev_minor) };
+        let mtime = (stx.stx_mtime.tv_sec, stx.stx_mtime.tv_nsec);
+        FileRecord {
+            path,
+            len: stx.stx_size,
+            modified: to_system_time(mtime.0, mtime.1),
+            identity: FileIdentity::from_raw(
+                dev,
+                stx.stx_ino,
+                (mtime.0, mtime.1 as i64),
+                stx.stx_size,
+            ),
+            kept_by: None,
+        }
+    }
+
+    impl Ring {
+        pub fn new(entries: u32) -> io::Result<Ring> {
+            let mut params = Params::default();
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_setup,
+                    entries,
+                    &mut params as *mut Params,
+                )
+            } as i32;
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mapped = (|| {
+                let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+                let cq_len = params.cq_off.cqes as usize
+                    + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+                let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+                Ok((
+                    Mmap::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+                    Mmap::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+                    Mmap::new(fd, sqes_len, IORING_OFF_SQES)?,
+                ))
+            })();
+            match mapped {
+                Ok((sq, cq, sqes)) => Ok(Ring {
+                    fd,
+                    params,
+                    sq,
+                    cq,
+                    sqes,
+                }),
+                Err(why) => {
+                    unsafe { libc::close(fd) };
+                    Err(why)
+                }
+            }
+        }
+
+        fn enter(&self, to_submit: u32, min_complete: u32) -> io::Result<u32> {
+            loop {
+                let res = unsafe {
+                    libc::syscall(
+                        libc::SYS_io_uring_enter,
+                        self.fd,
+                        to_submit,
+                        min_complete,
+                        IORING_ENTER_GETEVENTS,
+                        ptr::null::<libc::sigset_t>(),
+                        0usize,
+                    )
+                };
+                if res >= 0 {
+                    return Ok(res as u32);
+                }
+                let why = io::Error::last_os_error();
+                if why.kind() != io::ErrorKind::Interrupted {
+                    return Err(why);
+                }
+            }
+        }
+
+        /// Take the completions off the ring, into the results by index
+        fn reap(&self, results: &mut [Option<i32>]) -> usize {
+            let off = &self.params.cq_off;
+            let head = self.cq.atomic(off.head);
+            let tail = self.cq.atomic(off.tail).load(Ordering::Acquire);
+            let mask = self.cq.atomic(off.ring_mask).load(Ordering::Relaxed);
+            let mut current = head.load(Ordering::Relaxed);
+            let mut reaped = 0;
+            while current != tail {
+                let cqe = unsafe {
+                    &*(self.cq.ptr.add(off.cqes as usize) as *const Cqe)
+                        .add((current & mask) as usize)
+                };
+                results[cqe.user_data as usize] = Some(cqe.res);
+                current = current.wrapping_add(1);
+                reaped += 1;
+            }
+            head.store(current, Ordering::Release);
+            reaped
+        }
+
+        /// statx each of the files, without following symlinks
+        pub fn statx_all(&mut self, paths: Vec<PathBuf>) -> Vec<Result<FileRecord, PathBuf>> {
+            let names: Vec<Option<CString>> = paths
+                .iter()
+                .map(|path| CString::new(path.as_os_str().as_bytes()).ok())
+                .collect();
+            let mut buffers: Vec<MaybeUninit<libc::statx>> =
+                (0..paths.len()).map(|_| MaybeUninit::zeroed()).collect();
+            let mut results: Vec<Option<i32>> = vec![None; paths.len()];
+            let wanted: Vec<usize> = (0..paths.len()).filter(|&i| names[i].is_some()).collect();
+            for chunk in wanted.chunks(self.params.sq_entries as usize) {
+                let off = &self.params.sq_off;
+                let tail = self.sq.atomic(off.tail);
+                let mask = self.sq.atomic(off.ring_mask).load(Ordering::Relaxed);
+                let mut current = tail.load(Ordering::Relaxed);
+                for &i in chunk {
+                    let index = current & mask;
+                    let name = names[i].as_ref().expect("Unnamed file");
+                    let sqe = Sqe {
+                        opcode: IORING_OP_STATX,
+                        fd: libc::AT_FDCWD,
+                        off: buffers[i].as_mut_ptr() as u64,
+                        addr: name.as_ptr() as u64,
+                        len: libc::STATX_BASIC_STATS,
+                        op_flags: libc::AT_SYMLINK_NOFOLLOW as u32,
+                        user_data: i as u64,
+                        ..Sqe::default()
+                    };
+                    unsafe {
+                        ptr::write((self.sqes.ptr as *mut Sqe).add(index as usize), sqe);
+                        ptr::write(
+                            (self.sq.ptr.add(off.array as usize) as *mut u32).add(index as usize),
+                            index,
+                        );
+                    }
+                    current = current.wrapping_add(1);
+                }
+                tail.store(current, Ordering::Release);
+                let (mut to_submit, mut pending) = (chunk.len() as u32, chunk.len());
+                while pending > 0 {
+                    // The kernel still has the buffers, so there is no giving up on them
+                    let submitted = self.enter(to_submit, 1).expect("io_uring_enter failed");
+                    to_submit = to_submit.saturating_sub(submitted);
+                    pending = pending.saturating_sub(self.reap(&mut results));
+                }
+            }
+            paths
+                .into_iter()
+                .enumerate()
+                .map(|(i, path)| match results[i] {
+                    Some(0) => Ok(to_record(path, unsafe { buffers[i].assume_init_ref() })),
+                    _ => Err(path),
+                })
+                .collect()
+        }
+    }
+}
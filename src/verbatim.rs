@@ -0,0 +1,85 @@
+//! Windows verbatim paths (\\?\C:\... and \\?\UNC\server\share\...), which canonicalizing
+//! yields and which lift the MAX_PATH limit of 260 characters. Traversal, metadata and
+//! deletion take them as they are. Patterns are matched against the usual form, and other
+//! programs get that too where it is short enough, as many don't understand the verbatim
+//! one. Elsewhere, paths don't have these prefixes and are left alone.
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+const VERBATIM: &str = r"\\?\";
+const VERBATIM_UNC: &str = r"\\?\UNC\";
+/// Paths this long only work in the verbatim form
+const MAX_PATH: usize = 260;
+
+fn is_drive(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// The path in its usual form: C:\... or \\server\share\...
+pub fn strip(path: &Path) -> Cow<'_, Path> {
+    let s = match path.to_str() {
+        Some(s) => s,
+        None => return Cow::Borrowed(path),
+    };
+    if let Some(share) = s.strip_prefix(VERBATIM_UNC) {
+        return Cow::Owned(PathBuf::from(format!(r"\\{}", share)));
+    }
+    match s.strip_prefix(VERBATIM) {
+        // Others, like \\?\Volume{...}\, have no usual form
+        Some(rest) if is_drive(rest) => Cow::Owned(PathBuf::from(rest)),
+        _ => Cow::Borrowed(path),
+    }
+}
+
+/// The path to hand to other programs: the usual form, unless it's too long for that
+pub fn for_programs(path: &Path) -> PathBuf {
+    match strip(path) {
+        Cow::Owned(usual) if usual.as_os_str().len() < MAX_PATH => usual,
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stripped(path: &str) -> String {
+        strip(Path::new(path)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn strips_drive_paths() {
+        assert_eq!(stripped(r"\\?\C:\data\session"), r"C:\data\session");
+        assert_eq!(stripped(r"\\?\d:\"), r"d:\");
+    }
+
+    #[test]
+    fn strips_unc_paths() {
+        assert_eq!(
+            stripped(r"\\?\UNC\server\share\data"),
+            r"\\server\share\data"
+        );
+    }
+
+    #[test]
+    fn leaves_other_paths_alone() {
+        for path in [
+            r"C:\data",
+            r"\\server\share\data",
+            r"\\?\Volume{0b1c}\data",
+            "/var/data",
+            "relative/path",
+        ] {
+            assert_eq!(stripped(path), path);
+        }
+    }
+
+    #[test]
+    fn keeps_long_paths_verbatim_for_programs() {
+        let short = r"\\?\C:\data\file";
+        assert_eq!(for_programs(Path::new(short)), Path::new(r"C:\data\file"));
+        let long = format!(r"\\?\C:\{}\file", "d".repeat(300));
+        assert_eq!(for_programs(Path::new(&long)), Path::new(&long));
+    }
+}
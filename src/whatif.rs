@@ -0,0 +1,55 @@
+//! What different limits would delete from a saved scan, for choosing them (dirrotate whatif)
+use crate::clock;
+use crate::groups::{format_age, format_duration};
+use std::time::{Duration, SystemTime};
+
+/// Limits to try. Like in a run, expired files go regardless of size, then the oldest
+/// go until the rest fit.
+pub struct Policy {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+/// Print, for each of the policies, how many files and bytes it would delete and the
+/// oldest file it would keep. The files are (size, modification time).
+pub fn print_whatif(files: &[(u64, SystemTime)], policies: &[Policy]) {
+    let mut files = files.to_vec();
+    files.sort_by_key(|&(_, modified)| modified);
+    // What deleting the oldest i files frees, at [i]
+    let freed: Vec<u64> = std::iter::once(0)
+        .chain(files.iter().scan(0, |sum, &(size, _)| {
+            *sum += size;
+            Some(*sum)
+        }))
+        .collect();
+    let total = freed[files.len()];
+    println!("{} files, {} bytes", files.len(), total);
+    println!("  max size    max age  deletes  bytes       leaves      oldest kept");
+    for policy in policies {
+        // Either limit deletes a prefix of the files, oldest first
+        let by_size = policy.max_size.map_or(0, |max_size| {
+            freed.partition_point(|&f| total - f > max_size)
+        });
+        let by_age = policy.max_age.map_or(0, |max_age| {
+            files.partition_point(|&(_, modified)| {
+                clock::now().duration_since(modified).unwrap_or_default() > max_age
+            })
+        });
+        let deleted = by_size.max(by_age).min(files.len());
+        println!(
+            "  {:<10}  {:<7}  {:<7}  {:<10}  {:<10}  {}",
+            policy
+                .max_size
+                .map_or_else(|| "-".to_string(), |s| s.to_string()),
+            policy
+                .max_age
+                .map_or_else(|| "-".to_string(), |a| format_duration(a.as_secs())),
+            deleted,
+            freed[deleted],
+            total - freed[deleted],
+            files
+                .get(deleted)
+                .map_or_else(|| "-".to_string(), |&(_, modified)| format_age(modified)),
+        );
+    }
+}
@@ -0,0 +1,35 @@
+//! What the tests through the binary share
+#![allow(dead_code)]
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const OLD: Duration = Duration::from_secs(10 * 86400);
+pub const NEW: Duration = Duration::from_secs(60);
+
+/// An empty directory of the test's own, removed again when dropped
+pub struct TestDir(pub PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let dir =
+            std::env::temp_dir().join(format!("dirrotate-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write a small file, with its parents, modified `age` ago
+pub fn write_file(path: &Path, age: Duration) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, b"data").unwrap();
+    let file = File::options().write(true).open(path).unwrap();
+    file.set_modified(SystemTime::now() - age).unwrap();
+}
@@ -0,0 +1,82 @@
+//! Settings from argument files: checked with check-config, and run with @FILE
+mod common;
+
+use common::TestDir;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Write the settings for the data directory, followed by `lines`
+fn write_config(dir: &Path, lines: &[&str]) -> PathBuf {
+    let config = dir.join("rotate.conf");
+    let data = dir.join("data");
+    fs::create_dir_all(&data).unwrap();
+    let mut content = format!("# Rotation of the data\n{}\n1G\n", data.display());
+    for line in lines {
+        content.push_str(line);
+        content.push('\n');
+    }
+    fs::write(&config, content).unwrap();
+    config
+}
+
+fn dirrotate(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dirrotate"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn check_config(config: &Path) -> (bool, String) {
+    let output = dirrotate(&["check-config", config.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    (output.status.success(), stderr)
+}
+
+#[test]
+fn accepts_good_settings() {
+    let dir = TestDir::new("good");
+    let config = write_config(&dir.0, &["--max-age 30d", "--exclude *.keep", "", "-v"]);
+    assert_eq!(check_config(&config), (true, String::new()));
+    let run = format!("@{}", config.display());
+    assert!(dirrotate(&[&run, "--dryrun"]).status.success());
+}
+
+#[test]
+fn reports_the_line_of_bad_globs() {
+    let dir = TestDir::new("glob");
+    let config = write_config(&dir.0, &["--max-age 30d", "--exclude run[1"]);
+    let (ok, stderr) = check_config(&config);
+    assert!(!ok);
+    let expected = format!(
+        "{}:5: --exclude run[1 is not a valid glob pattern",
+        config.display()
+    );
+    assert!(stderr.contains(&expected), "{}", stderr);
+}
+
+#[test]
+fn reports_the_line_of_bad_values() {
+    let dir = TestDir::new("value");
+    let config = write_config(&dir.0, &["--max-age 30x"]);
+    let (ok, stderr) = check_config(&config);
+    assert!(!ok);
+    assert!(
+        stderr.contains(&format!("{}:4: ", config.display())),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn reports_missing_directories() {
+    let dir = TestDir::new("missing");
+    let config = write_config(&dir.0, &["--also /nonexistent/dirrotate"]);
+    let (ok, stderr) = check_config(&config);
+    assert!(!ok);
+    assert!(
+        stderr.contains(&format!("{}:4: ", config.display())),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,45 @@
+//! Runs after a run that was interrupted, told apart by the journal it left, through the binary
+mod common;
+
+use common::{write_file, TestDir, NEW, OLD};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn goes_on_with_what_the_interrupted_run_left() {
+    let dir = TestDir::new("journal");
+    let data = dir.0.join("data");
+    let (deleted, left, new) = (
+        data.join("deleted.dat"),
+        data.join("left.dat"),
+        data.join("new.dat"),
+    );
+    write_file(&left, OLD);
+    write_file(&new, NEW);
+    // Crashed after deleting the first of its two files
+    let journal = data.join(".dirrotate-journal");
+    fs::write(
+        &journal,
+        format!(
+            "dirrotate-journal 1\n0.000000000\nplanned\t0\t4\t{}\nplanned\t1\t4\t{}\ndone\t0\tdeleted\n",
+            deleted.display(),
+            left.display()
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_dirrotate"))
+        .arg(&data)
+        .args(["--max-age", "1d", "-vv"])
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("was interrupted: 1 of 2 operations finished"));
+    assert!(log.contains(&format!("Unfinished, still there: {}", left.display())));
+    // The file already deleted isn't tried again
+    assert!(!log.contains("deleted.dat"));
+    assert!(!left.exists());
+    assert!(new.exists());
+    assert!(!journal.exists());
+}
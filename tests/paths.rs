@@ -0,0 +1,76 @@
+//! Rotation of trees with long paths (longer than MAX_PATH on Windows), with glob characters
+//! in the directory name, and on UNC shares, through the binary
+mod common;
+
+use common::{write_file, TestDir, NEW, OLD};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn rotate(dir: &Path, args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_dirrotate"))
+        .arg(dir)
+        .args(["--max-age", "1d"])
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// A directory below `dir` that makes paths in it longer than MAX_PATH
+fn deep(dir: &Path) -> PathBuf {
+    (0..12).fold(dir.to_path_buf(), |path, level| {
+        path.join(format!("session-{:02}-acquisition-data", level))
+    })
+}
+
+#[test]
+fn rotates_long_paths() {
+    let dir = TestDir::new("long");
+    let deep = deep(&dir.0);
+    assert!(deep.as_os_str().len() > 260);
+    write_file(&deep.join("old.dat"), OLD);
+    write_file(&deep.join("old.log"), OLD);
+    write_file(&deep.join("new.dat"), NEW);
+    rotate(&dir.0, &["--exclude", "**/*.log"]);
+    assert!(!deep.join("old.dat").exists());
+    assert!(deep.join("old.log").exists());
+    assert!(deep.join("new.dat").exists());
+}
+
+#[test]
+fn matches_under_directories_with_glob_characters() {
+    let dir = TestDir::new("glob");
+    let base = dir.0.join("run [1]");
+    write_file(&base.join("a").join("old.dat"), OLD);
+    write_file(&base.join("a").join("old.log"), OLD);
+    write_file(&base.join("new.dat"), NEW);
+    rotate(&base, &["--protect-from-op", "**/*.log"]);
+    assert!(!base.join("a").join("old.dat").exists());
+    assert!(base.join("a").join("old.log").exists());
+}
+
+#[cfg(windows)]
+#[test]
+fn rotates_on_unc_shares() {
+    // The administrative share of the drive of the temporary directory
+    let dir = TestDir::new("unc");
+    let local = dir.0.to_str().unwrap().to_string();
+    let (drive, rest) = local.split_at(2);
+    let share = PathBuf::from(format!(
+        r"\\localhost\{}${}",
+        drive.trim_end_matches(':'),
+        rest
+    ));
+    if !share.exists() {
+        eprintln!("Skipping, {} is not shared", share.display());
+        return;
+    }
+    let deep = deep(&share);
+    write_file(&deep.join("old.dat"), OLD);
+    write_file(&deep.join("old.log"), OLD);
+    write_file(&deep.join("new.dat"), NEW);
+    rotate(&share, &["--exclude", "**/*.log"]);
+    assert!(!deep.join("old.dat").exists());
+    assert!(deep.join("old.log").exists());
+    assert!(deep.join("new.dat").exists());
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::{write_file, TestDir, NEW};
+use std::process::Command;
+use std::time::Duration;
+
+const DAY: u64 = 86400;
+
+#[test]
+fn deletes_the_oldest_files_whichever_directory_they_are_in() {
+    let dir = TestDir::new("pooled");
+    let (first, second) = (dir.0.join("first"), dir.0.join("second"));
+    write_file(&first.join("10.dat"), Duration::from_secs(10 * DAY));
+    write_file(&first.join("8.dat"), Duration::from_secs(8 * DAY));
+    write_file(&second.join("9.dat"), Duration::from_secs(9 * DAY));
+    write_file(&second.join("new.dat"), NEW);
+    // Four files of 4 bytes under one limit of 8: the two oldest go
+    let output = Command::new(env!("CARGO_BIN_EXE_dirrotate"))
+        .arg(&first)
+        .args(["8", "--also"])
+        .arg(&second)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!first.join("10.dat").exists());
+    assert!(first.join("8.dat").exists());
+    assert!(!second.join("9.dat").exists());
+    assert!(second.join("new.dat").exists());
+}
@@ -0,0 +1,33 @@
+//! Protection of listed paths, through the binary
+mod common;
+
+use common::{write_file, TestDir, OLD};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn listed_directories_protect_what_is_below_them() {
+    let dir = TestDir::new("dirs");
+    let data = dir.0.join("data");
+    let files = [
+        "referenced/a.bin",
+        "referenced/deeper/b.bin",
+        "c.bin",
+        "d.bin",
+    ];
+    for file in files {
+        write_file(&data.join(file), OLD);
+    }
+    let list = dir.0.join("protected.txt");
+    fs::write(&list, "# still referenced\nreferenced\nc.bin\n").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_dirrotate"))
+        .arg(&data)
+        .args(["--max-age", "1d", "--protect-from-list"])
+        .arg(&list)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let left: Vec<bool> = files.iter().map(|file| data.join(file).exists()).collect();
+    assert_eq!(left, [true, true, true, false]);
+}
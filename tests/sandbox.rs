@@ -0,0 +1,69 @@
+//! Runs kept to changing files below their own directories (--sandbox), through the binary
+#![cfg(target_os = "linux")]
+mod common;
+
+use common::{write_file, TestDir, NEW, OLD};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn keeps_the_run_and_its_commands_to_the_directory() {
+    let dir = TestDir::new("sandbox");
+    let (data, outside) = (dir.0.join("data"), dir.0.join("outside"));
+    write_file(&data.join("old.dat"), OLD);
+    write_file(&data.join("new.dat"), NEW);
+    fs::create_dir_all(&outside).unwrap();
+    // Commands run after the sandbox is in place
+    let command = format!(
+        "touch {} {}; true",
+        outside.join("touched").display(),
+        data.join("touched").display()
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_dirrotate"))
+        .arg(&data)
+        .args(["--max-age", "1d", "--sandbox", "--protect-from-command"])
+        .arg(&command)
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&output.stderr);
+    if log.contains("Landlock is not available") {
+        eprintln!("Skipping, {}", log.trim());
+        return;
+    }
+    assert!(output.status.success(), "{}", log);
+    assert!(!data.join("old.dat").exists());
+    assert!(data.join("new.dat").exists());
+    assert!(data.join("touched").exists());
+    assert!(!outside.join("touched").exists());
+}
+
+#[test]
+fn lets_a_run_spill_to_temporary_files() {
+    let dir = TestDir::new("sandbox-spill");
+    let data = dir.0.join("data");
+    for i in 0..100 {
+        write_file(&data.join(format!("{}.dat", i)), OLD);
+    }
+    write_file(&data.join("new.dat"), NEW);
+    let output = Command::new(env!("CARGO_BIN_EXE_dirrotate"))
+        .arg(&data)
+        .args([
+            "4",
+            "--sandbox",
+            "--max-memory",
+            "1K",
+            "--yes-i-mean-it",
+            "-vv",
+        ])
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&output.stderr);
+    if log.contains("Landlock is not available") {
+        eprintln!("Skipping, {}", log.trim());
+        return;
+    }
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("Sorting 101 candidates on disk"), "{}", log);
+    assert_eq!(fs::read_dir(&data).unwrap().count(), 1);
+    assert!(data.join("new.dat").exists());
+}
@@ -0,0 +1,55 @@
+//! Moving files to the trash, and putting them back with undo-last, through the binary
+#![cfg(all(unix, not(target_os = "macos")))]
+mod common;
+
+use common::{write_file, TestDir, NEW, OLD};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// The binary, with the trash in `data_home`
+fn dirrotate(data_home: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_dirrotate"));
+    command.env("XDG_DATA_HOME", data_home);
+    command
+}
+
+#[test]
+fn undo_last_puts_trashed_files_back() {
+    let dir = TestDir::new("trash");
+    let (data, data_home) = (dir.0.join("data"), dir.0.join("share"));
+    let (old, deeper, new) = (
+        data.join("old.dat"),
+        data.join("a").join("old.dat"),
+        data.join("new.dat"),
+    );
+    write_file(&old, OLD);
+    write_file(&deeper, OLD);
+    write_file(&new, NEW);
+    let trashed = || {
+        fs::read_dir(data_home.join("Trash").join("files"))
+            .unwrap()
+            .count()
+    };
+
+    let status = dirrotate(&data_home)
+        .arg(&data)
+        .args(["--max-age", "1d", "--trash"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(!old.exists());
+    assert!(!deeper.exists());
+    assert!(new.exists());
+    assert_eq!(trashed(), 2);
+
+    let status = dirrotate(&data_home)
+        .arg("undo-last")
+        .arg(&data)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(&old).unwrap(), b"data");
+    assert_eq!(fs::read(&deeper).unwrap(), b"data");
+    assert_eq!(trashed(), 0);
+}